@@ -0,0 +1,63 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Measures finding a keypair by public key in a keychain of 1,000 entries,
+//! with and without an up to date index.
+//!
+//! Run with `cargo bench --bench keychain`.
+
+use std::fs;
+use std::time::{Duration, Instant};
+
+use saltlick_cli::keychain::Keychain;
+
+const ENTRIES: usize = 1000;
+const ITERATIONS: u32 = 20;
+
+fn time(name: &str, mut f: impl FnMut()) {
+    let mut total = Duration::default();
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        f();
+        total += start.elapsed();
+    }
+    println!("{:<32} {:>12.3?} per lookup", name, total / ITERATIONS);
+}
+
+fn main() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    // Writing key files directly is much faster than creating each entry
+    // through the keychain, and leaves it in the same state.
+    let mut last = None;
+    for i in 0..ENTRIES {
+        let (public, secret) = saltlick::gen_keypair();
+        let name = format!("key_{}", i);
+        public
+            .to_file(temp.path().join(format!("{}.pub", name)))
+            .unwrap();
+        secret
+            .to_file(temp.path().join(format!("{}.sec", name)))
+            .unwrap();
+        last = Some(public);
+    }
+    let last = last.unwrap();
+    let keychain = Keychain::open_at(temp.path()).unwrap();
+    let index_path = temp.path().join("index");
+
+    time("find, no index", || {
+        let _ = fs::remove_file(&index_path);
+        keychain.find(&last).unwrap();
+    });
+    time("find, indexed", || {
+        keychain.find(&last).unwrap();
+    });
+    let (unknown, _) = saltlick::gen_keypair();
+    time("find missing, indexed", || {
+        keychain.find(&unknown).unwrap_err();
+    });
+}
@@ -0,0 +1,58 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Encryption and decryption throughput through the same paths as
+//! `saltlick bench`, for statistically sound comparisons between changes.
+//!
+//! Run with `cargo bench --bench throughput`.
+
+use std::io;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use saltlick_cli::bench::{decrypt, encrypt};
+
+const SIZES: &[usize] = &[64 * 1024, 1024 * 1024, 16 * 1024 * 1024];
+const BUFFER_SIZES: &[usize] = &[8 * 1024, 64 * 1024, 256 * 1024, 1024 * 1024];
+
+fn throughput(c: &mut Criterion) {
+    let (public, secret) = saltlick::gen_keypair();
+    for &size in SIZES {
+        let plaintext = vec![0x5a; size];
+        let mut ciphertext = Vec::new();
+        encrypt(&public, &plaintext, 64 * 1024, &mut ciphertext).unwrap();
+
+        let mut group = c.benchmark_group(format!("{}K", size / 1024));
+        group.throughput(Throughput::Bytes(size as u64));
+        if size >= 1024 * 1024 {
+            group.sample_size(10);
+        }
+        for &buffer_size in BUFFER_SIZES {
+            group.bench_with_input(
+                BenchmarkId::new("encrypt", buffer_size),
+                &buffer_size,
+                |b, &buffer_size| {
+                    b.iter(|| encrypt(&public, &plaintext, buffer_size, &mut io::sink()).unwrap())
+                },
+            );
+            group.bench_with_input(
+                BenchmarkId::new("decrypt", buffer_size),
+                &buffer_size,
+                |b, &buffer_size| {
+                    b.iter(|| {
+                        decrypt(&public, &secret, &ciphertext, buffer_size, &mut io::sink())
+                            .unwrap()
+                    })
+                },
+            );
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
@@ -0,0 +1,62 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Decrypts arbitrary input as `saltlick decrypt` does with no key given:
+//! through `SaltlickDecrypter` with a deferred keychain lookup, and through
+//! the stream reader used with the agent. The keychain holds the fuzz key,
+//! which the seed corpus is encrypted to.
+
+#![no_main]
+
+use std::io::{self, Cursor};
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+use saltlick::read::SaltlickDecrypter;
+use saltlick::{PublicKey, SecretKey};
+use saltlick_cli::keychain::{public_key_bytes, secret_key_bytes, Keychain, Keypair};
+use saltlick_cli::stream::{StreamDecrypter, StreamHeader, StreamKey};
+
+const PUBLIC_PEM: &str = include_str!("../keys/fuzz.pub.pem");
+const SECRET_PEM: &str = include_str!("../keys/fuzz.sec.pem");
+
+fn keychain() -> &'static Keychain {
+    static KEYCHAIN: OnceLock<Keychain> = OnceLock::new();
+    KEYCHAIN.get_or_init(|| {
+        let path = std::env::temp_dir().join(format!("saltlick-fuzz-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let keychain = Keychain::open_at(path).unwrap();
+        let public = PublicKey::from_pem(PUBLIC_PEM).unwrap();
+        let secret = SecretKey::from_pem(SECRET_PEM).unwrap();
+        keychain.create("fuzz", public, secret).unwrap();
+        keychain
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let keychain = keychain();
+    let mut decrypter = SaltlickDecrypter::new_deferred(data, move |public| {
+        keychain.find(public).ok().map(Keypair::into_secret)
+    });
+    let _ = io::copy(&mut decrypter, &mut io::sink());
+
+    let mut reader = Cursor::new(data);
+    if let Ok(header) = StreamHeader::read_from(&mut reader) {
+        if let Ok(keypair) = keychain.find(&header.public) {
+            let key = StreamKey::open(
+                &header.sealed,
+                &public_key_bytes(keypair.public()),
+                &secret_key_bytes(keypair.secret()),
+            );
+            if let Some(key) = key {
+                let mut decrypter = StreamDecrypter::new(&key, reader).unwrap();
+                let _ = io::copy(&mut decrypter, &mut io::sink());
+            }
+        }
+    }
+});
@@ -0,0 +1,39 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Parses arbitrary input as each document format, and as an encrypted
+//! document opened and decrypted with the fuzz key.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use saltlick::{PublicKey, SecretKey};
+use saltlick_cli::values::{Envelope, Format};
+
+const PUBLIC_PEM: &str = include_str!("../keys/fuzz.pub.pem");
+const SECRET_PEM: &str = include_str!("../keys/fuzz.sec.pem");
+
+fuzz_target!(|data: &[u8]| {
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(_) => return,
+    };
+    let public = PublicKey::from_pem(PUBLIC_PEM).unwrap();
+    let secret = SecretKey::from_pem(SECRET_PEM).unwrap();
+    for &format in &[Format::Json, Format::Toml, Format::Yaml] {
+        let document = match format.parse(text) {
+            Ok(document) => document,
+            Err(_) => continue,
+        };
+        let _ = format.render(&document);
+        let _ = Envelope::recipients(&document);
+        if let Ok(envelope) = Envelope::open(&document, &public, &secret) {
+            let _ = envelope.decrypt(&document);
+        }
+    }
+});
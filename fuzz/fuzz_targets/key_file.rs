@@ -0,0 +1,39 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Loads arbitrary input as public and secret key files, and checks that
+//! keys that load survive the conversions the keychain relies on.
+
+#![no_main]
+
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+use saltlick::{PublicKey, SecretKey};
+use saltlick_cli::keychain::{public_from_secret, public_key_bytes, secret_key_bytes};
+
+fn key_path() -> &'static std::path::Path {
+    static PATH: OnceLock<std::path::PathBuf> = OnceLock::new();
+    PATH.get_or_init(|| {
+        std::env::temp_dir().join(format!("saltlick-fuzz-key-{}.pem", std::process::id()))
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let path = key_path();
+    std::fs::write(path, data).unwrap();
+    if let Ok(public) = PublicKey::from_file(path) {
+        let bytes = public_key_bytes(&public);
+        assert_eq!(PublicKey::from_raw_curve25519(&bytes).unwrap(), public);
+    }
+    if let Ok(secret) = SecretKey::from_file(path) {
+        let bytes = secret_key_bytes(&secret);
+        assert_eq!(SecretKey::from_raw_curve25519(&bytes).unwrap(), secret);
+        public_from_secret(&secret);
+    }
+});
@@ -0,0 +1,32 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Checks that accepted keypair names always map to a single key file in
+//! the keychain directory, and are listed back under the same name.
+
+#![no_main]
+
+use std::path::{Component, Path};
+
+use libfuzzer_sys::fuzz_target;
+use saltlick_cli::keychain::KeypairName;
+
+fuzz_target!(|data: &[u8]| {
+    let name = match std::str::from_utf8(data) {
+        Ok(name) => name,
+        Err(_) => return,
+    };
+    if let Ok(parsed) = KeypairName::new(name) {
+        assert_eq!(parsed.as_ref(), name);
+        let file = format!("{}.pub", parsed);
+        let path = Path::new(&file);
+        let components = path.components().collect::<Vec<_>>();
+        assert!(matches!(components.as_slice(), [Component::Normal(_)]));
+        assert_eq!(path.file_stem().and_then(|stem| stem.to_str()), Some(name));
+    }
+});
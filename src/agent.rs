@@ -0,0 +1,510 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Key agent that holds unlocked secret keys in a separate process.
+//!
+//! The agent listens on a Unix domain socket, by convention named by the
+//! `SALTLICK_AUTH_SOCK` environment variable. Secret keys are added once and
+//! kept in locked memory until their time to live runs out, the agent is
+//! locked, or it stops. To decrypt, a client sends the stream header; the
+//! agent opens it and returns only the per-stream key, so the secret key
+//! itself never leaves the agent.
+//!
+//! Each connection carries a single request and response, each one line of
+//! JSON.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use saltlick::SecretKey;
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{hex, hex_decode};
+use crate::error::AgentError;
+use crate::keychain::{fingerprint, public_key_bytes, secret_key_bytes};
+use crate::secret::SecretBytes;
+use crate::stream::{StreamHeader, StreamKey};
+
+/// Environment variable naming the agent socket.
+pub const SOCKET_ENV: &str = "SALTLICK_AUTH_SOCK";
+
+/// How often the agent discards keys whose time to live has run out.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the agent waits for a client to send its request or read the
+/// response before giving up on the connection, so that one stalled client
+/// cannot hold up the others.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest request line the agent reads, including the newline.
+const MAX_REQUEST_LEN: u64 = 64 << 10;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+enum Request {
+    Add {
+        name: String,
+        secret: String,
+        ttl: Option<u64>,
+    },
+    List,
+    Lock,
+    Open {
+        public: String,
+        sealed: String,
+    },
+    Stop,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+enum Response {
+    Ok,
+    Keys { keys: Vec<AgentKey> },
+    StreamKey { name: String, key: String },
+    NotFound,
+    Error { message: String },
+}
+
+/// Description of a key held by the agent.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AgentKey {
+    /// Keychain name the key was added under.
+    pub name: String,
+
+    /// Fingerprint of the public key.
+    pub fingerprint: String,
+
+    /// Seconds until the key is discarded, if it has a time to live.
+    pub expires_in: Option<u64>,
+}
+
+struct HeldKey {
+    name: String,
+    public: Vec<u8>,
+    fingerprint: String,
+    secret: SecretBytes,
+    expires: Option<Instant>,
+}
+
+impl HeldKey {
+    fn describe(&self, now: Instant) -> AgentKey {
+        AgentKey {
+            name: self.name.clone(),
+            fingerprint: self.fingerprint.clone(),
+            expires_in: self
+                .expires
+                .map(|expires| expires.saturating_duration_since(now).as_secs()),
+        }
+    }
+}
+
+/// Runs an agent listening on `path` until it is asked to stop.
+///
+/// Keys added without their own time to live use `default_ttl`, or are kept
+/// until the agent is locked or stopped if it is `None`.
+pub fn run(path: impl AsRef<Path>, default_ttl: Option<Duration>) -> Result<(), AgentError> {
+    let path = path.as_ref();
+    let listener = bind(path)?;
+    let keys = Arc::new(Mutex::new(Vec::<HeldKey>::new()));
+
+    let expiring = Arc::downgrade(&keys);
+    thread::spawn(move || {
+        while let Some(keys) = expiring.upgrade() {
+            let now = Instant::now();
+            keys.lock()
+                .unwrap()
+                .retain(|key| key.expires.map(|expires| expires > now).unwrap_or(true));
+            drop(keys);
+            thread::sleep(EXPIRY_INTERVAL);
+        }
+    });
+
+    let result = serve(&listener, &keys, default_ttl);
+    let _ = fs::remove_file(path);
+    result
+}
+
+/// Binds the agent socket, readable and writable by the current user only.
+/// A socket left behind by an agent that is no longer running is replaced;
+/// one that an agent still answers on is not.
+fn bind(path: &Path) -> Result<UnixListener, AgentError> {
+    let bind_error = |error| AgentError::Bind {
+        path: path.to_path_buf(),
+        error,
+    };
+    let stale = fs::symlink_metadata(path)
+        .map(|metadata| metadata.file_type().is_socket())
+        .unwrap_or(false)
+        && matches!(
+            UnixStream::connect(path),
+            Err(ref error) if error.kind() == io::ErrorKind::ConnectionRefused
+        );
+    if stale {
+        fs::remove_file(path).map_err(bind_error)?;
+    }
+    // Restrict the umask while binding so the socket is never briefly
+    // accessible to other users.
+    // SAFETY: umask has no preconditions and cannot fail.
+    let old_umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    // SAFETY: as above.
+    unsafe { libc::umask(old_umask) };
+    let listener = listener.map_err(bind_error)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600)).map_err(bind_error)?;
+    Ok(listener)
+}
+
+fn serve(
+    listener: &UnixListener,
+    keys: &Mutex<Vec<HeldKey>>,
+    default_ttl: Option<Duration>,
+) -> Result<(), AgentError> {
+    for connection in listener.incoming() {
+        let mut connection = match connection {
+            Ok(connection) => connection,
+            Err(error) => {
+                eprintln!("Warning: failed to accept agent connection: {}", error);
+                continue;
+            }
+        };
+        let timeouts = connection
+            .set_read_timeout(Some(REQUEST_TIMEOUT))
+            .and_then(|_| connection.set_write_timeout(Some(REQUEST_TIMEOUT)));
+        let mut line = String::new();
+        if timeouts.is_err()
+            || BufReader::new((&connection).take(MAX_REQUEST_LEN))
+                .read_line(&mut line)
+                .is_err()
+        {
+            continue;
+        }
+        let (response, stop) = if !line.ends_with('\n') && line.len() as u64 == MAX_REQUEST_LEN {
+            (
+                Response::Error {
+                    message: String::from("request too long"),
+                },
+                false,
+            )
+        } else {
+            match serde_json::from_str(&line) {
+                Ok(Request::Stop) => (Response::Ok, true),
+                Ok(request) => (handle(request, keys, default_ttl), false),
+                Err(error) => (
+                    Response::Error {
+                        message: format!("malformed request: {}", error),
+                    },
+                    false,
+                ),
+            }
+        };
+        let mut reply =
+            serde_json::to_string(&response).expect("response serialization cannot fail");
+        reply.push('\n');
+        // The client may have gone away - that only affects its own request.
+        let _ = connection.write_all(reply.as_bytes());
+        if stop {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn handle(request: Request, keys: &Mutex<Vec<HeldKey>>, default_ttl: Option<Duration>) -> Response {
+    let mut keys = keys.lock().unwrap();
+    let now = Instant::now();
+    keys.retain(|key| key.expires.map(|expires| expires > now).unwrap_or(true));
+    match request {
+        Request::Add { name, secret, ttl } => {
+            let secret = match hex_decode(&secret)
+                .and_then(|bytes| SecretKey::from_raw_curve25519(&bytes).ok())
+            {
+                Some(secret) => secret,
+                None => {
+                    return Response::Error {
+                        message: String::from("invalid secret key"),
+                    }
+                }
+            };
+            let public = crate::keychain::public_from_secret(&secret);
+            let public_bytes = public_key_bytes(&public);
+            let ttl = ttl.map(Duration::from_secs).or(default_ttl);
+            keys.retain(|key| key.public != public_bytes);
+            keys.push(HeldKey {
+                name,
+                public: public_bytes,
+                fingerprint: fingerprint(&public),
+                secret: secret_key_bytes(&secret),
+                expires: ttl.map(|ttl| now + ttl),
+            });
+            Response::Ok
+        }
+        Request::List => Response::Keys {
+            keys: keys.iter().map(|key| key.describe(now)).collect(),
+        },
+        Request::Lock => {
+            keys.clear();
+            Response::Ok
+        }
+        Request::Open { public, sealed } => {
+            let (public, sealed) = match (hex_decode(&public), hex_decode(&sealed)) {
+                (Some(public), Some(sealed)) => (public, sealed),
+                _ => {
+                    return Response::Error {
+                        message: String::from("invalid stream header"),
+                    }
+                }
+            };
+            let key = match keys.iter().find(|key| key.public == public) {
+                Some(key) => key,
+                None => return Response::NotFound,
+            };
+            match StreamKey::open(&sealed, &key.public, &key.secret) {
+                Some(stream_key) => Response::StreamKey {
+                    name: key.name.clone(),
+                    key: hex(&stream_key.to_bytes()),
+                },
+                None => Response::Error {
+                    message: String::from("unable to open stream header"),
+                },
+            }
+        }
+        Request::Stop => Response::Ok,
+    }
+}
+
+/// Client for a running agent.
+#[derive(Debug)]
+pub struct AgentClient {
+    path: PathBuf,
+}
+
+impl AgentClient {
+    /// Create a client for the agent listening on `path`.
+    pub fn new(path: impl AsRef<Path>) -> AgentClient {
+        AgentClient {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Create a client for the agent named by `SALTLICK_AUTH_SOCK`, if it is
+    /// set.
+    pub fn from_env() -> Option<AgentClient> {
+        std::env::var_os(SOCKET_ENV)
+            .filter(|path| !path.is_empty())
+            .map(AgentClient::new)
+    }
+
+    /// Adds a secret key to the agent under `name`, replacing any key with
+    /// the same public key.
+    pub fn add(
+        &self,
+        name: impl AsRef<str>,
+        secret: &SecretKey,
+        ttl: Option<Duration>,
+    ) -> Result<(), AgentError> {
+        self.expect_ok(Request::Add {
+            name: name.as_ref().to_string(),
+            secret: hex(&secret_key_bytes(secret)),
+            ttl: ttl.map(|ttl| ttl.as_secs()),
+        })
+    }
+
+    /// Lists the keys held by the agent.
+    pub fn list(&self) -> Result<Vec<AgentKey>, AgentError> {
+        match self.request(Request::List)? {
+            Response::Keys { keys } => Ok(keys),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Discards all keys held by the agent.
+    pub fn lock(&self) -> Result<(), AgentError> {
+        self.expect_ok(Request::Lock)
+    }
+
+    /// Stops the agent.
+    pub fn stop(&self) -> Result<(), AgentError> {
+        self.expect_ok(Request::Stop)
+    }
+
+    /// Asks the agent to open `header`. Returns the name of the key used and
+    /// the stream key, or `None` if the agent does not hold the key the
+    /// stream was encrypted to.
+    pub fn open(&self, header: &StreamHeader) -> Result<Option<(String, StreamKey)>, AgentError> {
+        let request = Request::Open {
+            public: hex(&public_key_bytes(&header.public)),
+            sealed: hex(&header.sealed),
+        };
+        match self.request(request)? {
+            Response::StreamKey { name, key } => hex_decode(&key)
+                .and_then(|bytes| StreamKey::from_bytes(&bytes))
+                .map(|key| Some((name, key)))
+                .ok_or_else(|| AgentError::Protocol(String::from("invalid stream key"))),
+            Response::NotFound => Ok(None),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    fn expect_ok(&self, request: Request) -> Result<(), AgentError> {
+        match self.request(request)? {
+            Response::Ok => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    fn request(&self, request: Request) -> Result<Response, AgentError> {
+        let connect_error = |error| AgentError::Connect {
+            path: self.path.clone(),
+            error,
+        };
+        let mut connection = UnixStream::connect(&self.path).map_err(connect_error)?;
+        let mut line = serde_json::to_string(&request).expect("request serialization cannot fail");
+        line.push('\n');
+        connection
+            .write_all(line.as_bytes())
+            .map_err(AgentError::Io)?;
+        let mut reply = String::new();
+        BufReader::new(&connection)
+            .read_line(&mut reply)
+            .map_err(AgentError::Io)?;
+        serde_json::from_str(&reply).map_err(|error| AgentError::Protocol(error.to_string()))
+    }
+}
+
+fn unexpected(response: Response) -> AgentError {
+    match response {
+        Response::Error { message } => AgentError::Remote(message),
+        other => AgentError::Protocol(format!("unexpected response {:?}", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, AgentClient, MAX_REQUEST_LEN};
+    use crate::error::AgentError;
+    use crate::keychain::fingerprint;
+    use crate::stream::{StreamDecrypter, StreamHeader};
+
+    use saltlick::read::SaltlickEncrypter;
+    use std::io::{BufRead, BufReader, Cursor, Read, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::thread;
+    use std::time::Duration;
+
+    fn start_agent(dir: &assert_fs::TempDir) -> (AgentClient, thread::JoinHandle<()>) {
+        let socket = dir.path().join("agent.sock");
+        let path = socket.clone();
+        let handle = thread::spawn(move || run(path, None).unwrap());
+        let client = AgentClient::new(&socket);
+        while client.list().is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        (client, handle)
+    }
+
+    #[test]
+    fn agent_decrypt_test() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let (client, handle) = start_agent(&temp);
+        let (public, secret) = saltlick::gen_keypair();
+        let mut ciphertext = Vec::new();
+        SaltlickEncrypter::new(public.clone(), &b"agent plaintext"[..])
+            .read_to_end(&mut ciphertext)
+            .unwrap();
+        let mut reader = Cursor::new(&ciphertext);
+        let header = StreamHeader::read_from(&mut reader).unwrap();
+
+        // Unknown keys are reported as not found.
+        assert!(client.open(&header).unwrap().is_none());
+
+        client.add("held", &secret, None).unwrap();
+        let keys = client.list().unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].name, "held");
+        assert_eq!(keys[0].fingerprint, fingerprint(&public));
+
+        let (name, key) = client.open(&header).unwrap().unwrap();
+        assert_eq!(name, "held");
+        let mut plaintext = Vec::new();
+        StreamDecrypter::new(&key, reader)
+            .unwrap()
+            .read_to_end(&mut plaintext)
+            .unwrap();
+        assert_eq!(plaintext, b"agent plaintext");
+
+        client.lock().unwrap();
+        assert!(client.list().unwrap().is_empty());
+        client.stop().unwrap();
+        handle.join().unwrap();
+        assert!(!temp.path().join("agent.sock").exists());
+    }
+
+    #[test]
+    fn agent_stale_socket_test() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let socket = temp.path().join("agent.sock");
+
+        // A socket nothing listens on any more is replaced.
+        drop(UnixListener::bind(&socket).unwrap());
+        let (client, handle) = start_agent(&temp);
+
+        // A socket an agent still answers on is left alone.
+        match run(&socket, None) {
+            Err(AgentError::Bind { .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(client.list().unwrap().is_empty());
+        client.stop().unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn agent_ttl_test() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let (client, handle) = start_agent(&temp);
+        let (_, secret) = saltlick::gen_keypair();
+        client
+            .add("short", &secret, Some(Duration::from_secs(1)))
+            .unwrap();
+        assert!(client.list().unwrap()[0].expires_in.unwrap() <= 1);
+        thread::sleep(Duration::from_millis(1100));
+        assert!(client.list().unwrap().is_empty());
+        client.stop().unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn agent_stalled_client_test() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let (client, handle) = start_agent(&temp);
+        let socket = temp.path().join("agent.sock");
+
+        // An overlong request is refused without being read in full.
+        let mut long = UnixStream::connect(&socket).unwrap();
+        long.write_all(&vec![b'a'; MAX_REQUEST_LEN as usize + 100])
+            .unwrap();
+        let mut reply = String::new();
+        BufReader::new(&long).read_line(&mut reply).unwrap();
+        assert!(reply.contains("request too long"), "{}", reply);
+
+        // A client that connects and sends nothing only holds up the others
+        // until the request timeout.
+        let _stalled = UnixStream::connect(&socket).unwrap();
+        assert!(client.list().unwrap().is_empty());
+
+        client.stop().unwrap();
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,406 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Append-only, hash-chained log of keychain operations.
+//!
+//! Each line of the log is a JSON object describing one operation. Every
+//! entry includes the hash of the entry before it, and its own hash covers
+//! both that and its contents, so editing or deleting an entry in the middle
+//! of the log breaks the chain for every entry after it. Entries never
+//! contain secret key material - keys are identified by name and public key
+//! fingerprint only.
+
+use std::fmt::{self, Display};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::hash::sha256;
+
+use crate::error::AuditError;
+
+/// Hash used as the previous hash of the first entry in a log.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Keychain operations recorded in the audit log.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditOp {
+    ClearDefault,
+    Create,
+    Decrypt,
+    Export,
+    Import,
+    Remove,
+    Rename,
+    SetDefault,
+    SetMetadata,
+}
+
+impl AuditOp {
+    fn as_str(self) -> &'static str {
+        use self::AuditOp::*;
+        match self {
+            ClearDefault => "clear-default",
+            Create => "create",
+            Decrypt => "decrypt",
+            Export => "export",
+            Import => "import",
+            Remove => "remove",
+            Rename => "rename",
+            SetDefault => "set-default",
+            SetMetadata => "set-metadata",
+        }
+    }
+}
+
+impl Display for AuditOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+impl FromStr for AuditOp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AuditOp, String> {
+        use self::AuditOp::*;
+        [
+            ClearDefault,
+            Create,
+            Decrypt,
+            Export,
+            Import,
+            Remove,
+            Rename,
+            SetDefault,
+            SetMetadata,
+        ]
+        .iter()
+        .find(|op| op.as_str() == s)
+        .copied()
+        .ok_or_else(|| format!("unknown operation \"{}\"", s))
+    }
+}
+
+/// A single audit log entry.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AuditEntry {
+    /// Position of the entry in the log, starting at 1.
+    pub seq: u64,
+
+    /// Time the operation was performed.
+    pub time: DateTime<Utc>,
+
+    /// Operation performed.
+    pub op: AuditOp,
+
+    /// Name of the keypair operated on.
+    pub key: String,
+
+    /// Fingerprint of the keypair's public key.
+    pub fingerprint: String,
+
+    /// Additional operation-specific information, such as the new name for
+    /// a rename.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+
+    /// User ID of the process that performed the operation, where
+    /// available.
+    pub uid: Option<u32>,
+
+    /// Hostname of the machine that performed the operation.
+    pub host: String,
+
+    /// Hash of the previous entry.
+    pub prev: String,
+
+    /// Hash of this entry.
+    pub hash: String,
+}
+
+impl AuditEntry {
+    /// Computes the chained hash of this entry from every field except
+    /// `hash` itself.
+    fn compute_hash(&self) -> String {
+        let unhashed = AuditEntry {
+            hash: String::new(),
+            ..self.clone()
+        };
+        let json = serde_json::to_string(&unhashed).expect("audit entry serialization cannot fail");
+        let digest = sha256::hash(json.as_bytes());
+        hex(&digest[..])
+    }
+}
+
+/// Accessor for the audit log file in a keychain directory.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    /// Open the audit log at `path`. The file is created on first write.
+    pub fn open(path: impl AsRef<Path>) -> AuditLog {
+        AuditLog {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Appends an entry for `op` on keypair `key`.
+    ///
+    /// Only the last entry is read and checked, so damage earlier in the log
+    /// does not stop the keychain from recording changes; `verify` checks
+    /// the whole chain.
+    ///
+    /// Callers must serialize appends - the keychain does this by holding its
+    /// lock while recording.
+    pub fn append(
+        &self,
+        op: AuditOp,
+        key: impl AsRef<str>,
+        fingerprint: impl AsRef<str>,
+        detail: Option<String>,
+    ) -> Result<(), AuditError> {
+        let (seq, prev) = self
+            .last_entry()?
+            .map(|last| (last.seq + 1, last.hash))
+            .unwrap_or_else(|| (1, String::from(GENESIS_HASH)));
+        let mut entry = AuditEntry {
+            seq,
+            time: Utc::now(),
+            op,
+            key: key.as_ref().to_string(),
+            fingerprint: fingerprint.as_ref().to_string(),
+            detail,
+            uid: current_uid(),
+            host: hostname(),
+            prev,
+            hash: String::new(),
+        };
+        entry.hash = entry.compute_hash();
+        let mut line =
+            serde_json::to_string(&entry).expect("audit entry serialization cannot fail");
+        line.push('\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .map_err(|error| AuditError::Io {
+                path: self.path.clone(),
+                error,
+            })
+    }
+
+    /// Reads the last entry in the log, checking its hash.
+    fn last_entry(&self) -> Result<Option<AuditEntry>, AuditError> {
+        let contents = self.read()?;
+        let (index, line) = match contents.lines().enumerate().last() {
+            Some(last) => last,
+            None => return Ok(None),
+        };
+        let entry: AuditEntry = serde_json::from_str(line).map_err(|error| AuditError::Parse {
+            line: index + 1,
+            error,
+        })?;
+        if entry.hash != entry.compute_hash() {
+            return Err(AuditError::BadHash { seq: entry.seq });
+        }
+        Ok(Some(entry))
+    }
+
+    /// Reads all entries in the log.
+    pub fn entries(&self) -> Result<Vec<AuditEntry>, AuditError> {
+        self.read()?
+            .lines()
+            .enumerate()
+            .map(|(index, line)| {
+                serde_json::from_str(line).map_err(|error| AuditError::Parse {
+                    line: index + 1,
+                    error,
+                })
+            })
+            .collect()
+    }
+
+    /// Reads the log, which is empty if it does not exist yet.
+    fn read(&self) -> Result<String, AuditError> {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => Ok(contents),
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+            Err(error) => Err(AuditError::Io {
+                path: self.path.clone(),
+                error,
+            }),
+        }
+    }
+
+    /// Checks that every entry's hash is correct and chains to the entry
+    /// before it, returning the number of entries verified.
+    pub fn verify(&self) -> Result<usize, AuditError> {
+        let entries = self.entries()?;
+        let mut prev = String::from(GENESIS_HASH);
+        for (index, entry) in entries.iter().enumerate() {
+            if entry.seq != index as u64 + 1 || entry.prev != prev {
+                return Err(AuditError::BrokenChain { seq: entry.seq });
+            }
+            if entry.hash != entry.compute_hash() {
+                return Err(AuditError::BadHash { seq: entry.seq });
+            }
+            prev = entry.hash.clone();
+        }
+        Ok(entries.len())
+    }
+}
+
+/// Lowercase hex encoding of `bytes`.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decodes hex produced by `hex`, returning `None` if `s` is not valid hex.
+pub fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn current_uid() -> Option<u32> {
+    // SAFETY: getuid has no preconditions and cannot fail.
+    Some(unsafe { libc::getuid() })
+}
+
+#[cfg(not(unix))]
+fn current_uid() -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for writes of its full length.
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if result != 0 {
+        return String::from("unknown");
+    }
+    let len = buf.iter().position(|&byte| byte == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| String::from("unknown"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuditLog, AuditOp};
+    use crate::error::AuditError;
+    use crate::keychain::Keychain;
+
+    use assert_fs::prelude::*;
+    use std::fs;
+
+    #[test]
+    fn keychain_operations_are_logged_test() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let keychain = Keychain::open_at(temp.path()).unwrap();
+        let (public, secret) = saltlick::gen_keypair();
+        keychain
+            .create("logged", public.clone(), secret.clone())
+            .unwrap();
+        keychain.set_default("logged").unwrap();
+        keychain.rename("logged", "renamed").unwrap();
+        let keypair = keychain.get("renamed").unwrap();
+        keychain.record_use(AuditOp::Decrypt, &keypair).unwrap();
+        keychain.remove("renamed").unwrap();
+        keychain.import("restored", public, secret).unwrap();
+
+        let entries = keychain.audit_log().entries().unwrap();
+        let ops = entries.iter().map(|entry| entry.op).collect::<Vec<_>>();
+        assert_eq!(
+            ops,
+            vec![
+                AuditOp::Create,
+                AuditOp::SetDefault,
+                AuditOp::Rename,
+                AuditOp::Decrypt,
+                AuditOp::Remove,
+                AuditOp::Import,
+            ]
+        );
+        assert_eq!(entries[2].detail.as_deref(), Some("renamed"));
+        assert!(entries
+            .iter()
+            .all(|entry| entry.fingerprint == keypair.fingerprint()));
+        assert_eq!(keychain.audit_log().verify().unwrap(), 6);
+
+        // Nothing resembling the secret key is written to the log.
+        let contents = fs::read_to_string(temp.child("audit.log").path()).unwrap();
+        assert!(!contents.contains("PRIVATE KEY"));
+    }
+
+    #[test]
+    fn tampering_is_detected_test() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let log_file = temp.child("audit.log");
+        let log = AuditLog::open(log_file.path());
+        for name in &["first", "second", "third"] {
+            log.append(AuditOp::Create, name, "00", None).unwrap();
+        }
+        assert_eq!(log.verify().unwrap(), 3);
+        let original = fs::read_to_string(log_file.path()).unwrap();
+
+        // Editing an entry invalidates its hash.
+        log_file
+            .write_str(&original.replace("second", "altered"))
+            .unwrap();
+        match log.verify() {
+            Err(AuditError::BadHash { seq: 2 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // Damage before the last entry does not stop appends, but the last
+        // entry itself must be intact.
+        log_file
+            .write_str(&original.replacen("{", "not json", 1))
+            .unwrap();
+        log.append(AuditOp::Remove, "third", "00", None).unwrap();
+        let appended = fs::read_to_string(log_file.path()).unwrap();
+        assert!(appended.lines().last().unwrap().contains("\"seq\":4"));
+        log_file
+            .write_str(&original.replace("third", "altered"))
+            .unwrap();
+        match log.append(AuditOp::Remove, "third", "00", None) {
+            Err(AuditError::BadHash { seq: 3 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // Deleting an entry breaks the chain.
+        let without_second = original
+            .lines()
+            .filter(|line| !line.contains("second"))
+            .map(|line| format!("{}\n", line))
+            .collect::<String>();
+        log_file.write_str(&without_second).unwrap();
+        match log.verify() {
+            Err(AuditError::BrokenChain { seq: 3 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Processing many files on a pool of worker threads.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use saltlick::{PublicKey, SecretKey};
+
+use crate::audit::AuditOp;
+use crate::keychain::{public_key_bytes, Keychain, Keypair};
+
+/// Runs `job` on each of `files` using `jobs` worker threads, returning the
+/// files that failed along with their errors, in input order.
+///
+/// A failure does not stop the batch unless `fail_fast` is set, in which
+/// case no new files are started once one has failed.
+pub fn run<E, F>(files: &[PathBuf], jobs: usize, fail_fast: bool, job: F) -> Vec<(PathBuf, E)>
+where
+    E: Send,
+    F: Fn(&Path) -> Result<(), E> + Sync,
+{
+    let next = Mutex::new(files.iter().enumerate());
+    let failures = Mutex::new(Vec::new());
+    let stop = AtomicBool::new(false);
+    thread::scope(|scope| {
+        for _ in 0..jobs.max(1).min(files.len()) {
+            scope.spawn(|| loop {
+                if stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                let (index, path) = match next.lock().unwrap().next() {
+                    Some(item) => item,
+                    None => break,
+                };
+                if let Err(error) = job(path) {
+                    failures.lock().unwrap().push((index, path.clone(), error));
+                    if fail_fast {
+                        stop.store(true, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+    });
+    let mut failures = failures.into_inner().unwrap();
+    failures.sort_by_key(|&(index, _, _)| index);
+    failures
+        .into_iter()
+        .map(|(_, path, error)| (path, error))
+        .collect()
+}
+
+/// Reads a list of files, one per line, from `path`, or stdin if `path` is
+/// "-". Blank lines are ignored.
+pub fn read_file_list(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let reader: Box<dyn BufRead> = if path == Path::new("-") {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        Box::new(BufReader::new(fs::File::open(path)?))
+    };
+    let mut files = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            files.push(PathBuf::from(line));
+        }
+    }
+    Ok(files)
+}
+
+/// Number of worker threads to use when none is given.
+pub fn default_jobs() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+/// Keychain secret key lookups shared between the files of a batch.
+///
+/// Each public key is searched for in the keychain at most once per batch,
+/// and its use is recorded in the audit log once rather than for every file.
+pub struct KeyCache {
+    keychain: Keychain,
+    found: Mutex<HashMap<Vec<u8>, Option<Arc<Keypair>>>>,
+}
+
+impl KeyCache {
+    pub fn new(keychain: Keychain) -> Arc<KeyCache> {
+        Arc::new(KeyCache {
+            keychain,
+            found: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns the secret key matching `public`, if the keychain has one.
+    pub fn lookup(&self, public: &PublicKey) -> Option<SecretKey> {
+        let key = public_key_bytes(public);
+        if let Some(found) = self.found.lock().unwrap().get(&key) {
+            return found.as_ref().map(|keypair| keypair.secret().clone());
+        }
+        // Searching without holding the lock lets other workers use cached
+        // keys meanwhile. Two workers may search for the same key, in which
+        // case the first result is kept.
+        let keypair = self.keychain.find(public).ok().map(Arc::new);
+        let mut found = self.found.lock().unwrap();
+        if found.contains_key(&key) {
+            return found[&key].as_ref().map(|keypair| keypair.secret().clone());
+        }
+        if let Some(keypair) = keypair.as_ref() {
+            if let Err(error) = self.keychain.record_use(AuditOp::Decrypt, keypair) {
+                eprintln!("Warning: {}", error);
+            }
+        }
+        found.insert(key, keypair.clone());
+        keypair.map(|keypair| keypair.secret().clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, KeyCache};
+    use crate::keychain::Keychain;
+
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn batch_collects_errors_test() {
+        let files = (0..20)
+            .map(|i| PathBuf::from(i.to_string()))
+            .collect::<Vec<_>>();
+        let processed = AtomicUsize::new(0);
+        let failures = run(&files, 4, false, |path| {
+            processed.fetch_add(1, Ordering::SeqCst);
+            let n: usize = path.to_str().unwrap().parse().unwrap();
+            if [0, 5, 10, 15].contains(&n) {
+                Err(n)
+            } else {
+                Ok(())
+            }
+        });
+        assert_eq!(processed.load(Ordering::SeqCst), 20);
+        let failed = failures.iter().map(|(_, n)| *n).collect::<Vec<_>>();
+        assert_eq!(failed, vec![0, 5, 10, 15]);
+
+        // With fail-fast a single worker stops at the first failure.
+        let processed = AtomicUsize::new(0);
+        let failures = run(&files, 1, true, |path| {
+            processed.fetch_add(1, Ordering::SeqCst);
+            if path.to_str() == Some("3") {
+                Err(())
+            } else {
+                Ok(())
+            }
+        });
+        assert_eq!(failures.len(), 1);
+        assert_eq!(processed.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn key_cache_test() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let keychain = Keychain::open_at(temp.path()).unwrap();
+        let (public, secret) = saltlick::gen_keypair();
+        keychain
+            .create("cached", public.clone(), secret.clone())
+            .unwrap();
+        let cache = KeyCache::new(Keychain::open_at(temp.path()).unwrap());
+        assert_eq!(cache.lookup(&public), Some(secret.clone()));
+        assert_eq!(cache.lookup(&public), Some(secret));
+        let (unknown, _) = saltlick::gen_keypair();
+        assert_eq!(cache.lookup(&unknown), None);
+
+        // The key was used twice but only recorded once.
+        let entries = keychain.audit_log().entries().unwrap();
+        assert_eq!(entries.len(), 2);
+    }
+}
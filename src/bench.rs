@@ -0,0 +1,213 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Encryption and decryption throughput measurement for `saltlick bench`.
+//!
+//! Data is encrypted to an ephemeral keypair that only exists in memory, so
+//! no keychain is read or written.
+
+use std::fmt::{self, Display};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use saltlick::read::{SaltlickDecrypter, SaltlickEncrypter};
+use saltlick::{PublicKey, SecretKey};
+use serde::Serialize;
+use sodiumoxide::randombytes::randombytes;
+
+use crate::buffer;
+
+/// Input sizes measured when none are given.
+pub const DEFAULT_SIZES: &[usize] = &[64 * 1024, 1024 * 1024, 16 * 1024 * 1024];
+
+/// Read buffer sizes measured when none are given.
+pub const DEFAULT_BUFFER_SIZES: &[usize] = &[8 * 1024, 64 * 1024, 256 * 1024, 1024 * 1024];
+
+/// Operation being measured.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Encrypt,
+    Decrypt,
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operation::Encrypt => f.write_str("encrypt"),
+            Operation::Decrypt => f.write_str("decrypt"),
+        }
+    }
+}
+
+/// Result of timing one operation at one input and buffer size.
+#[derive(Debug, Serialize)]
+pub struct Measurement {
+    pub operation: Operation,
+    /// Plaintext bytes processed per iteration.
+    pub input_size: usize,
+    pub buffer_size: usize,
+    pub iterations: u32,
+    /// Mean wall clock time per iteration.
+    pub seconds: f64,
+    /// Mean user and system CPU time per iteration, where the platform
+    /// reports it.
+    pub cpu_seconds: Option<f64>,
+    /// Plaintext throughput in megabytes (10^6 bytes) per second.
+    pub megabytes_per_second: f64,
+}
+
+/// Encrypts `plaintext` to `public` into `output` as `saltlick encrypt`
+/// does, through read and write buffers of `buffer_size` bytes.
+pub fn encrypt(
+    public: &PublicKey,
+    plaintext: &[u8],
+    buffer_size: usize,
+    output: &mut impl Write,
+) -> io::Result<u64> {
+    let mut encrypter = SaltlickEncrypter::with_capacity(buffer_size, public.clone(), plaintext);
+    buffer::copy(&mut encrypter, output, buffer_size)
+}
+
+/// Decrypts `ciphertext` into `output` as `saltlick decrypt` does, through
+/// read and write buffers of `buffer_size` bytes.
+pub fn decrypt(
+    public: &PublicKey,
+    secret: &SecretKey,
+    ciphertext: &[u8],
+    buffer_size: usize,
+    output: &mut impl Write,
+) -> io::Result<u64> {
+    let mut decrypter =
+        SaltlickDecrypter::with_capacity(buffer_size, public.clone(), secret.clone(), ciphertext);
+    buffer::copy(&mut decrypter, output, buffer_size)
+}
+
+/// Measures encryption and decryption of random data of each of `sizes`
+/// with each of `buffer_sizes`, averaging over `iterations` runs.
+pub fn run(
+    sizes: &[usize],
+    buffer_sizes: &[usize],
+    iterations: u32,
+) -> io::Result<Vec<Measurement>> {
+    let iterations = iterations.max(1);
+    let (public, secret) = saltlick::gen_keypair();
+    let mut measurements = Vec::new();
+    for &size in sizes {
+        let plaintext = randombytes(size);
+        for &buffer_size in buffer_sizes {
+            let mut ciphertext = Vec::new();
+            measurements.push(measure(
+                Operation::Encrypt,
+                size,
+                buffer_size,
+                iterations,
+                || {
+                    ciphertext.clear();
+                    encrypt(&public, &plaintext, buffer_size, &mut ciphertext).map(|_| ())
+                },
+            )?);
+            measurements.push(measure(
+                Operation::Decrypt,
+                size,
+                buffer_size,
+                iterations,
+                || {
+                    let copied =
+                        decrypt(&public, &secret, &ciphertext, buffer_size, &mut io::sink())?;
+                    if copied == size as u64 {
+                        Ok(())
+                    } else {
+                        Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "decrypted size does not match input",
+                        ))
+                    }
+                },
+            )?);
+        }
+    }
+    Ok(measurements)
+}
+
+fn measure(
+    operation: Operation,
+    input_size: usize,
+    buffer_size: usize,
+    iterations: u32,
+    mut f: impl FnMut() -> io::Result<()>,
+) -> io::Result<Measurement> {
+    let cpu_start = cpu_time();
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f()?;
+    }
+    let elapsed = start.elapsed();
+    let cpu = cpu_start.and_then(|cpu_start| Some(cpu_time()?.saturating_sub(cpu_start)));
+    let seconds = elapsed.as_secs_f64() / f64::from(iterations);
+    Ok(Measurement {
+        operation,
+        input_size,
+        buffer_size,
+        iterations,
+        seconds,
+        cpu_seconds: cpu.map(|cpu| cpu.as_secs_f64() / f64::from(iterations)),
+        megabytes_per_second: input_size as f64 / 1e6 / seconds.max(f64::MIN_POSITIVE),
+    })
+}
+
+/// User and system CPU time used by this process so far.
+#[cfg(unix)]
+fn cpu_time() -> Option<Duration> {
+    fn duration(time: libc::timeval) -> Duration {
+        Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000)
+    }
+
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: getrusage fills in the rusage it is given, and it is only read
+    // once the call has succeeded.
+    let usage = unsafe {
+        if libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) != 0 {
+            return None;
+        }
+        usage.assume_init()
+    };
+    Some(duration(usage.ru_utime) + duration(usage.ru_stime))
+}
+
+#[cfg(not(unix))]
+fn cpu_time() -> Option<Duration> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decrypt, encrypt, run, Operation};
+
+    #[test]
+    fn bench_round_trip_test() {
+        let (public, secret) = saltlick::gen_keypair();
+        let plaintext = vec![7u8; 100_000];
+        let mut ciphertext = Vec::new();
+        encrypt(&public, &plaintext, 4096, &mut ciphertext).unwrap();
+        let mut decrypted = Vec::new();
+        decrypt(&public, &secret, &ciphertext, 4096, &mut decrypted).unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn bench_run_test() {
+        let measurements = run(&[1000, 20_000], &[1024, 65536], 2).unwrap();
+        assert_eq!(measurements.len(), 8);
+        assert_eq!(measurements[0].operation, Operation::Encrypt);
+        assert_eq!(measurements[1].operation, Operation::Decrypt);
+        assert_eq!(measurements[7].input_size, 20_000);
+        assert_eq!(measurements[7].buffer_size, 65536);
+        assert!(measurements.iter().all(|m| m.megabytes_per_second > 0.0));
+    }
+}
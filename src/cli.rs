@@ -6,10 +6,16 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::ffi::OsString;
 use std::path::PathBuf;
+use std::str::FromStr;
 
+use chrono::NaiveDate;
 use structopt::StructOpt;
 
+use saltlick_cli::audit::AuditOp;
+use saltlick_cli::values::Format;
+
 /// File and stream operations on saltlick format files.
 #[derive(Debug, StructOpt)]
 #[structopt(name = "saltlick")]
@@ -26,38 +32,267 @@ impl Cli {
 
 #[derive(Debug, StructOpt)]
 pub enum Command {
+    /// Measure encryption and decryption throughput with an ephemeral key.
+    #[structopt(name = "bench")]
+    Bench(BenchArgs),
+
+    /// Decrypt files and print them to stdout.
+    #[structopt(name = "cat")]
+    Cat(CatArgs),
+
+    /// Run or control a key agent that holds unlocked secret keys.
+    #[cfg(unix)]
+    #[structopt(name = "agent")]
+    Agent(AgentArgs),
+
     /// Decrypt an encrypted file.
     #[structopt(name = "decrypt")]
     Decrypt(DecryptArgs),
 
+    /// Decrypt the values of a document encrypted with `encrypt-values`.
+    #[structopt(name = "decrypt-values")]
+    DecryptValues(DecryptValuesArgs),
+
+    /// Decrypt a document encrypted with `encrypt-values` into an editor, and
+    /// encrypt it again when the editor exits.
+    #[structopt(name = "edit-values")]
+    EditValues(EditValuesArgs),
+
+    /// Show differences between the decrypted contents of two files.
+    #[structopt(name = "diff")]
+    Diff(DiffArgs),
+
     /// Encrypt a file or stream.
     #[structopt(name = "encrypt")]
     Encrypt(EncryptArgs),
 
+    /// Encrypt the values of a JSON, TOML or YAML document, leaving its keys
+    /// readable.
+    #[structopt(name = "encrypt-values")]
+    EncryptValues(EncryptValuesArgs),
+
+    /// Run a command with variables from encrypted dotenv files added to its
+    /// environment.
+    #[structopt(name = "exec")]
+    Exec(ExecArgs),
+
     /// Generate new key files.
     #[structopt(name = "generate")]
     Generate(GenerateArgs),
 
+    /// Print lines of encrypted files that match a regular expression.
+    #[structopt(name = "grep")]
+    Grep(GrepArgs),
+
+    /// Print the first lines of encrypted files.
+    #[structopt(name = "head")]
+    Head(HeadArgs),
+
     /// Interact with stored keys.
     #[structopt(name = "keychain")]
     Keychain(KeychainArgs),
 }
 
+#[cfg(unix)]
+#[derive(Debug, StructOpt)]
+pub struct AgentArgs {
+    /// Path of the agent socket.
+    #[structopt(long, env = "SALTLICK_AUTH_SOCK", parse(from_os_str))]
+    pub socket: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    pub cmd: AgentCommand,
+}
+
+#[cfg(unix)]
+#[derive(Debug, StructOpt)]
+pub enum AgentCommand {
+    /// Add a keychain secret key to the agent.
+    #[structopt(name = "add")]
+    Add {
+        /// Keypair name (default keypair if not given).
+        name: Option<String>,
+
+        /// Seconds to hold the key for (agent default if not given).
+        #[structopt(long)]
+        ttl: Option<u64>,
+    },
+
+    /// List keys held by the agent.
+    #[structopt(name = "list")]
+    List,
+
+    /// Discard all keys held by the agent.
+    #[structopt(name = "lock")]
+    Lock,
+
+    /// Run the agent in the foreground until stopped.
+    #[structopt(name = "start")]
+    Start {
+        /// Seconds to hold keys for, unless given when they are added. Keys
+        /// are held until the agent is locked or stopped by default.
+        #[structopt(long)]
+        ttl: Option<u64>,
+    },
+
+    /// Stop the agent, discarding all keys.
+    #[structopt(name = "stop")]
+    Stop,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct BenchArgs {
+    /// Buffer size to measure, in bytes with an optional K, M or G suffix.
+    /// May be given more than once. Defaults to 8K, 64K, 256K and 1M.
+    #[structopt(short, long = "buffer-size", number_of_values = 1)]
+    pub buffer_sizes: Vec<ByteSize>,
+
+    /// Number of times each measurement is repeated and averaged.
+    #[structopt(short = "n", long, default_value = "3")]
+    pub iterations: u32,
+
+    /// Print results as JSON instead of a table.
+    #[structopt(long)]
+    pub json: bool,
+
+    /// Input size to measure, in bytes with an optional K, M or G suffix.
+    /// May be given more than once. Defaults to 64K, 1M and 16M.
+    #[structopt(short, long = "size", number_of_values = 1)]
+    pub sizes: Vec<ByteSize>,
+}
+
+/// Size in bytes, parsed from a number with an optional binary K, M or G
+/// suffix.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ByteSize(pub usize);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ByteSize, String> {
+        let (digits, shift) = match s.char_indices().last() {
+            Some((i, 'K')) | Some((i, 'k')) => (&s[..i], 10),
+            Some((i, 'M')) | Some((i, 'm')) => (&s[..i], 20),
+            Some((i, 'G')) | Some((i, 'g')) => (&s[..i], 30),
+            _ => (s, 0),
+        };
+        digits
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| n.checked_mul(1 << shift))
+            .map(ByteSize)
+            .ok_or_else(|| format!("invalid size \"{}\" (expected e.g. 4096, 64K or 1M)", s))
+    }
+}
+
+/// Range of plaintext bytes, parsed from "START-END" where START and END
+/// are byte offsets accepted by `ByteSize`. START is inclusive and END is
+/// exclusive; START defaults to the beginning and END to the end.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: Option<u64>,
+}
+
+impl FromStr for ByteRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ByteRange, String> {
+        let invalid = || {
+            format!(
+                "invalid range \"{}\" (expected e.g. 100-200, 1M- or -4K)",
+                s
+            )
+        };
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let offset = |offset: &str| -> Result<Option<u64>, String> {
+            if offset.is_empty() {
+                return Ok(None);
+            }
+            let ByteSize(offset) = offset.parse().map_err(|_| invalid())?;
+            Ok(Some(offset as u64))
+        };
+        let range = ByteRange {
+            start: offset(start)?.unwrap_or(0),
+            end: offset(end)?,
+        };
+        match range.end {
+            Some(end) if end < range.start => Err(invalid()),
+            _ => Ok(range),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct CatArgs {
+    /// Encrypted files, decrypted in order. The key for each file is looked
+    /// up in the keychain.
+    #[structopt(required = true, parse(from_os_str))]
+    pub files: Vec<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct BatchArgs {
+    /// Files to process as a batch. Encrypting writes each output alongside
+    /// its input with ".slk" added, and decrypting writes it with ".slk"
+    /// removed.
+    #[structopt(parse(from_os_str), conflicts_with_all = &["infile", "outfile"])]
+    pub files: Vec<PathBuf>,
+
+    /// Read files to process as a batch from this file, one per line, or
+    /// from stdin if it is "-".
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["infile", "outfile"])]
+    pub files_from: Option<PathBuf>,
+
+    /// Stop starting new files in a batch once one has failed.
+    #[structopt(long)]
+    pub fail_fast: bool,
+
+    /// Number of files in a batch to process at once (default: number of
+    /// CPUs).
+    #[structopt(short, long)]
+    pub jobs: Option<usize>,
+}
+
+impl BatchArgs {
+    /// Returns true if files were given to process as a batch.
+    pub fn is_batch(&self) -> bool {
+        !self.files.is_empty() || self.files_from.is_some()
+    }
+}
+
 #[derive(Debug, StructOpt)]
 pub struct DecryptArgs {
+    #[structopt(flatten)]
+    pub batch: BatchArgs,
+
+    /// Restore stored extended attributes in every namespace with
+    /// `--restore-metadata`, not only `user.` ones. Attributes such as
+    /// `security.` ones can change how the system treats the file.
+    #[structopt(long, requires = "restore-metadata")]
+    pub all_xattrs: bool,
+
+    /// Size of the read and write buffers, in bytes with an optional K, M
+    /// or G suffix. Defaults to 256K.
+    #[structopt(long)]
+    pub buffer_size: Option<ByteSize>,
+
     /// Overwrite existing output file without warning.
     #[structopt(short, long)]
     pub force: bool,
 
-    /// Specify input file (stdin by default).
+    /// Specify input file (stdin by default). For output split with
+    /// `encrypt --split-size`, give the first volume or the manifest and the
+    /// volumes are decrypted in order.
     #[structopt(short, long, parse(from_os_str))]
     pub infile: Option<PathBuf>,
 
     /// Specify name of the key (in the keychain) to use to decrypt.
     ///
     /// Specify that only the provided keychain key is to be tried. By default
-    /// saltlick looks for an existing keychain keypair that matches the public
-    /// key that was used to encrypt the input.
+    /// saltlick tries the default keychain keypair, then looks for any
+    /// existing keychain keypair that matches the public key that was used to
+    /// encrypt the input.
     #[structopt(short, long)]
     pub key: Option<String>,
 
@@ -66,6 +301,30 @@ pub struct DecryptArgs {
     #[structopt(short, long, parse(from_os_str))]
     pub public: Option<PathBuf>,
 
+    /// Only decrypt plaintext bytes START (inclusive) to END (exclusive),
+    /// given as "START-END" with optional K, M or G suffixes. Either end
+    /// may be left out. Only the blocks holding the range are read and
+    /// authenticated, so this requires `-i/--infile`. Not supported for
+    /// split output.
+    #[structopt(long, requires = "infile", allow_hyphen_values = true)]
+    pub range: Option<ByteRange>,
+
+    /// Reapply the file name, permissions, timestamps and extended
+    /// attributes stored by `encrypt --preserve-metadata` to the output. If
+    /// `-o/--outfile` is a directory, the output is created in it with the
+    /// stored name. Requires an output file. Setuid, setgid and sticky bits
+    /// are never restored, and only `user.` extended attributes are unless
+    /// `--all-xattrs` is given.
+    #[structopt(long, conflicts_with_all = &["range", "resume"])]
+    pub restore_metadata: bool,
+
+    /// Record progress in a journal next to the output file, and continue
+    /// an interrupted `--resume` run with the same input and output instead
+    /// of starting again. Requires `-i/--infile` and `-o/--outfile`. Not
+    /// supported for split output.
+    #[structopt(long, requires_all = &["infile", "outfile"], conflicts_with = "range")]
+    pub resume: bool,
+
     /// Specify path to a secret keyfile to use to decrypt. Requires that
     /// `-p/--public` is also provided.
     #[structopt(short, long, parse(from_os_str))]
@@ -76,8 +335,130 @@ pub struct DecryptArgs {
     pub outfile: Option<PathBuf>,
 }
 
+#[derive(Debug, StructOpt)]
+pub struct DecryptValuesArgs {
+    /// Overwrite existing output file without warning.
+    #[structopt(short, long)]
+    pub force: bool,
+
+    /// Document format (json, toml or yaml). Guessed from the input file
+    /// extension by default.
+    #[structopt(long)]
+    pub format: Option<Format>,
+
+    /// Specify input file (stdin by default).
+    #[structopt(short, long, parse(from_os_str))]
+    pub infile: Option<PathBuf>,
+
+    /// Specify name of the key (in the keychain) to use to decrypt. By
+    /// default any keychain keypair that is a recipient of the document is
+    /// used.
+    #[structopt(short, long)]
+    pub key: Option<String>,
+
+    /// Specify output file (stdout by default).
+    #[structopt(short, long, parse(from_os_str))]
+    pub outfile: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct DiffArgs {
+    /// Lines of context around each change.
+    #[structopt(short = "U", long, default_value = "3")]
+    pub context: usize,
+
+    /// Run this diff tool on private temporary copies of the decrypted files
+    /// instead of printing a unified diff, e.g. "meld" or "diff -y".
+    #[structopt(long)]
+    pub external: Option<String>,
+
+    /// Largest decrypted size, in bytes, that will be read into memory.
+    #[structopt(long, default_value = "16777216")]
+    pub max_size: u64,
+
+    /// Original encrypted file.
+    #[structopt(parse(from_os_str))]
+    pub old: PathBuf,
+
+    /// Changed encrypted file.
+    #[structopt(parse(from_os_str))]
+    pub new: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct EditValuesArgs {
+    /// Document to edit.
+    #[structopt(parse(from_os_str))]
+    pub file: PathBuf,
+
+    /// Document format (json, toml or yaml). Guessed from the file extension
+    /// by default.
+    #[structopt(long)]
+    pub format: Option<Format>,
+
+    /// Specify name of the key (in the keychain) to use to decrypt.
+    #[structopt(short, long)]
+    pub key: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct EncryptValuesArgs {
+    /// Allow encrypting to a keychain key that has passed its expiry date.
+    #[structopt(long)]
+    pub allow_expired: bool,
+
+    /// Only encrypt values whose dotted path (e.g. "database.password")
+    /// matches this regular expression.
+    #[structopt(long)]
+    pub encrypted_regex: Option<String>,
+
+    /// Overwrite existing output file without warning.
+    #[structopt(short, long)]
+    pub force: bool,
+
+    /// Document format (json, toml or yaml). Guessed from the input file
+    /// extension by default.
+    #[structopt(long)]
+    pub format: Option<Format>,
+
+    /// Specify input file (stdin by default).
+    #[structopt(short, long, parse(from_os_str))]
+    pub infile: Option<PathBuf>,
+
+    /// Name of a keychain key to encrypt to. May be given more than once.
+    /// Defaults to the keychain default keypair if neither this nor
+    /// `-p/--public` is provided.
+    #[structopt(short, long = "key", number_of_values = 1)]
+    pub keys: Vec<String>,
+
+    /// Path to a public keyfile to encrypt to. May be given more than once.
+    #[structopt(short, long = "public", number_of_values = 1, parse(from_os_str))]
+    pub publics: Vec<PathBuf>,
+
+    /// Specify output file (stdout by default).
+    #[structopt(short, long, parse(from_os_str))]
+    pub outfile: Option<PathBuf>,
+}
+
 #[derive(Debug, StructOpt)]
 pub struct EncryptArgs {
+    /// Allow encrypting to a keychain key that has passed its expiry date.
+    #[structopt(long)]
+    pub allow_expired: bool,
+
+    #[structopt(flatten)]
+    pub batch: BatchArgs,
+
+    /// Amount of plaintext sealed in each encrypted block, in bytes with an
+    /// optional K or M suffix. Defaults to 512K.
+    #[structopt(long)]
+    pub block_size: Option<ByteSize>,
+
+    /// Size of the read and write buffers, in bytes with an optional K, M
+    /// or G suffix. Defaults to 256K.
+    #[structopt(long)]
+    pub buffer_size: Option<ByteSize>,
+
     /// Overwrite existing output file without warning.
     #[structopt(short, long)]
     pub force: bool,
@@ -86,21 +467,117 @@ pub struct EncryptArgs {
     #[structopt(short, long, parse(from_os_str))]
     pub infile: Option<PathBuf>,
 
-    /// Specify name of the key (in the keychain) to use to encrypt. Either
-    /// this or `-p/--public` are required.
+    /// Specify name of the key (in the keychain) to use to encrypt. Defaults
+    /// to the keychain default keypair if neither this nor `-p/--public` is
+    /// provided.
     #[structopt(short, long)]
     pub key: Option<String>,
 
-    /// Specify path to a public keyfile to use to encrypt. Either this or
-    /// `-k/--key` are required.
+    /// Store the input file's name, permissions and timestamps inside the
+    /// encrypted output, where `decrypt --restore-metadata` can reapply
+    /// them. Other ways of decrypting the output keep them at the start of
+    /// the plaintext. Requires an input file.
+    #[structopt(long, conflicts_with = "resume")]
+    pub preserve_metadata: bool,
+
+    /// Specify path to a public keyfile to use to encrypt.
     #[structopt(short, long, parse(from_os_str))]
     pub public: Option<PathBuf>,
 
+    /// Remove the input file once the output is written, synced to disk and
+    /// verified by decrypting it again. Inputs that are symbolic links or
+    /// have other hard links are not removed. Requires `-i/--infile` and
+    /// `-o/--outfile`.
+    #[structopt(
+        long,
+        requires_all = &["infile", "outfile"],
+        conflicts_with_all = &["resume", "split-size"]
+    )]
+    pub remove_source: bool,
+
+    /// Record progress in a journal next to the output file, and continue
+    /// an interrupted `--resume` run with the same input and output instead
+    /// of starting again. Requires `-i/--infile` and `-o/--outfile`.
+    ///
+    /// Resuming opens the key to the output with the recipient's secret key
+    /// from the agent or keychain, as `decrypt` does, and starts again if it
+    /// is not available.
+    #[structopt(long, requires_all = &["infile", "outfile"])]
+    pub resume: bool,
+
+    /// Overwrite the input file with random data this many times before
+    /// removing it with `--remove-source`. This only helps on filesystems
+    /// that overwrite files in place; copy-on-write filesystems, snapshots
+    /// and SSDs may keep the original data elsewhere.
+    #[structopt(long, requires = "remove-source")]
+    pub shred: Option<u32>,
+
+    /// Split the output into volumes of at most this many bytes, with an
+    /// optional K, M or G suffix. Volumes are named after the output file
+    /// with ".001", ".002" and so on appended, and are listed with their
+    /// order and hashes in a ".manifest" file alongside them. Each volume is
+    /// encrypted separately, so every one can be verified on its own.
+    #[structopt(long, requires = "outfile", conflicts_with = "resume")]
+    pub split_size: Option<ByteSize>,
+
+    /// Also store the input file's extended attributes with
+    /// `--preserve-metadata`.
+    #[structopt(long, requires = "preserve-metadata")]
+    pub xattrs: bool,
+
     /// Specify output file (stdout by default).
     #[structopt(short, long, parse(from_os_str))]
     pub outfile: Option<PathBuf>,
 }
 
+#[derive(Debug, StructOpt)]
+pub struct ExecArgs {
+    /// Encrypted dotenv file. May be given more than once; files are read in
+    /// order.
+    #[structopt(
+        short,
+        long = "infile",
+        required = true,
+        number_of_values = 1,
+        parse(from_os_str)
+    )]
+    pub infiles: Vec<PathBuf>,
+
+    /// How to handle a variable that is already set, either in the inherited
+    /// environment or by an earlier file: "always" replaces it, "never" keeps
+    /// the existing value, and "error" refuses to run the command.
+    #[structopt(long = "override", default_value = "always")]
+    pub override_policy: OverridePolicy,
+
+    /// Command to run, and its arguments.
+    #[structopt(required = true, last = true, parse(from_os_str))]
+    pub command: Vec<OsString>,
+}
+
+/// Policy for variables defined more than once by `exec`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OverridePolicy {
+    Always,
+    Never,
+    Error,
+}
+
+impl FromStr for OverridePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OverridePolicy, String> {
+        match s {
+            "always" => Ok(OverridePolicy::Always),
+            "never" => Ok(OverridePolicy::Never),
+            "error" => Ok(OverridePolicy::Error),
+            _ => Err(format!(
+                "unknown policy \"{}\" (expected always, never or error)",
+                s
+            )),
+        }
+    }
+}
+
 #[derive(Debug, StructOpt)]
 pub struct GenerateArgs {
     /// Name of output public key file (default public.pem).
@@ -112,8 +589,65 @@ pub struct GenerateArgs {
     pub secret: Option<PathBuf>,
 }
 
+#[derive(Debug, StructOpt)]
+pub struct GrepArgs {
+    /// Print only a count of matching lines per file.
+    #[structopt(short, long)]
+    pub count: bool,
+
+    /// Ignore case when matching.
+    #[structopt(short, long)]
+    pub ignore_case: bool,
+
+    /// Prefix each line with its line number.
+    #[structopt(short = "n", long)]
+    pub line_number: bool,
+
+    /// Never prefix lines with the file name. By default lines are prefixed
+    /// when more than one file is searched.
+    #[structopt(long, conflicts_with = "with-filename")]
+    pub no_filename: bool,
+
+    /// Print lines that do not match.
+    #[structopt(short = "v", long)]
+    pub invert_match: bool,
+
+    /// Always prefix lines with the file name.
+    #[structopt(short = "H", long)]
+    pub with_filename: bool,
+
+    /// Regular expression to search for.
+    pub pattern: String,
+
+    /// Encrypted files to search.
+    #[structopt(required = true, parse(from_os_str))]
+    pub files: Vec<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct HeadArgs {
+    /// Number of lines to print from each file.
+    #[structopt(short = "n", long, default_value = "10")]
+    pub lines: usize,
+
+    /// Encrypted files to read.
+    #[structopt(required = true, parse(from_os_str))]
+    pub files: Vec<PathBuf>,
+}
+
 #[derive(Debug, StructOpt)]
 pub enum KeychainArgs {
+    /// Recover a keypair from Shamir shares and store it in the keychain.
+    #[structopt(name = "combine")]
+    Combine {
+        /// Name for the recovered keypair.
+        name: String,
+
+        /// Share files produced by `keychain split`.
+        #[structopt(required = true, parse(from_os_str))]
+        shares: Vec<PathBuf>,
+    },
+
     /// Export existing keypair entry to files.
     #[structopt(name = "export")]
     Export {
@@ -127,6 +661,14 @@ pub enum KeychainArgs {
         /// Name of output secret key file (default <name>.sec.pem).
         #[structopt(short, long, parse(from_os_str))]
         secret: Option<PathBuf>,
+
+        /// Print the secret key as a checksummed paper backup and QR code.
+        #[structopt(long)]
+        paper: bool,
+
+        /// With `--paper`, also write the QR code to this SVG file.
+        #[structopt(long, requires = "paper", parse(from_os_str))]
+        qr_svg: Option<PathBuf>,
     },
 
     /// Create a new keypair and store it in the keychain.
@@ -134,6 +676,26 @@ pub enum KeychainArgs {
     Generate {
         /// Keypair name.
         name: String,
+
+        /// Derive the keypair from a BIP39 mnemonic phrase read from the
+        /// terminal or stdin. The same phrase and name always produce the same
+        /// keypair.
+        #[structopt(long, conflicts_with_all = &["from-passphrase", "show-mnemonic"])]
+        from_mnemonic: bool,
+
+        /// Derive the keypair from a passphrase read from the terminal or
+        /// stdin. The same passphrase and name always produce the same
+        /// keypair.
+        #[structopt(long, conflicts_with = "show-mnemonic")]
+        from_passphrase: bool,
+
+        /// Generate the keypair from a new random mnemonic phrase and print
+        /// it, so the keypair can later be recreated with `--from-mnemonic`.
+        #[structopt(long)]
+        show_mnemonic: bool,
+
+        #[structopt(flatten)]
+        meta: MetadataArgs,
     },
 
     /// Import existing public/secret key files into keychain.
@@ -143,15 +705,43 @@ pub enum KeychainArgs {
         name: String,
 
         /// Path to public keyfile.
-        public: PathBuf,
+        #[structopt(required_unless = "paper", parse(from_os_str))]
+        public: Option<PathBuf>,
 
         /// Path to secret keyfile.
-        secret: PathBuf,
+        #[structopt(required_unless = "paper", parse(from_os_str))]
+        secret: Option<PathBuf>,
+
+        /// Read a paper backup from stdin instead of key files.
+        #[structopt(long, conflicts_with_all = &["public", "secret"])]
+        paper: bool,
     },
 
     /// List all keypairs in the keychain.
     #[structopt(name = "list")]
-    List,
+    List {
+        /// Show keypair metadata along with names.
+        #[structopt(short, long)]
+        long: bool,
+    },
+
+    /// Show the keychain audit log.
+    #[structopt(name = "log")]
+    Log {
+        /// Only show entries for this keypair.
+        #[structopt(short, long)]
+        key: Option<String>,
+
+        /// Only show entries for this operation (e.g. create, import,
+        /// remove, rename, export, decrypt).
+        #[structopt(long)]
+        op: Option<AuditOp>,
+
+        /// Check the integrity of the log's hash chain instead of printing
+        /// entries.
+        #[structopt(long)]
+        verify: bool,
+    },
 
     /// Remove the specified keypair from the keychain.
     #[structopt(name = "remove")]
@@ -169,4 +759,70 @@ pub enum KeychainArgs {
         /// New keypair name.
         new_name: String,
     },
+
+    /// Split a secret key into Shamir shares, a threshold number of which
+    /// are needed to recover it.
+    #[structopt(name = "split")]
+    Split {
+        /// Keypair name.
+        name: String,
+
+        /// Number of shares to create.
+        #[structopt(long)]
+        shares: u8,
+
+        /// Number of shares needed to recover the key.
+        #[structopt(long)]
+        threshold: u8,
+
+        /// Directory to write share files to.
+        #[structopt(short, long, parse(from_os_str))]
+        outdir: PathBuf,
+    },
+
+    /// Make the specified keypair the default for encryption and decryption.
+    #[structopt(name = "set-default")]
+    SetDefault {
+        /// Keypair name.
+        #[structopt(required_unless = "clear")]
+        name: Option<String>,
+
+        /// Clear the default instead of setting it.
+        #[structopt(long, conflicts_with = "name")]
+        clear: bool,
+    },
+
+    /// Update metadata stored with the specified keypair.
+    #[structopt(name = "set-meta")]
+    SetMeta {
+        /// Keypair name.
+        name: String,
+
+        /// Remove any existing expiry date.
+        #[structopt(long, conflicts_with = "expires")]
+        clear_expires: bool,
+
+        #[structopt(flatten)]
+        meta: MetadataArgs,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub struct MetadataArgs {
+    /// Free-text comment describing the keypair.
+    #[structopt(long)]
+    pub comment: Option<String>,
+
+    /// Date (YYYY-MM-DD) after which the key should not be used to encrypt.
+    #[structopt(long)]
+    pub expires: Option<NaiveDate>,
+
+    /// Email address of the keypair owner.
+    #[structopt(long)]
+    pub owner: Option<String>,
+
+    /// Label to attach to the keypair. May be given multiple times; replaces
+    /// any existing tags.
+    #[structopt(long = "tag", number_of_values = 1)]
+    pub tags: Vec<String>,
 }
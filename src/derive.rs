@@ -0,0 +1,111 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Deterministic keypair derivation from a passphrase or mnemonic.
+//!
+//! The secret key is the output of Argon2id over the input material, salted
+//! with a hash of the keypair name, so the same passphrase produces
+//! unrelated keys under different names. The KDF parameters are part of the
+//! derivation and must never change, or previously derived keys could not
+//! be recovered.
+
+use bip39::Mnemonic;
+use saltlick::{PublicKey, SecretKey};
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::pwhash::argon2id13::{self, Salt, SALTBYTES};
+use sodiumoxide::randombytes::randombytes;
+
+use crate::error::DeriveError;
+use crate::keychain::public_from_secret;
+
+/// Domain separation prefix for the name-bound salt.
+const SALT_CONTEXT: &str = "saltlick-cli keypair derivation v1:";
+
+/// Number of bytes of entropy in generated mnemonics (24 words).
+const MNEMONIC_ENTROPY_BYTES: usize = 32;
+
+/// Derive a keypair for `name` from `passphrase`.
+pub fn keypair_from_passphrase(
+    name: impl AsRef<str>,
+    passphrase: impl AsRef<[u8]>,
+) -> Result<(PublicKey, SecretKey), DeriveError> {
+    if passphrase.as_ref().is_empty() {
+        return Err(DeriveError::EmptyPassphrase);
+    }
+    derive(name, passphrase)
+}
+
+/// Derive a keypair for `name` from a BIP39 English mnemonic phrase.
+pub fn keypair_from_mnemonic(
+    name: impl AsRef<str>,
+    phrase: impl AsRef<str>,
+) -> Result<(PublicKey, SecretKey), DeriveError> {
+    let mnemonic = Mnemonic::parse_normalized(phrase.as_ref().trim())
+        .map_err(|error| DeriveError::InvalidMnemonic(error.to_string()))?;
+    derive(name, mnemonic.to_entropy())
+}
+
+/// Generate a new random mnemonic phrase.
+pub fn generate_mnemonic() -> String {
+    Mnemonic::from_entropy(&randombytes(MNEMONIC_ENTROPY_BYTES))
+        .expect("entropy length is always valid")
+        .to_string()
+}
+
+fn derive(
+    name: impl AsRef<str>,
+    material: impl AsRef<[u8]>,
+) -> Result<(PublicKey, SecretKey), DeriveError> {
+    let mut secret_bytes = [0u8; 32];
+    argon2id13::derive_key(
+        &mut secret_bytes,
+        material.as_ref(),
+        &name_salt(name),
+        argon2id13::OPSLIMIT_MODERATE,
+        argon2id13::MEMLIMIT_MODERATE,
+    )
+    .map_err(|()| DeriveError::KdfFailure)?;
+    let secret = SecretKey::from_raw_curve25519(&secret_bytes)
+        .expect("derived key is always the correct length");
+    sodiumoxide::utils::memzero(&mut secret_bytes);
+    Ok((public_from_secret(&secret), secret))
+}
+
+fn name_salt(name: impl AsRef<str>) -> Salt {
+    let digest = sha256::hash(format!("{}{}", SALT_CONTEXT, name.as_ref()).as_bytes());
+    Salt::from_slice(&digest[..SALTBYTES]).expect("digest is longer than salt")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate_mnemonic, keypair_from_mnemonic, keypair_from_passphrase};
+
+    #[test]
+    fn passphrase_derivation_test() {
+        let (public, secret) = keypair_from_passphrase("backup", "correct horse").unwrap();
+        let (again_public, again_secret) =
+            keypair_from_passphrase("backup", "correct horse").unwrap();
+        assert_eq!(public, again_public);
+        assert_eq!(secret, again_secret);
+
+        // The name is part of the derivation.
+        let (other_public, _) = keypair_from_passphrase("other", "correct horse").unwrap();
+        assert_ne!(public, other_public);
+        keypair_from_passphrase("backup", "").unwrap_err();
+    }
+
+    #[test]
+    fn mnemonic_derivation_test() {
+        let phrase = generate_mnemonic();
+        assert_eq!(phrase.split_whitespace().count(), 24);
+        let (public, _) = keypair_from_mnemonic("backup", &phrase).unwrap();
+        let (again_public, _) = keypair_from_mnemonic("backup", format!("  {}\n", phrase)).unwrap();
+        assert_eq!(public, again_public);
+        keypair_from_mnemonic("backup", "not a valid mnemonic").unwrap_err();
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Parser for dotenv (`.env`) files.
+//!
+//! Supports `KEY=value` lines with an optional `export` prefix, comments,
+//! single-quoted literal values, and double-quoted values with escapes that
+//! may span several lines. Unquoted values run to the end of the line or to
+//! a `#` preceded by whitespace.
+
+use crate::error::DotenvError;
+
+/// Parses `text`, returning variables in the order they are defined.
+pub fn parse(text: &str) -> Result<Vec<(String, String)>, DotenvError> {
+    let mut variables = Vec::new();
+    let mut lines = text.lines().enumerate();
+    while let Some((index, line)) = lines.next() {
+        let line_number = index + 1;
+        let error = |reason: &str| DotenvError {
+            line: line_number,
+            reason: reason.to_string(),
+        };
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line
+            .strip_prefix("export ")
+            .map(str::trim_start)
+            .unwrap_or(line);
+        let (key, rest) = line
+            .split_once('=')
+            .ok_or_else(|| error("expected KEY=value"))?;
+        let key = key.trim_end();
+        if !is_valid_key(key) {
+            return Err(error("invalid variable name"));
+        }
+        let rest = rest.trim_start();
+
+        let value = if let Some(quoted) = rest.strip_prefix('\'') {
+            let end = quoted
+                .find('\'')
+                .ok_or_else(|| error("unterminated single quote"))?;
+            check_trailing(&quoted[end + 1..])
+                .map_err(|()| error("unexpected text after value"))?;
+            quoted[..end].to_string()
+        } else if let Some(quoted) = rest.strip_prefix('"') {
+            let mut value = String::new();
+            let mut current = quoted.to_string();
+            loop {
+                match unescape_until_quote(&current, &mut value) {
+                    Some(trailing) => {
+                        check_trailing(trailing)
+                            .map_err(|()| error("unexpected text after value"))?;
+                        break;
+                    }
+                    None => {
+                        // The value continues on the next line.
+                        value.push('\n');
+                        current = lines
+                            .next()
+                            .map(|(_, line)| line.to_string())
+                            .ok_or_else(|| error("unterminated double quote"))?;
+                    }
+                }
+            }
+            value
+        } else {
+            let end = rest
+                .char_indices()
+                .find(|&(i, c)| c == '#' && rest[..i].ends_with(char::is_whitespace))
+                .map(|(i, _)| i)
+                .unwrap_or(rest.len());
+            rest[..end].trim_end().to_string()
+        };
+        variables.push((key.to_string(), value));
+    }
+    Ok(variables)
+}
+
+fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Appends the unescaped contents of `s` up to a closing double quote to
+/// `value`, returning the text after the quote, or `None` if `s` has no
+/// closing quote.
+fn unescape_until_quote<'a>(s: &'a str, value: &mut String) -> Option<&'a str> {
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some(&s[i + 1..]),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some('t') => value.push('\t'),
+                Some(other) => value.push(other),
+                None => value.push('\\'),
+            },
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// Only whitespace or a comment may follow a quoted value.
+fn check_trailing(trailing: &str) -> Result<(), ()> {
+    let trailing = trailing.trim_start();
+    if trailing.is_empty() || trailing.starts_with('#') {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn dotenv_parse_test() {
+        let text = r#"
+# Database settings
+DB_HOST=db.example.com
+export DB_PASSWORD = 'p@ss #1'   # trailing comment
+DB_URL=postgres://host/db#fragment
+EMPTY=
+GREETING="hello\tworld \"quoted\""
+CERT="-----BEGIN-----
+abc
+-----END-----"
+UNQUOTED=some value # comment
+"#;
+        let variables = parse(text).unwrap();
+        let expected = vec![
+            ("DB_HOST", "db.example.com"),
+            ("DB_PASSWORD", "p@ss #1"),
+            ("DB_URL", "postgres://host/db#fragment"),
+            ("EMPTY", ""),
+            ("GREETING", "hello\tworld \"quoted\""),
+            ("CERT", "-----BEGIN-----\nabc\n-----END-----"),
+            ("UNQUOTED", "some value"),
+        ];
+        assert_eq!(
+            variables,
+            expected
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn dotenv_errors_test() {
+        assert_eq!(parse("NOVALUE").unwrap_err().line, 1);
+        assert_eq!(parse("\n1BAD=x").unwrap_err().line, 2);
+        assert_eq!(parse("A='open").unwrap_err().line, 1);
+        assert_eq!(parse("A=\"open\nstill open").unwrap_err().line, 1);
+        assert_eq!(parse("A=\"x\" junk").unwrap_err().line, 1);
+    }
+}
@@ -10,18 +10,53 @@ use std::error::Error as StdError;
 use std::fmt::{self, Display};
 use std::io;
 use std::path::PathBuf;
+use std::process::ExitStatus;
 
+use chrono::NaiveDate;
 use saltlick::SaltlickKeyIoError;
 
+use crate::buffer::format_size;
+use crate::values::Format;
+
 #[derive(Debug)]
 pub enum CliError {
+    #[cfg(unix)]
+    AgentError {
+        error: AgentError,
+    },
+    BatchFailed {
+        failed: usize,
+        total: usize,
+    },
     BothKeyAndPath {
         type_: String,
     },
+    DeriveError {
+        error: DeriveError,
+    },
+    DotenvError {
+        error: DotenvError,
+        path: PathBuf,
+    },
+    EditorError {
+        editor: String,
+        error: io::Error,
+    },
+    EditorFailed {
+        editor: String,
+        status: ExitStatus,
+    },
+    ExecError {
+        command: String,
+        error: io::Error,
+    },
     InputFileIoError {
         error: io::Error,
         path: PathBuf,
     },
+    InvalidPattern {
+        error: regex::Error,
+    },
     KeychainError {
         error: KeychainError,
     },
@@ -29,24 +64,75 @@ pub enum CliError {
         path: PathBuf,
         type_: String,
     },
+    KeyExpired {
+        name: String,
+        expires: NaiveDate,
+    },
     KeyLoadError {
         error: SaltlickKeyIoError,
         path: PathBuf,
         type_: String,
     },
+    MetadataRequiresFile {
+        option: String,
+    },
     MissingKeyAndPath {
         type_: String,
     },
+    NoOutputName {
+        path: PathBuf,
+    },
+    NoStoredName {
+        dir: PathBuf,
+    },
     OutputFileIoError {
         error: io::Error,
         path: PathBuf,
     },
+    PaperBackupError {
+        error: PaperError,
+    },
+    RemoveError {
+        error: RemoveError,
+        path: PathBuf,
+    },
+    RestoreMetadataError {
+        error: io::Error,
+        path: PathBuf,
+    },
     SaltlickKeyIoError {
         error: SaltlickKeyIoError,
     },
+    ShareError {
+        error: ShareError,
+        path: Option<PathBuf>,
+    },
+    SizeOutOfRange {
+        option: String,
+        size: usize,
+        min: usize,
+        max: usize,
+    },
     StreamIoError {
         error: io::Error,
     },
+    TooLarge {
+        path: PathBuf,
+        max_size: u64,
+    },
+    UnknownFormat {
+        path: Option<PathBuf>,
+    },
+    VariableConflict {
+        name: String,
+    },
+    ValuesError {
+        error: ValuesError,
+    },
+    VolumeError {
+        error: VolumeError,
+        manifest: PathBuf,
+    },
 }
 
 impl StdError for CliError {}
@@ -55,17 +141,39 @@ impl Display for CliError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::CliError::*;
         match self {
+            #[cfg(unix)]
+            AgentError { error } => write!(f, "agent: {}", error),
+            BatchFailed { failed, total } => write!(f, "{} of {} files failed", failed, total),
             BothKeyAndPath { type_ } => write!(
                 f,
                 "only one of \"--key\" or \"--{}\" can be specified",
                 type_
             ),
+            DeriveError { error } => write!(f, "unable to derive keypair: {}", error),
+            DotenvError { error, path } => write!(
+                f,
+                "unable to parse \"{}\": {}",
+                path.to_string_lossy(),
+                error
+            ),
+            EditorError { editor, error } => {
+                write!(f, "unable to run editor \"{}\": {}", editor, error)
+            }
+            EditorFailed { editor, status } => write!(
+                f,
+                "editor \"{}\" failed ({}), changes were not saved",
+                editor, status
+            ),
+            ExecError { command, error } => {
+                write!(f, "unable to run \"{}\": {}", command, error)
+            }
             InputFileIoError { error, path } => write!(
                 f,
                 "unable to read input file \"{}\": {}",
                 path.to_string_lossy(),
                 error
             ),
+            InvalidPattern { error } => write!(f, "invalid pattern: {}", error),
             KeychainError { error } => Display::fmt(error, f),
             KeyExists { path, type_ } => write!(
                 f,
@@ -73,6 +181,11 @@ impl Display for CliError {
                 type_,
                 path.to_string_lossy(),
             ),
+            KeyExpired { name, expires } => write!(
+                f,
+                "keypair \"{}\" expired on {} (use \"--allow-expired\" to override)",
+                name, expires,
+            ),
             KeyLoadError { error, path, type_ } => write!(
                 f,
                 "unable to load {} key from \"{}\": {}",
@@ -80,29 +193,138 @@ impl Display for CliError {
                 path.to_string_lossy(),
                 error,
             ),
+            MetadataRequiresFile { option } => {
+                write!(f, "\"{}\" cannot be used with stdin or stdout", option)
+            }
             MissingKeyAndPath { type_ } => {
                 write!(f, "one of \"--key\" or \"--{}\" must be specified", type_)
             }
+            NoOutputName { path } => write!(
+                f,
+                "unable to name output for \"{}\": it does not end in \".slk\"",
+                path.to_string_lossy()
+            ),
+            NoStoredName { dir } => write!(
+                f,
+                "unable to name output in \"{}\": no file name is stored in the input",
+                dir.to_string_lossy()
+            ),
             OutputFileIoError { error, path } => write!(
                 f,
                 "unable to write output file \"{}\": {}",
                 path.to_string_lossy(),
                 error
             ),
+            PaperBackupError { error } => write!(f, "invalid paper backup: {}", error),
+            RemoveError { error, path } => write!(
+                f,
+                "unable to remove input file \"{}\": {}",
+                path.to_string_lossy(),
+                error
+            ),
+            RestoreMetadataError { error, path } => write!(
+                f,
+                "unable to restore metadata of \"{}\": {}",
+                path.to_string_lossy(),
+                error
+            ),
             SaltlickKeyIoError { error } => Display::fmt(error, f),
+            ShareError {
+                error,
+                path: Some(path),
+            } => write!(f, "share \"{}\": {}", path.to_string_lossy(), error),
+            ShareError { error, path: None } => Display::fmt(error, f),
+            SizeOutOfRange {
+                option,
+                size,
+                min,
+                max,
+            } => write!(
+                f,
+                "\"{}\" must be between {} and {} (got {})",
+                option,
+                format_size(*min),
+                format_size(*max),
+                format_size(*size)
+            ),
             StreamIoError { error } => {
                 write!(f, "error occurred while performing file I/O: {}", error)
             }
+            TooLarge { path, max_size } => write!(
+                f,
+                "decrypted \"{}\" is larger than {} bytes (see \"--max-size\")",
+                path.to_string_lossy(),
+                max_size
+            ),
+            UnknownFormat { path: Some(path) } => write!(
+                f,
+                "unable to tell the format of \"{}\" (use \"--format\")",
+                path.to_string_lossy()
+            ),
+            UnknownFormat { path: None } => {
+                write!(f, "\"--format\" is required when reading from stdin")
+            }
+            ValuesError { error } => Display::fmt(error, f),
+            VariableConflict { name } => write!(
+                f,
+                "environment variable \"{}\" is defined more than once",
+                name
+            ),
+            VolumeError { error, manifest } => write!(
+                f,
+                "split output \"{}\": {}",
+                manifest.to_string_lossy(),
+                error
+            ),
         }
     }
 }
 
+#[cfg(unix)]
+impl From<AgentError> for CliError {
+    fn from(error: AgentError) -> CliError {
+        CliError::AgentError { error }
+    }
+}
+
+impl From<AuditError> for CliError {
+    fn from(error: AuditError) -> CliError {
+        CliError::KeychainError {
+            error: KeychainError::AuditError { error },
+        }
+    }
+}
+
+impl From<DeriveError> for CliError {
+    fn from(error: DeriveError) -> CliError {
+        CliError::DeriveError { error }
+    }
+}
+
 impl From<KeychainError> for CliError {
     fn from(error: KeychainError) -> CliError {
         CliError::KeychainError { error }
     }
 }
 
+impl From<PaperError> for CliError {
+    fn from(error: PaperError) -> CliError {
+        CliError::PaperBackupError { error }
+    }
+}
+
+impl From<ShareError> for CliError {
+    fn from(error: ShareError) -> CliError {
+        CliError::ShareError { error, path: None }
+    }
+}
+
+impl From<ValuesError> for CliError {
+    fn from(error: ValuesError) -> CliError {
+        CliError::ValuesError { error }
+    }
+}
+
 impl From<SaltlickKeyIoError> for CliError {
     fn from(error: SaltlickKeyIoError) -> CliError {
         CliError::SaltlickKeyIoError { error }
@@ -111,10 +333,17 @@ impl From<SaltlickKeyIoError> for CliError {
 
 #[derive(Debug)]
 pub enum KeychainError {
+    AuditError {
+        error: AuditError,
+    },
     BadKeychainDir {
         error: io::Error,
         path: PathBuf,
     },
+    DefaultError {
+        path: PathBuf,
+        error: io::Error,
+    },
     DeleteError {
         name: String,
         error: io::Error,
@@ -137,7 +366,24 @@ pub enum KeychainError {
         name: String,
         error: SaltlickKeyIoError,
     },
+    LockError {
+        path: PathBuf,
+        error: io::Error,
+    },
+    MetadataError {
+        name: String,
+        error: MetadataError,
+    },
     PublicKeyNotFound,
+    RenameError {
+        old_name: String,
+        new_name: String,
+        error: io::Error,
+    },
+    RenameJournalError {
+        path: PathBuf,
+        error: io::Error,
+    },
     SaveError {
         name: String,
         error: SaltlickKeyIoError,
@@ -150,12 +396,19 @@ impl Display for KeychainError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::KeychainError::*;
         match self {
+            AuditError { error } => Display::fmt(error, f),
             BadKeychainDir { error, path } => write!(
                 f,
                 "keychain path \"{}\" is invalid: {}",
                 path.to_string_lossy(),
                 error
             ),
+            DefaultError { path, error } => write!(
+                f,
+                "unable to access default keypair setting \"{}\": {}",
+                path.to_string_lossy(),
+                error
+            ),
             DeleteError { name, error } => write!(f, "error deleting key \"{}\": {}", name, error),
             InvalidKeypairName { name, error } => {
                 write!(f, "keypair name \"{}\" is invalid: {}", name, error)
@@ -169,7 +422,35 @@ impl Display for KeychainError {
             KeypairAlreadyExists { name } => write!(f, "keypair \"{}\" already exists", name),
             KeypairNotFound { name } => write!(f, "keypair \"{}\" not found", name),
             LoadError { name, error } => write!(f, "error loading key \"{}\": {}", name, error),
+            LockError { path, error } => write!(
+                f,
+                "unable to lock keychain \"{}\": {}",
+                path.to_string_lossy(),
+                error
+            ),
+            MetadataError { name, error } => {
+                write!(
+                    f,
+                    "error accessing metadata for key \"{}\": {}",
+                    name, error
+                )
+            }
             PublicKeyNotFound => write!(f, "no matching keypair found for public key"),
+            RenameError {
+                old_name,
+                new_name,
+                error,
+            } => write!(
+                f,
+                "error renaming key \"{}\" to \"{}\": {}",
+                old_name, new_name, error
+            ),
+            RenameJournalError { path, error } => write!(
+                f,
+                "unable to recover interrupted rename from \"{}\": {}",
+                path.to_string_lossy(),
+                error
+            ),
             SaveError { name, error } => write!(f, "error saving key \"{}\": {}", name, error),
         }
     }
@@ -192,3 +473,357 @@ impl Display for InvalidKeypairName {
         }
     }
 }
+
+#[derive(Debug)]
+pub enum MetadataError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Serialize(toml::ser::Error),
+}
+
+impl StdError for MetadataError {}
+
+impl Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::MetadataError::*;
+        match self {
+            Io(error) => Display::fmt(error, f),
+            Parse(error) => write!(f, "invalid metadata file: {}", error),
+            Serialize(error) => write!(f, "unable to serialize metadata: {}", error),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AuditError {
+    BadHash {
+        seq: u64,
+    },
+    BrokenChain {
+        seq: u64,
+    },
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    Parse {
+        line: usize,
+        error: serde_json::Error,
+    },
+}
+
+impl StdError for AuditError {}
+
+impl Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::AuditError::*;
+        match self {
+            BadHash { seq } => write!(f, "audit log entry {} has been modified", seq),
+            BrokenChain { seq } => write!(
+                f,
+                "audit log chain is broken at entry {} - entries were removed or reordered",
+                seq
+            ),
+            Io { path, error } => write!(
+                f,
+                "unable to access audit log \"{}\": {}",
+                path.to_string_lossy(),
+                error
+            ),
+            Parse { line, error } => write!(f, "audit log line {} is invalid: {}", line, error),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PaperError {
+    ChecksumMismatch { line: usize },
+    FingerprintMismatch,
+    InvalidData,
+    MissingLine { line: usize },
+}
+
+impl StdError for PaperError {}
+
+impl Display for PaperError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::PaperError::*;
+        match self {
+            ChecksumMismatch { line } => {
+                write!(f, "checksum mismatch on line {} - check it for typos", line)
+            }
+            FingerprintMismatch => {
+                write!(f, "reconstructed key does not match the backup fingerprint")
+            }
+            InvalidData => write!(f, "backup does not contain a valid secret key"),
+            MissingLine { line } => write!(f, "line {} is missing or out of order", line),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ShareError {
+    BadParameters { shares: u8, threshold: u8 },
+    DuplicateShare,
+    FingerprintMismatch,
+    InvalidShare,
+    MismatchedShares,
+    NotEnoughShares { found: usize, threshold: u8 },
+}
+
+impl StdError for ShareError {}
+
+impl Display for ShareError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ShareError::*;
+        match self {
+            BadParameters { shares, threshold } => write!(
+                f,
+                "cannot split into {} shares with threshold {} - threshold must be at least 2 \
+                 and no more than the number of shares",
+                shares, threshold
+            ),
+            DuplicateShare => write!(f, "the same share was provided more than once"),
+            FingerprintMismatch => write!(
+                f,
+                "recovered key does not match the fingerprint recorded in the shares"
+            ),
+            InvalidShare => write!(f, "share file is invalid"),
+            MismatchedShares => write!(f, "shares do not all belong to the same key split"),
+            NotEnoughShares { found, threshold } => write!(
+                f,
+                "{} shares provided but {} are required",
+                found, threshold
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DeriveError {
+    EmptyPassphrase,
+    InvalidMnemonic(String),
+    KdfFailure,
+    PassphraseMismatch,
+}
+
+impl StdError for DeriveError {}
+
+impl Display for DeriveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::DeriveError::*;
+        match self {
+            EmptyPassphrase => write!(f, "passphrase cannot be empty"),
+            InvalidMnemonic(reason) => write!(f, "invalid mnemonic: {}", reason),
+            KdfFailure => write!(f, "key derivation failed - is enough memory available?"),
+            PassphraseMismatch => write!(f, "passphrases do not match"),
+        }
+    }
+}
+
+#[cfg(unix)]
+#[derive(Debug)]
+pub enum AgentError {
+    Bind { path: PathBuf, error: io::Error },
+    Connect { path: PathBuf, error: io::Error },
+    Io(io::Error),
+    NoSocket,
+    Protocol(String),
+    Remote(String),
+}
+
+#[cfg(unix)]
+impl StdError for AgentError {}
+
+#[cfg(unix)]
+impl Display for AgentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::AgentError::*;
+        match self {
+            Bind { path, error } => write!(
+                f,
+                "unable to listen on \"{}\": {}",
+                path.to_string_lossy(),
+                error
+            ),
+            Connect { path, error } => write!(
+                f,
+                "unable to connect to agent at \"{}\": {}",
+                path.to_string_lossy(),
+                error
+            ),
+            Io(error) => write!(f, "error communicating with agent: {}", error),
+            NoSocket => write!(
+                f,
+                "no agent socket given (use \"--socket\" or set SALTLICK_AUTH_SOCK)"
+            ),
+            Protocol(reason) => write!(f, "invalid response from agent: {}", reason),
+            Remote(message) => write!(f, "agent reported an error: {}", message),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ValuesError {
+    AlreadyEncrypted,
+    BadMetadata(String),
+    InvalidRegex(regex::Error),
+    InvalidValue { path: String },
+    MacMismatch,
+    NoMatchingKey,
+    NotATable,
+    NotEncrypted,
+    Parse { format: Format, message: String },
+    Render { format: Format, message: String },
+}
+
+impl StdError for ValuesError {}
+
+impl Display for ValuesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ValuesError::*;
+        match self {
+            AlreadyEncrypted => write!(f, "document already has encrypted values"),
+            BadMetadata(reason) => write!(f, "invalid saltlick metadata: {}", reason),
+            InvalidRegex(error) => write!(f, "invalid encrypted value regex: {}", error),
+            InvalidValue { path } => write!(f, "unable to decrypt value at \"{}\"", path),
+            MacMismatch => write!(
+                f,
+                "document MAC does not match - the document has been modified"
+            ),
+            NoMatchingKey => write!(f, "no available key matches a document recipient"),
+            NotATable => write!(f, "document must be a map at the top level"),
+            NotEncrypted => write!(f, "document has no saltlick metadata"),
+            Parse { format, message } => write!(f, "unable to parse {}: {}", format, message),
+            Render { format, message } => write!(f, "unable to write {}: {}", format, message),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DotenvError {
+    pub line: usize,
+    pub reason: String,
+}
+
+impl StdError for DotenvError {}
+
+impl Display for DotenvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+#[derive(Debug)]
+pub enum RemoveError {
+    Changed,
+    HardLinked { links: u64 },
+    Io(io::Error),
+    NotAFile,
+    Symlink,
+    Unverified(io::Error),
+}
+
+impl StdError for RemoveError {}
+
+impl Display for RemoveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::RemoveError::*;
+        match self {
+            Changed => write!(f, "it changed while it was being encrypted"),
+            HardLinked { links } => write!(
+                f,
+                "it has {} other hard link{}",
+                links - 1,
+                if *links == 2 { "" } else { "s" }
+            ),
+            Io(error) => Display::fmt(error, f),
+            NotAFile => write!(f, "it is not a regular file"),
+            Symlink => write!(f, "it is a symbolic link"),
+            Unverified(error) => write!(f, "the output could not be verified: {}", error),
+        }
+    }
+}
+
+impl From<io::Error> for RemoveError {
+    fn from(error: io::Error) -> RemoveError {
+        RemoveError::Io(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum VolumeError {
+    HashMismatch,
+    InvalidManifest(String),
+    LengthMismatch {
+        name: String,
+        expected: u64,
+        actual: u64,
+    },
+    Mismatch {
+        name: String,
+    },
+    Missing {
+        name: String,
+    },
+    OutOfOrder {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    Truncated {
+        name: String,
+    },
+    Unsupported {
+        option: String,
+    },
+}
+
+impl StdError for VolumeError {}
+
+impl Display for VolumeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::VolumeError::*;
+        match self {
+            HashMismatch => write!(
+                f,
+                "volumes do not match the stream hash recorded in the manifest"
+            ),
+            InvalidManifest(reason) => write!(f, "invalid manifest: {}", reason),
+            LengthMismatch {
+                name,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "volume \"{}\" holds {} bytes of plaintext but the manifest records {}",
+                name, actual, expected
+            ),
+            Mismatch { name } => write!(
+                f,
+                "volume \"{}\" is damaged or does not belong to this set",
+                name
+            ),
+            Missing { name } => write!(f, "volume \"{}\" is missing", name),
+            OutOfOrder {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "volume \"{}\" is out of order: it should be part {} but is part {}",
+                name, expected, found
+            ),
+            Truncated { name } => write!(
+                f,
+                "volume \"{}\" is not the last of its set, the volumes after it are missing",
+                name
+            ),
+            Unsupported { option } => write!(
+                f,
+                "{} cannot be used with split output, which is only decrypted as a whole",
+                option
+            ),
+        }
+    }
+}
@@ -0,0 +1,404 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Original file name, permissions, timestamps and extended attributes,
+//! stored by `encrypt --preserve-metadata` and reapplied by
+//! `decrypt --restore-metadata`.
+//!
+//! The metadata is written as a block at the start of the plaintext, so it
+//! is encrypted and authenticated along with the file contents. The block is
+//! `MAGIC`, the length of the metadata as a 32-bit big-endian integer, and
+//! the metadata as JSON.
+//!
+//! Nothing outside the plaintext records whether it starts with a block, and
+//! any file may start with `MAGIC`. The block is therefore only looked for,
+//! and removed, when restoring the metadata is asked for. Everything else
+//! decrypts the plaintext unchanged, block included, warning when it starts
+//! with `MAGIC`.
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Cursor, Read};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+
+#[cfg(unix)]
+use crate::audit::{hex, hex_decode};
+
+/// Start of a metadata block.
+pub const MAGIC: &[u8; 16] = b"\0saltlick-meta\0\x01";
+
+/// Largest metadata block accepted when decrypting.
+const MAX_METADATA_LEN: u32 = 16 << 20;
+
+/// Namespace of the extended attributes restored by default. Attributes in
+/// other namespaces, such as `security.` and `trusted.`, can change how the
+/// system treats the file, so they are only restored when asked for.
+pub const USER_XATTR_PREFIX: &str = "user.";
+
+/// Metadata of an encrypted file, as it was when it was encrypted.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct FileMetadata {
+    /// File name, without any directory.
+    pub name: Option<String>,
+
+    /// Unix permission bits.
+    pub mode: Option<u32>,
+
+    /// Time the file was last modified.
+    pub modified: Option<DateTime<Utc>>,
+
+    /// Time the file was last accessed.
+    pub accessed: Option<DateTime<Utc>>,
+
+    /// Extended attribute values in hex, by name.
+    pub xattrs: BTreeMap<String, String>,
+}
+
+impl FileMetadata {
+    /// Reads the metadata of the file at `path`, including its extended
+    /// attributes if `xattrs` is true and the platform has them.
+    pub fn read(path: &Path, xattrs: bool) -> io::Result<FileMetadata> {
+        let metadata = fs::metadata(path)?;
+        #[cfg(unix)]
+        let mode = Some(metadata.permissions().mode() & 0o7777);
+        #[cfg(not(unix))]
+        let mode = None;
+        let mut file_metadata = FileMetadata {
+            name: path.file_name().and_then(OsStr::to_str).map(str::to_string),
+            mode,
+            modified: metadata.modified().ok().map(DateTime::from),
+            accessed: metadata.accessed().ok().map(DateTime::from),
+            xattrs: BTreeMap::new(),
+        };
+        #[cfg(unix)]
+        if xattrs && xattr::SUPPORTED_PLATFORM {
+            for name in xattr::list(path)? {
+                if let (Some(name), Some(value)) = (name.to_str(), xattr::get(path, &name)?) {
+                    file_metadata.xattrs.insert(name.to_string(), hex(&value));
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = xattrs;
+        Ok(file_metadata)
+    }
+
+    /// Returns the metadata block to write at the start of the plaintext.
+    pub fn to_block(&self) -> Vec<u8> {
+        let json = serde_json::to_vec(self).expect("file metadata serializes");
+        let mut block = Vec::with_capacity(MAGIC.len() + 4 + json.len());
+        block.extend_from_slice(MAGIC);
+        block.extend_from_slice(&(json.len() as u32).to_be_bytes());
+        block.extend_from_slice(&json);
+        block
+    }
+
+    /// Returns the stored file name if it is a plain file name that is safe
+    /// to create in a directory: not empty, and with no directory parts.
+    pub fn output_name(&self) -> Option<&str> {
+        self.name
+            .as_deref()
+            .filter(|name| Path::new(name).file_name() == Some(OsStr::new(name)))
+    }
+
+    /// Applies the metadata, other than the name, to the file at `path`,
+    /// returning the names of the extended attributes it left out.
+    ///
+    /// Only the read, write and execute permission bits are restored, never
+    /// setuid, setgid or sticky. Only extended attributes in the `user.`
+    /// namespace are restored unless `all_xattrs` is true. Permissions are
+    /// set last, so a read-only mode does not stop the rest being applied.
+    pub fn apply(&self, path: &Path, all_xattrs: bool) -> io::Result<Vec<String>> {
+        let mut skipped = Vec::new();
+        #[cfg(unix)]
+        for (name, value) in &self.xattrs {
+            if !all_xattrs && !name.starts_with(USER_XATTR_PREFIX) {
+                skipped.push(name.clone());
+                continue;
+            }
+            let value = hex_decode(value).ok_or_else(|| {
+                invalid_metadata(&format!("invalid value for extended attribute {}", name))
+            })?;
+            xattr::set(path, name, &value)?;
+        }
+        #[cfg(not(unix))]
+        let _ = all_xattrs;
+        if let Some(modified) = self.modified {
+            let modified = FileTime::from_system_time(modified.into());
+            let accessed = self.accessed.map_or(modified, |accessed| {
+                FileTime::from_system_time(accessed.into())
+            });
+            filetime::set_file_times(path, accessed, modified)?;
+        }
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777))?;
+        }
+        Ok(skipped)
+    }
+}
+
+fn invalid_metadata(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid file metadata: {}", reason),
+    )
+}
+
+/// Reader over decrypted plaintext that removes the metadata block from its
+/// start, if there is one and it is looked for.
+pub struct MetadataReader<R> {
+    inner: R,
+    /// Bytes read while looking for the block that turned out not to be
+    /// part of one, or `None` until the block has been looked for.
+    prefix: Option<Cursor<Vec<u8>>>,
+    /// Whether a block at the start is removed or left in place.
+    remove: bool,
+    /// Whether the plaintext starts with `MAGIC` but was left unchanged.
+    kept_block: bool,
+    metadata: Option<FileMetadata>,
+    block_len: u64,
+}
+
+impl<R: Read> MetadataReader<R> {
+    /// Returns a reader that removes the metadata block from the start of
+    /// `inner`, if there is one.
+    pub fn new(inner: R) -> MetadataReader<R> {
+        MetadataReader {
+            inner,
+            prefix: None,
+            remove: true,
+            kept_block: false,
+            metadata: None,
+            block_len: 0,
+        }
+    }
+
+    /// Returns a reader that passes `inner` through unchanged, with no
+    /// metadata, even if it starts with a block.
+    pub fn passthrough(inner: R) -> MetadataReader<R> {
+        MetadataReader {
+            remove: false,
+            ..MetadataReader::new(inner)
+        }
+    }
+
+    /// Returns the stored metadata, or `None` if the plaintext has none.
+    pub fn metadata(&mut self) -> io::Result<Option<&FileMetadata>> {
+        self.read_block()?;
+        Ok(self.metadata.as_ref())
+    }
+
+    /// Returns the length of the metadata block, or 0 if there is none.
+    pub fn block_len(&mut self) -> io::Result<u64> {
+        self.read_block()?;
+        Ok(self.block_len)
+    }
+
+    /// Returns true if the reader passes the plaintext through unchanged
+    /// and it starts with `MAGIC`, so probably with a block.
+    pub fn kept_block(&mut self) -> io::Result<bool> {
+        self.read_block()?;
+        Ok(self.kept_block)
+    }
+
+    fn read_block(&mut self) -> io::Result<()> {
+        if self.prefix.is_some() {
+            return Ok(());
+        }
+        let mut magic = Vec::with_capacity(MAGIC.len());
+        (&mut self.inner)
+            .take(MAGIC.len() as u64)
+            .read_to_end(&mut magic)?;
+        if magic[..] != MAGIC[..] || !self.remove {
+            self.kept_block = magic[..] == MAGIC[..];
+            self.prefix = Some(Cursor::new(magic));
+            return Ok(());
+        }
+
+        let truncated = |error: io::Error| match error.kind() {
+            io::ErrorKind::UnexpectedEof => invalid_metadata("truncated"),
+            _ => error,
+        };
+        let mut len = [0u8; 4];
+        self.inner.read_exact(&mut len).map_err(truncated)?;
+        let len = u32::from_be_bytes(len);
+        if len > MAX_METADATA_LEN {
+            return Err(invalid_metadata("too large"));
+        }
+        let mut json = vec![0u8; len as usize];
+        self.inner.read_exact(&mut json).map_err(truncated)?;
+        let metadata =
+            serde_json::from_slice(&json).map_err(|error| invalid_metadata(&error.to_string()))?;
+        self.metadata = Some(metadata);
+        self.block_len = (MAGIC.len() + 4 + json.len()) as u64;
+        self.prefix = Some(Cursor::new(Vec::new()));
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for MetadataReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_block()?;
+        let prefix = self.prefix.as_mut().expect("metadata block was read");
+        match prefix.read(buf)? {
+            0 => self.inner.read(buf),
+            n => Ok(n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Read;
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::{FileMetadata, MetadataReader, MAGIC};
+
+    fn read_all<R: Read>(mut reader: MetadataReader<R>) -> Vec<u8> {
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn metadata_reader_test() {
+        let metadata = FileMetadata {
+            name: Some("notes.txt".to_string()),
+            mode: Some(0o640),
+            modified: Some(Utc.with_ymd_and_hms(2020, 5, 1, 12, 0, 0).unwrap()),
+            ..FileMetadata::default()
+        };
+        let mut plaintext = metadata.to_block();
+        let block_len = plaintext.len() as u64;
+        plaintext.extend_from_slice(b"contents");
+
+        let mut reader = MetadataReader::new(&plaintext[..]);
+        assert_eq!(reader.metadata().unwrap(), Some(&metadata));
+        assert_eq!(reader.block_len().unwrap(), block_len);
+        assert_eq!(read_all(reader), b"contents");
+
+        // Plaintext without a block, even one that starts like one, is
+        // passed through unchanged.
+        for &plaintext in &[&b""[..], b"short", b"\0saltlick-meta\0\x02 and more"] {
+            let mut reader = MetadataReader::new(plaintext);
+            assert_eq!(reader.metadata().unwrap(), None);
+            assert_eq!(reader.block_len().unwrap(), 0);
+            assert_eq!(read_all(reader), plaintext);
+        }
+
+        // Unless the block is looked for, it is left in place.
+        let mut reader = MetadataReader::passthrough(&plaintext[..]);
+        assert_eq!(reader.metadata().unwrap(), None);
+        assert_eq!(reader.block_len().unwrap(), 0);
+        assert!(reader.kept_block().unwrap());
+        assert_eq!(read_all(reader), plaintext);
+        assert!(!MetadataReader::passthrough(&b"contents"[..])
+            .kept_block()
+            .unwrap());
+        let damaged = &plaintext[..MAGIC.len() + 2];
+        assert_eq!(read_all(MetadataReader::passthrough(damaged)), damaged);
+
+        // A damaged or truncated block is an error.
+        let block = metadata.to_block();
+        for partial in &[&block[..MAGIC.len() + 2], &block[..block.len() - 1]] {
+            assert!(MetadataReader::new(*partial).metadata().is_err());
+        }
+        let mut damaged = block.clone();
+        damaged[MAGIC.len() + 4] = b'[';
+        assert!(MetadataReader::new(&damaged[..])
+            .read(&mut [0; 10])
+            .is_err());
+    }
+
+    #[test]
+    fn output_name_test() {
+        let named = |name: &str| FileMetadata {
+            name: Some(name.to_string()),
+            ..FileMetadata::default()
+        };
+        assert_eq!(named("notes.txt").output_name(), Some("notes.txt"));
+        assert_eq!(named(".env").output_name(), Some(".env"));
+        for &name in &["", ".", "..", "a/b", "/etc/passwd", "../x", "dir/"] {
+            assert_eq!(named(name).output_name(), None, "{:?}", name);
+        }
+        assert_eq!(FileMetadata::default().output_name(), None);
+    }
+
+    #[test]
+    fn read_and_apply_test() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let source = temp.path().join("source.txt");
+        fs::write(&source, b"contents").unwrap();
+        #[cfg(unix)]
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o640)).unwrap();
+        let modified = Utc.with_ymd_and_hms(2001, 2, 3, 4, 5, 6).unwrap();
+        filetime::set_file_mtime(
+            &source,
+            filetime::FileTime::from_system_time(modified.into()),
+        )
+        .unwrap();
+
+        let metadata = FileMetadata::read(&source, false).unwrap();
+        assert_eq!(metadata.name.as_deref(), Some("source.txt"));
+        assert_eq!(metadata.modified, Some(modified));
+        #[cfg(unix)]
+        assert_eq!(metadata.mode, Some(0o640));
+
+        let target = temp.path().join("target.txt");
+        fs::write(&target, b"contents").unwrap();
+        assert!(metadata.apply(&target, false).unwrap().is_empty());
+        let applied = FileMetadata::read(&target, false).unwrap();
+        assert_eq!(applied.modified, Some(modified));
+        assert_eq!(applied.mode, metadata.mode);
+    }
+
+    #[test]
+    fn apply_restricted_test() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let target = temp.path().join("target");
+        fs::write(&target, b"contents").unwrap();
+        let mut metadata = FileMetadata {
+            mode: Some(0o4755),
+            ..FileMetadata::default()
+        };
+        metadata
+            .xattrs
+            .insert("security.test".to_string(), "01".to_string());
+        metadata
+            .xattrs
+            .insert("user.test".to_string(), "02".to_string());
+
+        // Setuid is dropped, and only the `user.` attribute is restored.
+        let skipped = metadata.apply(&target, false).unwrap();
+        #[cfg(unix)]
+        {
+            assert_eq!(skipped, vec!["security.test".to_string()]);
+            let mode = fs::metadata(&target).unwrap().permissions().mode();
+            assert_eq!(mode & 0o7777, 0o755);
+            if xattr::SUPPORTED_PLATFORM {
+                assert_eq!(xattr::get(&target, "user.test").unwrap(), Some(vec![2]));
+                assert_eq!(xattr::get(&target, "security.test").unwrap(), None);
+            }
+        }
+        #[cfg(not(unix))]
+        assert!(skipped.is_empty());
+    }
+}
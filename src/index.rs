@@ -0,0 +1,168 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Index from public key fingerprints to keypair names, so that finding the
+//! keypair for a public key does not load every key in the keychain.
+//!
+//! The index is a text file in the keychain directory. Its first line holds
+//! the modification time of the directory when the index was written along
+//! with the number of entries, and each following line holds a fingerprint
+//! and a keypair name. Adding, removing or renaming any file in the
+//! directory changes its modification time, so an index written before such
+//! a change is ignored. For the same reason the index is rewritten in place
+//! rather than replaced.
+//!
+//! The index is only ever a hint. Names found through it must be checked
+//! against the key files.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::keychain::KeypairName;
+
+/// First word of the index file, identifying its format.
+const INDEX_VERSION: &str = "saltlick-index-v1";
+
+/// Fingerprint to keypair name mappings.
+#[derive(Debug, Default, PartialEq)]
+pub struct KeyIndex {
+    entries: Vec<(String, KeypairName)>,
+}
+
+impl KeyIndex {
+    /// Returns the names of keypairs with the given public key fingerprint.
+    pub fn names<'a>(&'a self, fingerprint: &'a str) -> impl Iterator<Item = &'a KeypairName> {
+        self.entries
+            .iter()
+            .filter(move |(entry, _)| entry == fingerprint)
+            .map(|(_, name)| name)
+    }
+
+    /// Adds a keypair to the index.
+    pub fn insert(&mut self, fingerprint: String, name: KeypairName) {
+        self.entries.push((fingerprint, name));
+    }
+
+    /// Removes the keypair with `name` from the index.
+    pub fn remove(&mut self, name: &KeypairName) {
+        self.entries.retain(|(_, entry)| entry != name);
+    }
+
+    /// Updates the index for a keypair renamed from `old_name` to
+    /// `new_name`.
+    pub fn rename(&mut self, old_name: &KeypairName, new_name: &KeypairName) {
+        for (_, name) in self.entries.iter_mut() {
+            if name == old_name {
+                *name = new_name.clone();
+            }
+        }
+    }
+
+    /// Loads the index at `path` for the keychain directory `dir`. Returns
+    /// `None` if there is no index, it cannot be parsed, or the directory
+    /// has changed since it was written.
+    pub fn load(path: impl AsRef<Path>, dir: impl AsRef<Path>) -> Option<KeyIndex> {
+        let contents = fs::read_to_string(path).ok()?;
+        let mut lines = contents.lines();
+        let header = lines.next()?.split(' ').collect::<Vec<_>>();
+        let (written, count) = match header.as_slice() {
+            [INDEX_VERSION, written, count] => (*written, count.parse::<usize>().ok()?),
+            _ => return None,
+        };
+        if written != modified_stamp(dir.as_ref())? {
+            return None;
+        }
+        let entries = lines
+            .map(|line| {
+                let (fingerprint, name) = line.split_once(' ')?;
+                Some((fingerprint.to_string(), KeypairName::new(name).ok()?))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        // A short count means the index was read while being rewritten.
+        if entries.len() == count {
+            Some(KeyIndex { entries })
+        } else {
+            None
+        }
+    }
+
+    /// Writes the index to `path`, stamped with the current modification
+    /// time of the keychain directory `dir`.
+    pub fn save(&self, path: impl AsRef<Path>, dir: impl AsRef<Path>) -> io::Result<()> {
+        // Creating the file changes the directory, so it must exist before
+        // the directory is stamped.
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        let stamp = modified_stamp(dir.as_ref()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "directory modification time is unavailable",
+            )
+        })?;
+        let mut contents = format!("{} {} {}\n", INDEX_VERSION, stamp, self.entries.len());
+        for (fingerprint, name) in &self.entries {
+            contents.push_str(&format!("{} {}\n", fingerprint, name));
+        }
+        file.set_len(0)?;
+        file.write_all(contents.as_bytes())
+    }
+}
+
+/// Returns the modification time of `dir` as text, to the full precision
+/// available.
+fn modified_stamp(dir: &Path) -> Option<String> {
+    let modified = fs::metadata(dir).ok()?.modified().ok()?;
+    let since_epoch = modified.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!(
+        "{}.{:09}",
+        since_epoch.as_secs(),
+        since_epoch.subsec_nanos()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{modified_stamp, KeyIndex};
+    use crate::keychain::KeypairName;
+
+    use assert_fs::prelude::*;
+
+    #[test]
+    fn index_round_trip_test() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let path = temp.child("index");
+        let name = |name: &str| KeypairName::new(name).unwrap();
+
+        let mut index = KeyIndex::default();
+        index.insert(String::from("aaaa"), name("first"));
+        index.insert(String::from("bbbb"), name("second"));
+        index.insert(String::from("aaaa"), name("copy"));
+        index.remove(&name("second"));
+        index.rename(&name("copy"), &name("renamed"));
+        let names = index.names("aaaa").cloned().collect::<Vec<_>>();
+        assert_eq!(names, vec![name("first"), name("renamed")]);
+
+        index.save(path.path(), temp.path()).unwrap();
+        assert_eq!(KeyIndex::load(path.path(), temp.path()), Some(index));
+
+        // Any change to the directory invalidates the index.
+        temp.child("new.pub").touch().unwrap();
+        assert_eq!(KeyIndex::load(path.path(), temp.path()), None);
+
+        // As does a partially written one.
+        let stamp = modified_stamp(temp.path()).unwrap();
+        path.write_str(&format!("saltlick-index-v1 {} 2\naaaa first\n", stamp))
+            .unwrap();
+        assert_eq!(KeyIndex::load(path.path(), temp.path()), None);
+    }
+}
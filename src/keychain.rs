@@ -8,18 +8,45 @@
 
 use std::collections::HashSet;
 use std::fmt::{self, Display};
-use std::fs;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use directories::ProjectDirs;
-use saltlick::{PublicKey, SecretKey};
+use fs2::FileExt;
+use saltlick::{PublicKey, SaltlickKeyIoError, SecretKey, PUBLICKEYBYTES, SECRETKEYBYTES};
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::scalarmult::curve25519::{scalarmult_base, Scalar};
+use sodiumoxide::utils::memzero;
 
+use crate::audit::{self, AuditLog, AuditOp};
 use crate::error::{InvalidKeypairName, KeychainError};
+use crate::index::KeyIndex;
+use crate::metadata::KeyMetadata;
+use crate::secret::SecretBytes;
+
+/// File in the keychain directory used for advisory locking.
+const LOCK_FILENAME: &str = ".lock";
+
+/// File in the keychain directory holding the audit log.
+const AUDIT_LOG_FILENAME: &str = "audit.log";
+
+/// File in the keychain directory recording an in-progress rename.
+const RENAME_JOURNAL_FILENAME: &str = "rename.journal";
+
+/// File in the keychain directory mapping fingerprints to keypair names.
+const INDEX_FILENAME: &str = "index";
 
 /// Accessor to keychain directory for saltlick CLI.
+///
+/// Operations that modify the keychain hold an exclusive advisory lock on
+/// the keychain directory for their duration, so concurrent `saltlick`
+/// processes cannot interleave their changes. Each modification is recorded
+/// in the keychain's audit log.
 #[derive(Debug)]
 pub struct Keychain {
+    audit_log: AuditLog,
     key_dir: PathBuf,
 }
 
@@ -35,9 +62,18 @@ impl Keychain {
             path: path.as_ref().to_path_buf(),
             error,
         })?;
-        Ok(Keychain {
+        let keychain = Keychain {
+            audit_log: AuditLog::open(path.as_ref().join(AUDIT_LOG_FILENAME)),
             key_dir: path.as_ref().to_path_buf(),
-        })
+        };
+
+        // Finish any rename that was interrupted, so that readers never see
+        // a keypair split between its old and new names. Taking the lock
+        // performs the recovery.
+        if keychain.rename_journal_path().is_file() {
+            keychain.lock()?;
+        }
+        Ok(keychain)
     }
 
     fn config_dir() -> PathBuf {
@@ -65,6 +101,39 @@ impl Keychain {
         name: impl AsRef<str>,
         public: PublicKey,
         secret: SecretKey,
+    ) -> Result<(), KeychainError> {
+        self.create_with_metadata(name, public, secret, KeyMetadata::now())
+    }
+
+    /// Create a keypair as with `create`, storing `metadata` alongside it.
+    pub fn create_with_metadata(
+        &self,
+        name: impl AsRef<str>,
+        public: PublicKey,
+        secret: SecretKey,
+        metadata: KeyMetadata,
+    ) -> Result<(), KeychainError> {
+        self.insert(name, public, secret, metadata, AuditOp::Create)
+    }
+
+    /// Create a keypair as with `create` from keys that already existed
+    /// outside the keychain, recording it in the audit log as an import.
+    pub fn import(
+        &self,
+        name: impl AsRef<str>,
+        public: PublicKey,
+        secret: SecretKey,
+    ) -> Result<(), KeychainError> {
+        self.insert(name, public, secret, KeyMetadata::now(), AuditOp::Import)
+    }
+
+    fn insert(
+        &self,
+        name: impl AsRef<str>,
+        public: PublicKey,
+        secret: SecretKey,
+        metadata: KeyMetadata,
+        op: AuditOp,
     ) -> Result<(), KeychainError> {
         let keypair_name = Keypair::parse_keypair_name(name)?;
         let keypair = Keypair {
@@ -72,7 +141,15 @@ impl Keychain {
             public,
             secret,
         };
-        keypair.save(&self.key_dir)
+        let _lock = self.lock()?;
+        let index = self.load_index();
+        keypair.save(&self.key_dir)?;
+        keypair.save_metadata(&self.key_dir, &metadata)?;
+        self.append_audit(op, &keypair, None)?;
+        self.update_index(index, |index| {
+            index.insert(keypair.fingerprint(), keypair.name.clone())
+        });
+        Ok(())
     }
 
     /// Get the keypair with the specified `name`, if it exists.
@@ -83,38 +160,491 @@ impl Keychain {
         Keypair::load(&self.key_dir, name)
     }
 
+    /// Get the public key of the keypair with the specified `name`, without
+    /// loading its secret key.
+    ///
+    /// Returns an error if the keychain directory is not readable or the
+    /// specified key is not found.
+    pub fn get_public(&self, name: impl AsRef<str>) -> Result<PublicKey, KeychainError> {
+        let name = Keypair::parse_keypair_name(name)?;
+        if !self.exists(&name) {
+            return Err(KeychainError::KeypairNotFound {
+                name: name.to_string(),
+            });
+        }
+        PublicKey::from_file(self.key_dir.join(name.public_filename())).map_err(|error| {
+            KeychainError::LoadError {
+                name: name.to_string(),
+                error,
+            }
+        })
+    }
+
     /// Find a keypair with the matching public key, if it exists.
     ///
+    /// Looks the key up in the keychain index, so only the matching secret
+    /// key is loaded. If the index is out of date, has no entry for the key
+    /// or names a keypair that does not match, it is rebuilt from the public
+    /// key files first.
+    ///
     /// Returns an error if the keychain directory is not readable or no
     /// matching key is found.
     pub fn find(&self, public: &PublicKey) -> Result<Keypair, KeychainError> {
-        KeychainIter::new(&self.key_dir)?
-            .find(|keypair| keypair.public() == public)
+        self.find_fingerprint(&fingerprint(public))
+    }
+
+    /// Find a keypair whose public key has the given fingerprint, as with
+    /// `find`.
+    pub fn find_fingerprint(&self, fingerprint: &str) -> Result<Keypair, KeychainError> {
+        if let Some(index) = self.load_index() {
+            if let Some(keypair) = self.find_indexed(&index, fingerprint) {
+                return Ok(keypair);
+            }
+        }
+        let index = self.rebuild_index()?;
+        self.find_indexed(&index, fingerprint)
             .ok_or(KeychainError::PublicKeyNotFound)
     }
 
+    /// Returns the names of all keypairs in the keychain, in order, without
+    /// loading any keys.
+    ///
+    /// Returns an error if the keychain directory is not listable.
+    pub fn names(&self) -> Result<Vec<KeypairName>, KeychainError> {
+        let mut names = keypair_names(&self.key_dir)?
+            .into_iter()
+            .filter_map(|name| KeypairName::new(name).ok())
+            .filter(|name| self.exists(name))
+            .collect::<Vec<_>>();
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(names)
+    }
+
+    /// Get the metadata for the keypair with the specified `name`.
+    ///
+    /// Keypairs without a metadata file return default (empty) metadata.
+    pub fn metadata(&self, name: impl AsRef<str>) -> Result<KeyMetadata, KeychainError> {
+        let name = Keypair::parse_keypair_name(name)?;
+        if !self.exists(&name) {
+            return Err(KeychainError::KeypairNotFound {
+                name: name.to_string(),
+            });
+        }
+        let metadata_path = self.key_dir.join(name.metadata_filename());
+        KeyMetadata::load(metadata_path).map_err(|error| KeychainError::MetadataError {
+            name: name.to_string(),
+            error,
+        })
+    }
+
+    /// Replace the metadata for the keypair with the specified `name`.
+    pub fn set_metadata(
+        &self,
+        name: impl AsRef<str>,
+        metadata: &KeyMetadata,
+    ) -> Result<(), KeychainError> {
+        let _lock = self.lock()?;
+        let index = self.load_index();
+        let keypair = self.get(name)?;
+        keypair.save_metadata(&self.key_dir, metadata)?;
+        self.append_audit(AuditOp::SetMetadata, &keypair, None)?;
+        self.update_index(index, |_| ());
+        Ok(())
+    }
+
+    /// Get the name of the default keypair, if one has been set.
+    ///
+    /// A default that refers to a keypair that no longer exists is treated
+    /// as unset.
+    pub fn default_name(&self) -> Result<Option<KeypairName>, KeychainError> {
+        match self.read_default()? {
+            Some(name) if self.exists(&name) => Ok(Some(name)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Get the default keypair, if one has been set.
+    pub fn default_keypair(&self) -> Result<Option<Keypair>, KeychainError> {
+        match self.default_name()? {
+            Some(name) => self.get(name).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Make the keypair with `name` the default.
+    ///
+    /// Returns an error if the specified key is not found.
+    pub fn set_default(&self, name: impl AsRef<str>) -> Result<(), KeychainError> {
+        let _lock = self.lock()?;
+        let index = self.load_index();
+        let keypair = self.get(name)?;
+        self.write_default(keypair.name())?;
+        self.append_audit(AuditOp::SetDefault, &keypair, None)?;
+        self.update_index(index, |_| ());
+        Ok(())
+    }
+
+    /// Clear the default keypair setting, if any.
+    pub fn clear_default(&self) -> Result<(), KeychainError> {
+        let _lock = self.lock()?;
+        let index = self.load_index();
+        if let Some(name) = self.read_default()? {
+            self.remove_default()?;
+            if let Ok(keypair) = self.get(name) {
+                self.append_audit(AuditOp::ClearDefault, &keypair, None)?;
+            }
+        }
+        self.update_index(index, |_| ());
+        Ok(())
+    }
+
     /// Remove keypair with given name.
     ///
+    /// Removing the default keypair also clears the default setting.
+    ///
     /// Returns an error if the keychain directory is not readable or the
     /// specified key is not found.
     pub fn remove(&self, name: impl AsRef<str>) -> Result<(), KeychainError> {
+        let _lock = self.lock()?;
+        let index = self.load_index();
         let keypair = self.get(name)?;
-        keypair.delete(&self.key_dir)
+        let was_default = self.read_default()?.as_ref() == Some(keypair.name());
+        keypair.delete(&self.key_dir)?;
+        if was_default {
+            self.remove_default()?;
+        }
+        self.append_audit(AuditOp::Remove, &keypair, None)?;
+        self.update_index(index, |index| index.remove(keypair.name()));
+        Ok(())
     }
 
     /// Renames the keypair with `old_name` to `new_name`.
     ///
-    /// Returns an error if the keychain directory is not readable or the
-    /// specified key is not found.
+    /// If the keypair was the default, the default follows the new name.
+    /// Files are moved with filesystem renames, and the operation is recorded
+    /// in a journal first so that an interrupted rename is completed the next
+    /// time the keychain is opened.
+    ///
+    /// Returns an error if the keychain directory is not readable, the
+    /// specified key is not found, or a keypair with `new_name` already
+    /// exists.
     pub fn rename(
         &self,
         old_name: impl AsRef<str>,
         new_name: impl AsRef<str>,
     ) -> Result<(), KeychainError> {
-        let old = self.get(old_name.as_ref())?;
-        self.create(new_name, old.public().clone(), old.secret().clone())?;
-        self.remove(old_name)
+        let new_name = Keypair::parse_keypair_name(new_name)?;
+        let _lock = self.lock()?;
+        let index = self.load_index();
+        let old = self.get(old_name)?;
+        let already_exists = new_name
+            .filenames()
+            .iter()
+            .any(|filename| self.key_dir.join(filename).exists());
+        if already_exists {
+            return Err(KeychainError::KeypairAlreadyExists {
+                name: new_name.to_string(),
+            });
+        }
+        write_replace(
+            self.rename_journal_path(),
+            format!("{}\n{}\n", old.name(), new_name),
+        )
+        .map_err(|error| KeychainError::RenameError {
+            old_name: old.name().to_string(),
+            new_name: new_name.to_string(),
+            error,
+        })?;
+        self.finish_rename(old.name(), &new_name)?;
+        self.append_audit(AuditOp::Rename, &old, Some(new_name.to_string()))?;
+        self.update_index(index, |index| index.rename(old.name(), &new_name));
+        Ok(())
+    }
+
+    /// Record use of `keypair` for an operation that does not modify the
+    /// keychain, such as exporting it or decrypting with its secret key.
+    pub fn record_use(&self, op: AuditOp, keypair: &Keypair) -> Result<(), KeychainError> {
+        let _lock = self.lock()?;
+        self.append_audit(op, keypair, None)
+    }
+
+    /// Record use of keypair `name`, whose public key is `public`, for an
+    /// operation carried out elsewhere, such as by the agent. The secret key
+    /// is not loaded.
+    pub fn record_public_use(
+        &self,
+        op: AuditOp,
+        name: impl AsRef<str>,
+        public: &PublicKey,
+    ) -> Result<(), KeychainError> {
+        let _lock = self.lock()?;
+        self.audit_log
+            .append(op, name, fingerprint(public), None)
+            .map_err(|error| KeychainError::AuditError { error })
+    }
+
+    /// Return the keychain's audit log.
+    pub fn audit_log(&self) -> &AuditLog {
+        &self.audit_log
+    }
+
+    /// Appends an audit log entry. Must be called with the lock held.
+    fn append_audit(
+        &self,
+        op: AuditOp,
+        keypair: &Keypair,
+        detail: Option<String>,
+    ) -> Result<(), KeychainError> {
+        self.audit_log
+            .append(op, keypair.name(), keypair.fingerprint(), detail)
+            .map_err(|error| KeychainError::AuditError { error })
+    }
+
+    /// Acquire the keychain lock, blocking until any other process holding
+    /// it releases it, then complete any interrupted rename.
+    fn lock(&self) -> Result<KeychainLock, KeychainError> {
+        let lock_path = self.key_dir.join(LOCK_FILENAME);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .and_then(|file| file.lock_exclusive().map(|()| file))
+            .map_err(|error| KeychainError::LockError {
+                path: lock_path,
+                error,
+            })?;
+        let lock = KeychainLock { file };
+        self.recover_rename()?;
+        Ok(lock)
+    }
+
+    /// Completes a rename recorded in the journal, if there is one. Must be
+    /// called with the lock held.
+    fn recover_rename(&self) -> Result<(), KeychainError> {
+        let journal_path = self.rename_journal_path();
+        if !journal_path.is_file() {
+            return Ok(());
+        }
+        let journal_error = |error| KeychainError::RenameJournalError {
+            path: journal_path.clone(),
+            error,
+        };
+        let contents = fs::read_to_string(&journal_path).map_err(journal_error)?;
+        let mut lines = contents.lines().map(KeypairName::new);
+        match (lines.next(), lines.next()) {
+            (Some(Ok(old_name)), Some(Ok(new_name))) => self.finish_rename(&old_name, &new_name),
+            _ => Err(journal_error(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "journal is corrupt",
+            ))),
+        }
+    }
+
+    /// Moves each file belonging to `old_name` that has not yet been moved,
+    /// updates the default setting, and removes the journal. Safe to repeat
+    /// if interrupted. Must be called with the lock held.
+    fn finish_rename(
+        &self,
+        old_name: &KeypairName,
+        new_name: &KeypairName,
+    ) -> Result<(), KeychainError> {
+        let rename_error = |error| KeychainError::RenameError {
+            old_name: old_name.to_string(),
+            new_name: new_name.to_string(),
+            error,
+        };
+        for (old_filename, new_filename) in old_name.filenames().iter().zip(&new_name.filenames()) {
+            let old_path = self.key_dir.join(old_filename);
+            let new_path = self.key_dir.join(new_filename);
+            if old_path.exists() && !new_path.exists() {
+                fs::rename(&old_path, &new_path).map_err(rename_error)?;
+            }
+        }
+        if self.read_default()?.as_ref() == Some(old_name) {
+            self.write_default(new_name)?;
+        }
+        fs::remove_file(self.rename_journal_path()).map_err(rename_error)
+    }
+
+    /// Returns true if both key files for `name` exist.
+    fn exists(&self, name: &KeypairName) -> bool {
+        self.key_dir.join(name.public_filename()).is_file()
+            && self.key_dir.join(name.secret_filename()).is_file()
+    }
+
+    /// Loads the index, if it is up to date.
+    fn load_index(&self) -> Option<KeyIndex> {
+        KeyIndex::load(self.index_path(), &self.key_dir)
     }
+
+    /// Applies `update` to an index loaded before modifying the keychain and
+    /// saves it, so that it stays up to date. Does nothing if the index was
+    /// already out of date. Must be called with the lock held.
+    fn update_index(&self, index: Option<KeyIndex>, update: impl FnOnce(&mut KeyIndex)) {
+        if let Some(mut index) = index {
+            update(&mut index);
+            // A failure just leaves the index out of date.
+            let _ = index.save(self.index_path(), &self.key_dir);
+        }
+    }
+
+    /// Builds the index from the public key files of every keypair, saving
+    /// it if the keychain can be locked.
+    fn rebuild_index(&self) -> Result<KeyIndex, KeychainError> {
+        let lock = self.lock().ok();
+        let mut index = KeyIndex::default();
+        for name in keypair_names(&self.key_dir)? {
+            let name = match KeypairName::new(name) {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            if !self.exists(&name) {
+                continue;
+            }
+            if let Ok(public) = PublicKey::from_file(self.key_dir.join(name.public_filename())) {
+                index.insert(fingerprint(&public), name);
+            }
+        }
+        if lock.is_some() {
+            let _ = index.save(self.index_path(), &self.key_dir);
+        }
+        Ok(index)
+    }
+
+    /// Loads the first keypair named in `index` for `fingerprint` whose
+    /// public key file matches it. Secret keys are only loaded once the
+    /// public key matches.
+    fn find_indexed(&self, index: &KeyIndex, fingerprint: &str) -> Option<Keypair> {
+        index.names(fingerprint).find_map(|name| {
+            let public = PublicKey::from_file(self.key_dir.join(name.public_filename())).ok()?;
+            if self::fingerprint(&public) == fingerprint {
+                Keypair::load(&self.key_dir, name).ok()
+            } else {
+                None
+            }
+        })
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.key_dir.join(INDEX_FILENAME)
+    }
+
+    fn read_default(&self) -> Result<Option<KeypairName>, KeychainError> {
+        let default_path = self.default_path();
+        if !default_path.is_file() {
+            return Ok(None);
+        }
+        let contents =
+            fs::read_to_string(&default_path).map_err(|error| KeychainError::DefaultError {
+                path: default_path.clone(),
+                error,
+            })?;
+        Ok(KeypairName::new(contents.trim()).ok())
+    }
+
+    fn write_default(&self, name: &KeypairName) -> Result<(), KeychainError> {
+        let default_path = self.default_path();
+        write_replace(&default_path, format!("{}\n", name)).map_err(|error| {
+            KeychainError::DefaultError {
+                path: default_path,
+                error,
+            }
+        })
+    }
+
+    fn remove_default(&self) -> Result<(), KeychainError> {
+        let default_path = self.default_path();
+        if default_path.is_file() {
+            fs::remove_file(&default_path).map_err(|error| KeychainError::DefaultError {
+                path: default_path,
+                error,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn default_path(&self) -> PathBuf {
+        self.key_dir.join("default")
+    }
+
+    fn rename_journal_path(&self) -> PathBuf {
+        self.key_dir.join(RENAME_JOURNAL_FILENAME)
+    }
+}
+
+/// Exclusive advisory lock on the keychain directory, released on drop.
+struct KeychainLock {
+    file: File,
+}
+
+impl Drop for KeychainLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+/// Returns the fingerprint of `public` - the hex-encoded SHA-256 digest of
+/// the raw key bytes.
+pub fn fingerprint(public: &PublicKey) -> String {
+    audit::hex(&sha256::hash(&public_key_bytes(public))[..])
+}
+
+/// Returns the raw Curve25519 bytes of `public`.
+pub fn public_key_bytes(public: &PublicKey) -> Vec<u8> {
+    // The DER encoding ends with the key as a bit string with no unused bits,
+    // so the raw key is always the final `PUBLICKEYBYTES` bytes.
+    let der = pem::parse(public.to_pem())
+        .expect("PEM encoding of PublicKey is always valid")
+        .contents;
+    der[der.len() - PUBLICKEYBYTES..].to_vec()
+}
+
+/// Returns the raw Curve25519 bytes of `secret`.
+pub fn secret_key_bytes(secret: &SecretKey) -> SecretBytes {
+    // The DER encoding ends with the key as an octet string nested inside
+    // another octet string, so the raw key is always the final
+    // `SECRETKEYBYTES` bytes. Both encodings are zeroed once the key has
+    // been copied out.
+    let mut encoded = secret.to_pem().into_bytes();
+    let mut der = pem::parse(&encoded)
+        .expect("PEM encoding of SecretKey is always valid")
+        .contents;
+    let bytes = SecretBytes::new(&der[der.len() - SECRETKEYBYTES..]);
+    memzero(&mut der);
+    memzero(&mut encoded);
+    bytes
+}
+
+/// Derive the public key corresponding to `secret`.
+pub fn public_from_secret(secret: &SecretKey) -> PublicKey {
+    let scalar = Scalar::from_slice(&secret_key_bytes(secret))
+        .expect("secret key is always the correct length");
+    let public = scalarmult_base(&scalar);
+    PublicKey::from_raw_curve25519(&public[..]).expect("public key is always the correct length")
+}
+
+/// Replaces the contents of `path` by writing to a temporary file and
+/// renaming it into place, so readers never observe a partial write. The
+/// file keeps the permissions of the one it replaces, if any.
+pub fn write_replace(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let mut temp_name = path
+        .as_ref()
+        .file_name()
+        .map(|name| name.to_os_string())
+        .unwrap_or_default();
+    temp_name.push(".tmp");
+    let temp_path = path.as_ref().with_file_name(temp_name);
+    let mut file = File::create(&temp_path)?;
+    match fs::metadata(&path) {
+        Ok(metadata) => file.set_permissions(metadata.permissions())?,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error),
+    }
+    file.write_all(contents.as_ref())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
 }
 
 /// Public/secret keypair with an associated name.
@@ -141,6 +671,16 @@ impl Keypair {
         &self.secret
     }
 
+    /// Consume the keypair, returning the secret key without copying it.
+    pub fn into_secret(self) -> SecretKey {
+        self.secret
+    }
+
+    /// Return the fingerprint of the public key.
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public)
+    }
+
     fn parse_keypair_name(name: impl AsRef<str>) -> Result<KeypairName, KeychainError> {
         KeypairName::new(name.as_ref()).map_err(|error| KeychainError::InvalidKeypairName {
             name: name.as_ref().to_string(),
@@ -175,27 +715,54 @@ impl Keypair {
         }
     }
 
+    /// Writes the key files. Files are created with `create_new` semantics,
+    /// so an existing keypair is never overwritten even if another process
+    /// creates it concurrently.
     fn save(&self, dir: impl AsRef<Path>) -> Result<(), KeychainError> {
         let public_path = dir.as_ref().join(self.name.public_filename());
         let secret_path = dir.as_ref().join(self.name.secret_filename());
-        if public_path.is_file() || secret_path.is_file() {
-            Err(KeychainError::KeypairAlreadyExists {
-                name: self.name.to_string(),
-            })
-        } else {
-            self.public
-                .to_file(&public_path)
-                .and_then(|()| self.secret.to_file(&secret_path))
-                .map_err(|e| KeychainError::SaveError {
+        self.public
+            .to_file(&public_path)
+            .map_err(|error| self.save_error(error))?;
+        if let Err(error) = self.secret.to_file(&secret_path) {
+            let _ = fs::remove_file(&public_path);
+            return Err(self.save_error(error));
+        }
+        Ok(())
+    }
+
+    fn save_error(&self, error: SaltlickKeyIoError) -> KeychainError {
+        match error {
+            SaltlickKeyIoError::IoError(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                KeychainError::KeypairAlreadyExists {
                     name: self.name.to_string(),
-                    error: e,
-                })
+                }
+            }
+            error => KeychainError::SaveError {
+                name: self.name.to_string(),
+                error,
+            },
         }
     }
 
+    fn save_metadata(
+        &self,
+        dir: impl AsRef<Path>,
+        metadata: &KeyMetadata,
+    ) -> Result<(), KeychainError> {
+        let metadata_path = dir.as_ref().join(self.name.metadata_filename());
+        metadata
+            .save(metadata_path)
+            .map_err(|error| KeychainError::MetadataError {
+                name: self.name.to_string(),
+                error,
+            })
+    }
+
     fn delete(&self, dir: impl AsRef<Path>) -> Result<(), KeychainError> {
         let public_path = dir.as_ref().join(self.name.public_filename());
         let secret_path = dir.as_ref().join(self.name.secret_filename());
+        let metadata_path = dir.as_ref().join(self.name.metadata_filename());
         let public_result = if public_path.is_file() {
             fs::remove_file(public_path)
         } else {
@@ -206,9 +773,15 @@ impl Keypair {
         } else {
             Ok(())
         };
+        let metadata_result = if metadata_path.is_file() {
+            fs::remove_file(metadata_path)
+        } else {
+            Ok(())
+        };
 
         public_result
             .and(secret_result)
+            .and(metadata_result)
             .map_err(|error| KeychainError::DeleteError {
                 name: self.name.to_string(),
                 error,
@@ -228,6 +801,18 @@ impl KeypairName {
     fn secret_filename(&self) -> String {
         format!("{}.sec", self.0)
     }
+
+    fn metadata_filename(&self) -> String {
+        format!("{}.meta", self.0)
+    }
+
+    fn filenames(&self) -> [String; 3] {
+        [
+            self.public_filename(),
+            self.secret_filename(),
+            self.metadata_filename(),
+        ]
+    }
 }
 
 impl AsRef<str> for KeypairName {
@@ -247,10 +832,7 @@ impl KeypairName {
     /// naming rules.
     pub fn new(name: impl AsRef<str>) -> Result<KeypairName, InvalidKeypairName> {
         fn is_invalid_char(c: char) -> bool {
-            match c {
-                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => false,
-                _ => true,
-            }
+            !matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.')
         }
 
         if name.as_ref().is_empty() {
@@ -279,40 +861,44 @@ pub struct KeychainIter {
 
 impl KeychainIter {
     fn new(root_path: impl AsRef<Path>) -> Result<KeychainIter, KeychainError> {
-        let owned_root_path = root_path.as_ref().to_path_buf();
-        let name_iter = fs::read_dir(&owned_root_path)
-            .map_err(|e| KeychainError::BadKeychainDir {
-                error: e,
-                path: owned_root_path.clone(),
-            })?
-            .filter_map(Result::ok)
-            .filter_map(|entry| {
-                let path = entry.path();
-                let ext = Self::ext_or_empty(&path);
-                if ext == "pub" || ext == "sec" {
-                    Some(path)
-                } else {
-                    None
-                }
-            })
-            .filter_map(|path| {
-                path.file_stem()
-                    .and_then(|stem| stem.to_str())
-                    .map(String::from)
-            })
-            .collect::<HashSet<String>>()
-            .into_iter();
+        let name_iter = keypair_names(root_path.as_ref())?.into_iter();
         Ok(KeychainIter {
             name_iter: Box::new(name_iter),
-            root_path: owned_root_path,
+            root_path: root_path.as_ref().to_path_buf(),
         })
     }
+}
 
-    fn ext_or_empty(path: &PathBuf) -> &str {
+/// Returns the names of files in the keychain directory with a public or
+/// secret key extension.
+fn keypair_names(root_path: &Path) -> Result<HashSet<String>, KeychainError> {
+    fn ext_or_empty(path: &Path) -> &str {
         path.extension()
             .map(|ext| ext.to_str().unwrap_or_default())
             .unwrap_or_default()
     }
+
+    Ok(fs::read_dir(root_path)
+        .map_err(|e| KeychainError::BadKeychainDir {
+            error: e,
+            path: root_path.to_path_buf(),
+        })?
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let path = entry.path();
+            let ext = ext_or_empty(&path);
+            if ext == "pub" || ext == "sec" {
+                Some(path)
+            } else {
+                None
+            }
+        })
+        .filter_map(|path| {
+            path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .map(String::from)
+        })
+        .collect())
 }
 
 impl Iterator for KeychainIter {
@@ -333,11 +919,12 @@ impl Iterator for KeychainIter {
 
 #[cfg(test)]
 mod tests {
-    use super::Keychain;
+    use super::{fingerprint, Keychain, KeypairName};
+    use crate::audit::AuditOp;
+    use crate::metadata::KeyMetadata;
 
     use assert_fs::prelude::*;
     use predicates::prelude::*;
-    use saltlick;
 
     fn setup() -> (Keychain, assert_fs::TempDir) {
         let temp = assert_fs::TempDir::new().unwrap();
@@ -405,4 +992,214 @@ mod tests {
         temp.child("renamed_keypair.sec")
             .assert(predicate::path::missing());
     }
+
+    #[test]
+    fn record_public_use_test() {
+        let (keychain, _temp) = setup();
+        let (public, secret) = saltlick::gen_keypair();
+        keychain
+            .create("agent_keypair", public.clone(), secret)
+            .unwrap();
+        keychain
+            .record_public_use(AuditOp::Decrypt, "agent_keypair", &public)
+            .unwrap();
+
+        let entries = keychain.audit_log().entries().unwrap();
+        let entry = entries.last().unwrap();
+        assert_eq!(entry.op, AuditOp::Decrypt);
+        assert_eq!(entry.key, "agent_keypair");
+        assert_eq!(entry.fingerprint, fingerprint(&public));
+        assert_eq!(keychain.audit_log().verify().unwrap(), entries.len());
+    }
+
+    #[test]
+    fn metadata_test() {
+        let (keychain, temp) = setup();
+        let (public, secret) = saltlick::gen_keypair();
+        keychain.create("meta_keypair", public, secret).unwrap();
+        temp.child("meta_keypair.meta")
+            .assert(predicate::path::is_file());
+
+        // New keypairs get a creation time and nothing else.
+        let metadata = keychain.metadata("meta_keypair").unwrap();
+        assert!(metadata.created.is_some());
+        assert_eq!(metadata.comment, None);
+        assert!(!metadata.is_expired());
+
+        // Update metadata and read it back.
+        let updated = KeyMetadata {
+            comment: Some(String::from("backup key")),
+            owner: Some(String::from("ops@example.com")),
+            expires: Some("2000-01-01".parse().unwrap()),
+            tags: vec![String::from("escrow")],
+            ..metadata
+        };
+        keychain.set_metadata("meta_keypair", &updated).unwrap();
+        let metadata = keychain.metadata("meta_keypair").unwrap();
+        assert_eq!(metadata, updated);
+        assert!(metadata.is_expired());
+
+        // Metadata follows the keypair through a rename.
+        keychain.rename("meta_keypair", "renamed_meta").unwrap();
+        assert_eq!(keychain.metadata("renamed_meta").unwrap(), updated);
+        temp.child("meta_keypair.meta")
+            .assert(predicate::path::missing());
+
+        // And is removed along with it.
+        keychain.remove("renamed_meta").unwrap();
+        temp.child("renamed_meta.meta")
+            .assert(predicate::path::missing());
+    }
+
+    #[test]
+    fn default_keypair_test() {
+        let (keychain, _temp) = setup();
+        assert_eq!(keychain.default_name().unwrap(), None);
+        keychain.set_default("missing").unwrap_err();
+
+        let (public, secret) = saltlick::gen_keypair();
+        keychain.create("first", public.clone(), secret).unwrap();
+        let (other_public, other_secret) = saltlick::gen_keypair();
+        keychain
+            .create("second", other_public, other_secret)
+            .unwrap();
+
+        keychain.set_default("first").unwrap();
+        let keypair = keychain.default_keypair().unwrap().unwrap();
+        assert_eq!(keypair.name().as_ref(), "first");
+        assert_eq!(keypair.public(), &public);
+
+        // The default follows a rename and is cleared by removal.
+        keychain.rename("first", "renamed").unwrap();
+        let name = keychain.default_name().unwrap().unwrap();
+        assert_eq!(name.as_ref(), "renamed");
+        keychain.remove("renamed").unwrap();
+        assert_eq!(keychain.default_name().unwrap(), None);
+
+        // Removing a non-default keypair leaves the default alone.
+        keychain.set_default("second").unwrap();
+        let (public, secret) = saltlick::gen_keypair();
+        keychain.create("third", public, secret).unwrap();
+        keychain.remove("third").unwrap();
+        let name = keychain.default_name().unwrap().unwrap();
+        assert_eq!(name.as_ref(), "second");
+    }
+
+    #[test]
+    fn interrupted_rename_test() {
+        let (keychain, temp) = setup();
+        let (public, secret) = saltlick::gen_keypair();
+        keychain.create("before", public.clone(), secret).unwrap();
+        keychain.set_default("before").unwrap();
+
+        // Simulate a rename that was interrupted after moving only one file.
+        temp.child("rename.journal")
+            .write_str("before\nafter\n")
+            .unwrap();
+        std::fs::rename(
+            temp.child("before.pub").path(),
+            temp.child("after.pub").path(),
+        )
+        .unwrap();
+
+        // Reopening the keychain completes the rename.
+        let keychain = Keychain::open_at(temp.path()).unwrap();
+        let keypair = keychain.get("after").unwrap();
+        assert_eq!(&public, keypair.public());
+        keychain.get("before").unwrap_err();
+        assert_eq!(keychain.default_name().unwrap().unwrap().as_ref(), "after");
+        temp.child("after.meta").assert(predicate::path::is_file());
+        temp.child("rename.journal")
+            .assert(predicate::path::missing());
+    }
+
+    #[test]
+    fn index_test() {
+        let (keychain, temp) = setup();
+        let (public, secret) = saltlick::gen_keypair();
+        keychain.create("indexed", public.clone(), secret).unwrap();
+
+        // The first lookup builds the index and later changes maintain it.
+        assert_eq!(keychain.find(&public).unwrap().name().as_ref(), "indexed");
+        temp.child("index").assert(predicate::path::is_file());
+        let index = keychain.load_index().unwrap();
+        assert_eq!(index.names(&fingerprint(&public)).count(), 1);
+        keychain.rename("indexed", "renamed").unwrap();
+        let (other_public, other_secret) = saltlick::gen_keypair();
+        keychain
+            .create("other", other_public.clone(), other_secret)
+            .unwrap();
+        let index = keychain.load_index().unwrap();
+        let names = index
+            .names(&fingerprint(&public))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(names.len(), 1);
+        assert_eq!(names[0].as_ref(), "renamed");
+        assert_eq!(index.names(&fingerprint(&other_public)).count(), 1);
+
+        // Changes made behind the keychain's back are picked up.
+        std::fs::rename(
+            temp.child("other.pub").path(),
+            temp.child("moved.pub").path(),
+        )
+        .unwrap();
+        std::fs::rename(
+            temp.child("other.sec").path(),
+            temp.child("moved.sec").path(),
+        )
+        .unwrap();
+        assert!(keychain.load_index().is_none());
+        assert_eq!(
+            keychain.find(&other_public).unwrap().name().as_ref(),
+            "moved"
+        );
+
+        // A wrong entry in an otherwise valid index is ignored.
+        let mut index = keychain.load_index().unwrap();
+        index.remove(&KeypairName::new("renamed").unwrap());
+        index.insert(fingerprint(&public), KeypairName::new("moved").unwrap());
+        index.save(keychain.index_path(), temp.path()).unwrap();
+        assert_eq!(keychain.find(&public).unwrap().name().as_ref(), "renamed");
+
+        // So is an entry missing from it.
+        let mut index = keychain.load_index().unwrap();
+        index.remove(&KeypairName::new("renamed").unwrap());
+        index.save(keychain.index_path(), temp.path()).unwrap();
+        assert_eq!(keychain.find(&public).unwrap().name().as_ref(), "renamed");
+        let (unknown, _) = saltlick::gen_keypair();
+        keychain.find(&unknown).unwrap_err();
+
+        // Lookups by name or fingerprint never need unrelated secret keys.
+        temp.child("moved.sec").write_str("not a key").unwrap();
+        let keypair = keychain.find_fingerprint(&fingerprint(&public)).unwrap();
+        assert_eq!(keypair.name().as_ref(), "renamed");
+        assert_eq!(keychain.get_public("moved").unwrap(), other_public);
+        let names = keychain.names().unwrap();
+        assert_eq!(
+            names.iter().map(|name| name.as_ref()).collect::<Vec<_>>(),
+            vec!["moved", "renamed"]
+        );
+    }
+
+    #[test]
+    fn concurrent_create_test() {
+        let (_keychain, temp) = setup();
+        let handles = (0..8)
+            .map(|_| {
+                let path = temp.path().to_path_buf();
+                std::thread::spawn(move || {
+                    let keychain = Keychain::open_at(path).unwrap();
+                    let (public, secret) = saltlick::gen_keypair();
+                    keychain.create("contended", public, secret).is_ok()
+                })
+            })
+            .collect::<Vec<_>>();
+        let created = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(|created| *created)
+            .count();
+        assert_eq!(created, 1);
+    }
 }
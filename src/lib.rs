@@ -0,0 +1,35 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Internals of the `saltlick` command-line tool.
+//!
+//! These modules are exposed so benchmarks and other tooling can exercise
+//! them directly. They are not a stable API; use the `saltlick` crate for
+//! that.
+
+#[cfg(unix)]
+pub mod agent;
+pub mod audit;
+pub mod batch;
+pub mod bench;
+pub mod buffer;
+pub mod derive;
+pub mod dotenv;
+pub mod error;
+pub mod file_metadata;
+pub mod index;
+pub mod keychain;
+pub mod metadata;
+pub mod paper;
+pub mod remove;
+pub mod resume;
+pub mod secret;
+pub mod shamir;
+pub mod stream;
+pub mod values;
+pub mod volume;
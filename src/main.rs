@@ -9,70 +9,191 @@
 //! Simple CLI for encrypting and decrypting saltlick file streams.
 
 mod cli;
-mod error;
-mod keychain;
 
-use std::fs::{File, OpenOptions};
-use std::io::{self, BufRead, BufReader, Write};
+use std::convert::TryFrom;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
 
 use human_panic::setup_panic;
+use regex::bytes::RegexBuilder;
 use saltlick::{
     self,
     bufread::{SaltlickDecrypter, SaltlickEncrypter},
-    PublicKey, SecretKey,
+    PublicKey, SaltlickError, SecretKey,
 };
+use serde_json::Value;
+use similar::TextDiff;
+use sodiumoxide::randombytes::randombytes;
+
+use saltlick_cli::audit::{hex, hex_decode, AuditEntry, AuditOp};
+use saltlick_cli::batch::{self, KeyCache};
+use saltlick_cli::bench;
+use saltlick_cli::buffer::{self, format_size};
+use saltlick_cli::error::{
+    CliError, DeriveError, DotenvError, RemoveError, ValuesError, VolumeError,
+};
+use saltlick_cli::file_metadata::{FileMetadata, MetadataReader};
+use saltlick_cli::keychain::{write_replace, Keychain, Keypair};
+use saltlick_cli::metadata::KeyMetadata;
+use saltlick_cli::remove;
+use saltlick_cli::resume::{
+    self, InputIdentity, Journal, JournalOp, JournaledReader, JournaledWriter,
+};
+use saltlick_cli::shamir::{self, KeyShare};
+use saltlick_cli::stream::{
+    self, SeekableDecrypter, StreamDecrypter, StreamEncrypter, StreamHeader, StreamKey,
+};
+use saltlick_cli::values::{Envelope, Format};
+use saltlick_cli::volume::{
+    self, HashingReader, HashingWriter, Manifest, StreamHash, VolumeEntry, VolumeReader,
+    VolumeTrailer,
+};
+#[cfg(unix)]
+use saltlick_cli::{
+    agent::{self, AgentClient},
+    error::AgentError,
+};
+use saltlick_cli::{derive, dotenv, paper, secret};
 
 use crate::cli::*;
-use crate::error::CliError;
-use crate::keychain::Keychain;
+
+/// Extension added to files encrypted in a batch.
+const BATCH_EXTENSION: &str = "slk";
 
 /// Opens and returns `path` for `Read` if it is `Some`, otherwise returns
 /// stdin.
 fn read_or_stdin(path: Option<impl AsRef<Path>>) -> Result<Box<dyn BufRead>, CliError> {
+    read_or_stdin_buffered(path, buffer::DEFAULT_BUFFER_SIZE)
+}
+
+/// Like `read_or_stdin`, with a read buffer of `buffer_size` bytes. Large
+/// regular files are memory mapped instead.
+fn read_or_stdin_buffered(
+    path: Option<impl AsRef<Path>>,
+    buffer_size: usize,
+) -> Result<Box<dyn BufRead>, CliError> {
     if let Some(input_file) = path.as_ref() {
-        Ok(Box::new(
-            File::open(input_file)
-                .map(BufReader::new)
-                .map_err(|error| CliError::InputFileIoError {
-                    error,
-                    path: input_file.as_ref().to_path_buf(),
-                })?,
-        ))
+        buffer::open_input(input_file, buffer_size).map_err(|error| CliError::InputFileIoError {
+            error,
+            path: input_file.as_ref().to_path_buf(),
+        })
     } else {
-        Ok(Box::new(BufReader::new(io::stdin())))
+        Ok(Box::new(BufReader::with_capacity(buffer_size, io::stdin())))
     }
 }
 
+/// Returns the length of `path` if it is a regular file.
+fn input_len(path: Option<impl AsRef<Path>>) -> Option<u64> {
+    let metadata = fs::metadata(path?).ok()?;
+    if metadata.is_file() {
+        Some(metadata.len())
+    } else {
+        None
+    }
+}
+
+/// Creates and returns the file at `path` for writing. If `force` is false,
+/// opening an existing file is an error, otherwise the file is truncated and
+/// no error is raised.
+fn create_output(path: impl AsRef<Path>, force: bool) -> Result<File, CliError> {
+    let path = path.as_ref();
+    let file = if force {
+        File::create(path)
+    } else {
+        OpenOptions::new().create_new(true).write(true).open(path)
+    };
+    file.map_err(|error| CliError::OutputFileIoError {
+        error,
+        path: path.to_path_buf(),
+    })
+}
+
+/// Creates `path`, which must not exist, readable and writable only by the
+/// owner, for output that holds secret key material.
+fn create_private_output(path: impl AsRef<Path>) -> Result<File, CliError> {
+    let path = path.as_ref();
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(path)
+        .map_err(|error| CliError::OutputFileIoError {
+            error,
+            path: path.to_path_buf(),
+        })
+}
+
 /// Opens and returns `path` for `Write` if it is `Some`, otherwise returns
-/// stdout. If `force` is false, opening an existing file is an error,
-/// otherwise the file is truncated and no error is raised.
+/// stdout. See `create_output` for the meaning of `force`.
 fn write_or_stdout(
     path: Option<impl AsRef<Path>>,
     force: bool,
 ) -> Result<Box<dyn Write>, CliError> {
     if let Some(output_file) = path.as_ref() {
-        let writer = if force {
-            File::create(output_file).map_err(|error| CliError::OutputFileIoError {
-                error,
-                path: output_file.as_ref().to_path_buf(),
-            })?
-        } else {
-            OpenOptions::new()
-                .create_new(true)
-                .write(true)
-                .open(output_file)
-                .map_err(|error| CliError::OutputFileIoError {
-                    error,
-                    path: output_file.as_ref().to_path_buf(),
-                })?
-        };
-        Ok(Box::new(writer))
+        Ok(Box::new(create_output(output_file, force)?))
     } else {
         Ok(Box::new(io::stdout()))
     }
 }
 
+/// Copies everything from `reader` to `outfile`, or stdout if it is `None`,
+/// through a buffer of `buffer_size` bytes. An output file is allocated
+/// `expected_len` bytes up front when that is known.
+fn copy_to_output(
+    reader: &mut impl Read,
+    outfile: Option<File>,
+    buffer_size: usize,
+    expected_len: Option<u64>,
+) -> Result<(), CliError> {
+    match outfile {
+        Some(mut file) => buffer::copy_to_file(reader, &mut file, buffer_size, expected_len),
+        None => buffer::copy(reader, io::stdout(), buffer_size),
+    }
+    .map(|_| ())
+    .map_err(|error| CliError::StreamIoError { error })
+}
+
+/// Returns `size`, or `default` if it was not given, checking that it is
+/// between `min` and `max` inclusive.
+fn size_option(
+    option: &str,
+    size: Option<ByteSize>,
+    default: usize,
+    min: usize,
+    max: usize,
+) -> Result<usize, CliError> {
+    let size = size.map_or(default, |size| size.0);
+    if (min..=max).contains(&size) {
+        Ok(size)
+    } else {
+        Err(CliError::SizeOutOfRange {
+            option: option.to_string(),
+            size,
+            min,
+            max,
+        })
+    }
+}
+
+/// Returns the `--buffer-size` to use.
+fn buffer_size(size: Option<ByteSize>) -> Result<usize, CliError> {
+    size_option(
+        "--buffer-size",
+        size,
+        buffer::DEFAULT_BUFFER_SIZE,
+        buffer::MIN_BUFFER_SIZE,
+        buffer::MAX_BUFFER_SIZE,
+    )
+}
+
 /// Checks options on commands that take either a public key path
 /// (i.e.  -p/--public) or a keychain name (-k/--key), returning the
 /// appropriate `PublicKey` or error.
@@ -122,49 +243,1647 @@ fn get_secret_key(
                 })?,
             )
         }
-        (None, Some(name)) => Ok(Keychain::open()?.get(name)?.secret().clone()),
+        (None, Some(name)) => Ok(Keychain::open()?.get(name)?.into_secret()),
         (None, None) => Err(CliError::MissingKeyAndPath {
             type_: secret_string,
         }),
     }
 }
 
+/// Returns a deferred key lookup function for `SaltlickDecrypter` that tries
+/// the keychain default keypair first, then searches the whole keychain.
+/// Only the matching secret key is loaded, and its use is recorded in the
+/// keychain audit log.
+fn keychain_lookup(keychain: Keychain) -> impl FnOnce(&PublicKey) -> Option<SecretKey> {
+    move |key: &PublicKey| -> Option<SecretKey> {
+        let default = keychain.default_name().ok().flatten();
+        let keypair = match default {
+            Some(name) if keychain.get_public(&name).ok().as_ref() == Some(key) => {
+                keychain.get(name).ok()?
+            }
+            _ => keychain.find(key).ok()?,
+        };
+        record_secret_use(&keychain, &keypair);
+        Some(keypair.into_secret())
+    }
+}
+
+/// Records decryption with `keypair` in the audit log. Failing to write the
+/// log is reported but does not prevent decryption.
+fn record_secret_use(keychain: &Keychain, keypair: &Keypair) {
+    if let Err(error) = keychain.record_use(AuditOp::Decrypt, keypair) {
+        eprintln!("Warning: {}", error);
+    }
+}
+
 /// Decrypts input - either from stdin or an input file - and writes it to
 /// stdout or an output file. If no information about which key to use is
 /// provided, automatically looks for a matching key in the keychain.
 fn decrypt(args: DecryptArgs) -> Result<(), CliError> {
-    let infile = read_or_stdin(args.infile.as_ref())?;
-    let mut outfile = write_or_stdout(args.outfile.as_ref(), args.force)?;
-    let mut decrypter = if args.public.is_none() && args.key.is_none() {
+    let buffer_size = buffer_size(args.buffer_size)?;
+    if args.restore_metadata && args.outfile.is_none() && !args.batch.is_batch() {
+        return Err(CliError::MetadataRequiresFile {
+            option: "--restore-metadata".to_string(),
+        });
+    }
+    if args.batch.is_batch() {
+        return decrypt_batch(args, buffer_size);
+    }
+    if let (Some(option), Some(infile)) = (single_stream_option(&args), args.infile.as_ref()) {
+        let manifest = volume::find_manifest(infile).or_else(|| {
+            Some(volume::manifest_path(infile.with_extension("")))
+                .filter(|_| volume::is_first_volume(infile))
+        });
+        if let Some(manifest) = manifest {
+            return Err(CliError::VolumeError {
+                error: VolumeError::Unsupported {
+                    option: option.to_string(),
+                },
+                manifest,
+            });
+        }
+    }
+    if let (Some(range), Some(path)) = (args.range, args.infile.as_ref()) {
+        return decrypt_range(&args, path, range, buffer_size);
+    }
+    if let (true, Some(infile), Some(outfile)) = (args.resume, &args.infile, &args.outfile) {
+        return decrypt_resumable(&args, infile, outfile, buffer_size);
+    }
+    let mut single_volume = false;
+    if let Some(infile) = args.infile.as_ref() {
+        match volume::find_manifest(infile) {
+            Some(manifest) => return decrypt_split(&args, &manifest, buffer_size),
+            None if volume::is_first_volume(infile) => {
+                eprintln!(
+                    "Warning: no manifest found for {:?}, decrypting this volume only",
+                    infile
+                );
+                single_volume = true;
+            }
+            None => {}
+        }
+    }
+    let infile = read_or_stdin_buffered(args.infile.as_ref(), buffer_size)?;
+    let decrypter: Box<dyn Read> = if args.public.is_none() && args.key.is_none() {
+        deferred_decrypter(infile)?
+    } else {
+        let (public, secret) = decrypt_keys(&args)?;
+        Box::new(SaltlickDecrypter::new(public, secret, infile))
+    };
+    // The volume's trailer is not part of the plaintext.
+    let decrypter: Box<dyn Read> = if single_volume {
+        Box::new(VolumeReader::new(decrypter))
+    } else {
+        decrypter
+    };
+    let mut plaintext = metadata_reader(&args, decrypter);
+    warn_kept_block(&mut plaintext, args.infile.as_deref())?;
+    let metadata = plaintext
+        .metadata()
+        .map_err(|error| CliError::StreamIoError { error })?
+        .cloned();
+    let output_path = decrypt_output_path(&args, metadata.as_ref())?;
+    let outfile = output_path
+        .as_ref()
+        .map(|path| create_output(path, args.force))
+        .transpose()?;
+    let expected_len = input_len(args.infile.as_ref()).map(stream::max_plaintext_len);
+    copy_to_output(&mut plaintext, outfile, buffer_size, expected_len)?;
+    restore_metadata(&args, metadata.as_ref(), output_path.as_deref())
+}
+
+/// Returns the path `decrypt` writes to: `-o/--outfile`, or with
+/// `--restore-metadata` and a directory, the stored file name in that
+/// directory.
+fn decrypt_output_path(
+    args: &DecryptArgs,
+    metadata: Option<&FileMetadata>,
+) -> Result<Option<PathBuf>, CliError> {
+    match &args.outfile {
+        Some(dir) if args.restore_metadata && dir.is_dir() => metadata
+            .and_then(FileMetadata::output_name)
+            .map(|name| Some(dir.join(name)))
+            .ok_or_else(|| CliError::NoStoredName { dir: dir.clone() }),
+        outfile => Ok(outfile.clone()),
+    }
+}
+
+/// Returns a reader over `plaintext` that removes its metadata block if
+/// `--restore-metadata` was given, or passes it through unchanged if not.
+fn metadata_reader<R: Read>(args: &DecryptArgs, plaintext: R) -> MetadataReader<R> {
+    if args.restore_metadata {
+        MetadataReader::new(plaintext)
+    } else {
+        MetadataReader::passthrough(plaintext)
+    }
+}
+
+/// Warns if `plaintext`, decrypted from `path` or stdin, is passed through
+/// unchanged but starts with what looks like a file metadata block.
+fn warn_kept_block<R: Read>(
+    plaintext: &mut MetadataReader<R>,
+    path: Option<&Path>,
+) -> Result<(), CliError> {
+    if plaintext
+        .kept_block()
+        .map_err(|error| CliError::StreamIoError { error })?
+    {
+        let name = path.map_or_else(|| "stdin".into(), Path::to_string_lossy);
+        eprintln!(
+            "Warning: \"{}\" starts with stored file metadata, which is left in the output (use \"decrypt --restore-metadata\" to remove it)",
+            name
+        );
+    }
+    Ok(())
+}
+
+/// Applies `metadata` to the decrypted file at `path` if
+/// `--restore-metadata` was given, warning if the input had none stored.
+fn restore_metadata(
+    args: &DecryptArgs,
+    metadata: Option<&FileMetadata>,
+    path: Option<&Path>,
+) -> Result<(), CliError> {
+    let path = match path {
+        Some(path) if args.restore_metadata => path,
+        _ => return Ok(()),
+    };
+    match metadata {
+        Some(metadata) => {
+            let skipped = metadata.apply(path, args.all_xattrs).map_err(|error| {
+                CliError::RestoreMetadataError {
+                    error,
+                    path: path.to_path_buf(),
+                }
+            })?;
+            for name in skipped {
+                eprintln!(
+                    "Warning: not restoring extended attribute {} of {} without --all-xattrs",
+                    name,
+                    path.display()
+                );
+            }
+            Ok(())
+        }
+        None => {
+            eprintln!(
+                "Warning: no metadata is stored for {}, leaving it unchanged",
+                path.display()
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Returns the keys given explicitly to `decrypt`. A keychain keypair is
+/// loaded once, and its use recorded in the audit log.
+fn decrypt_keys(args: &DecryptArgs) -> Result<(PublicKey, SecretKey), CliError> {
+    if let (Some(name), None, None) = (&args.key, &args.public, &args.secret) {
         let keychain = Keychain::open()?;
-        let lookup = move |key: &PublicKey| -> Option<SecretKey> {
-            keychain
-                .find(key)
-                .map(|keypair| keypair.secret().clone())
-                .ok()
+        let keypair = keychain.get(name)?;
+        record_secret_use(&keychain, &keypair);
+        let public = keypair.public().clone();
+        return Ok((public, keypair.into_secret()));
+    }
+    let public = get_public_key(args.public.as_ref(), args.key.as_ref())?;
+    let secret = get_secret_key(args.secret.as_ref(), args.key.as_ref())?;
+    Ok((public, secret))
+}
+
+/// Returns the `decrypt` option given in `args` that works on a single
+/// stream and would skip the checks on split output, if any.
+fn single_stream_option(args: &DecryptArgs) -> Option<&'static str> {
+    if args.range.is_some() {
+        Some("--range")
+    } else if args.resume {
+        Some("--resume")
+    } else {
+        None
+    }
+}
+
+/// Decrypts the volumes listed in the manifest at `manifest_path` in order,
+/// after checking that they are all present and in the right order. The
+/// output file is removed again if any volume fails to decrypt.
+fn decrypt_split(
+    args: &DecryptArgs,
+    manifest_path: &Path,
+    buffer_size: usize,
+) -> Result<(), CliError> {
+    let volume_error = |error| CliError::VolumeError {
+        error,
+        manifest: manifest_path.to_path_buf(),
+    };
+    let contents = fs::read(manifest_path).map_err(|error| CliError::InputFileIoError {
+        error,
+        path: manifest_path.to_path_buf(),
+    })?;
+    let manifest = Manifest::parse(&contents).map_err(volume_error)?;
+    let paths = manifest
+        .check_volumes(manifest_path)
+        .map_err(volume_error)?;
+    let keys = if args.public.is_none() && args.key.is_none() {
+        None
+    } else {
+        Some(decrypt_keys(args)?)
+    };
+
+    let mut created = None;
+    let result = decrypt_volumes(
+        args,
+        manifest_path,
+        &manifest,
+        &paths,
+        keys,
+        buffer_size,
+        |metadata| {
+            let path = decrypt_output_path(args, metadata)?;
+            let writer = write_or_stdout(path.as_ref(), args.force)?;
+            created = path;
+            Ok(BufWriter::with_capacity(buffer_size, writer))
+        },
+    )
+    .and_then(|(writer, metadata)| {
+        writer
+            .into_inner()
+            .map_err(|error| CliError::StreamIoError {
+                error: error.into_error(),
+            })?;
+        restore_metadata(args, metadata.as_ref(), created.as_deref())
+    });
+    if let (Err(_), Some(path)) = (&result, &created) {
+        let _ = fs::remove_file(path);
+    }
+    result
+}
+
+/// Decrypts the volumes of `manifest` at `paths`, checking each volume's
+/// length, that its trailer places it next in the same set as the first
+/// volume, that the last volume listed ends the set and the hash of the
+/// whole set. The output is opened by
+/// `open_output`, given any file metadata stored at the start of the first
+/// volume when restoring it, and returned along with that metadata.
+fn decrypt_volumes<W: Write>(
+    args: &DecryptArgs,
+    manifest_path: &Path,
+    manifest: &Manifest,
+    paths: &[PathBuf],
+    keys: Option<(PublicKey, SecretKey)>,
+    buffer_size: usize,
+    open_output: impl FnOnce(Option<&FileMetadata>) -> Result<W, CliError>,
+) -> Result<(W, Option<FileMetadata>), CliError> {
+    let stream_error = |error| CliError::StreamIoError { error };
+    let volume_error = |error| CliError::VolumeError {
+        error,
+        manifest: manifest_path.to_path_buf(),
+    };
+    let mut open_output = Some(open_output);
+    let mut output = None;
+    let mut metadata = None;
+    let mut hash = StreamHash::new();
+    let mut set_id = None;
+    let mut end = 0;
+    for (index, (entry, path)) in manifest.volumes.iter().zip(paths).enumerate() {
+        let file = File::open(path).map_err(|error| CliError::InputFileIoError {
+            error,
+            path: path.clone(),
+        })?;
+        let mut reader = HashingReader::new(BufReader::with_capacity(buffer_size, file), &mut hash);
+        let header = StreamHeader::read_from(&mut reader).map_err(stream_error)?;
+        let key = match &keys {
+            Some((public, secret)) => {
+                stream::open_stream_key(&header, public, secret).map_err(stream_error)?
+            }
+            None => find_stream_key(&header)?,
         };
-        SaltlickDecrypter::new_deferred(infile, lookup)
+        let decrypter = StreamDecrypter::new(&key, &mut reader).map_err(stream_error)?;
+        let mut volume = VolumeReader::new(decrypter);
+        let len = match open_output.take() {
+            Some(open_output) => {
+                let mut plaintext = metadata_reader(args, &mut volume);
+                warn_kept_block(&mut plaintext, Some(path))?;
+                metadata = plaintext.metadata().map_err(stream_error)?.cloned();
+                let mut writer = open_output(metadata.as_ref())?;
+                let block_len = plaintext.block_len().map_err(stream_error)?;
+                let len = io::copy(&mut plaintext, &mut writer).map_err(stream_error)?;
+                output = Some(writer);
+                block_len + len
+            }
+            None => {
+                let writer = output
+                    .as_mut()
+                    .expect("output opened with the first volume");
+                io::copy(&mut volume, writer).map_err(stream_error)?
+            }
+        };
+        let trailer = volume.trailer().cloned().expect("volume read to the end");
+        if len != entry.plaintext_len {
+            return Err(volume_error(VolumeError::LengthMismatch {
+                name: entry.name.clone(),
+                expected: entry.plaintext_len,
+                actual: len,
+            }));
+        }
+        end += len;
+        if trailer.set_id != *set_id.get_or_insert(trailer.set_id) || trailer.end != end {
+            return Err(volume_error(VolumeError::Mismatch {
+                name: entry.name.clone(),
+            }));
+        }
+        if trailer.number != index as u64 + 1 {
+            return Err(volume_error(VolumeError::OutOfOrder {
+                name: entry.name.clone(),
+                expected: index + 1,
+                found: trailer.number as usize,
+            }));
+        }
+        if !trailer.last && index + 1 == paths.len() {
+            return Err(volume_error(VolumeError::Truncated {
+                name: entry.name.clone(),
+            }));
+        }
+    }
+    if hash.finish() != manifest.sha256 {
+        return Err(volume_error(VolumeError::HashMismatch));
+    }
+    let output = output.expect("a manifest lists at least one volume");
+    Ok((output, metadata))
+}
+
+/// Decrypts only the plaintext bytes in `range` of the file at `path`,
+/// reading and authenticating just the blocks that hold them. Bytes past the
+/// end of the plaintext are ignored.
+fn decrypt_range(
+    args: &DecryptArgs,
+    path: &Path,
+    range: ByteRange,
+    buffer_size: usize,
+) -> Result<(), CliError> {
+    let stream_error = |error| CliError::StreamIoError { error };
+    let mut decrypter = open_seekable(args, path, buffer_size)?;
+    let len = decrypter.plaintext_len();
+    let start = range.start.min(len);
+    let end = range.end.unwrap_or(len).min(len);
+    // A range running to the end never reads past it, so check the stream
+    // is not truncated before writing anything.
+    if end == len {
+        decrypter.authenticate_end().map_err(stream_error)?;
+    }
+    let outfile = args
+        .outfile
+        .as_ref()
+        .map(|path| create_output(path, args.force))
+        .transpose()?;
+
+    decrypter
+        .seek(SeekFrom::Start(start))
+        .map_err(stream_error)?;
+    let mut reader = decrypter.take(end.saturating_sub(start));
+    copy_to_output(
+        &mut reader,
+        outfile,
+        buffer_size,
+        Some(end.saturating_sub(start)),
+    )
+}
+
+/// Decrypts `infile` to `outfile`, recording checkpoints in a journal, and
+/// continues from the last checkpoint of an earlier interrupted run if its
+/// output is intact.
+fn decrypt_resumable(
+    args: &DecryptArgs,
+    infile: &Path,
+    outfile: &Path,
+    buffer_size: usize,
+) -> Result<(), CliError> {
+    let journal_path = resume::journal_path(outfile);
+    let output_error = |error| CliError::OutputFileIoError {
+        error,
+        path: outfile.to_path_buf(),
+    };
+    let input = InputIdentity::of(infile).map_err(|error| CliError::InputFileIoError {
+        error,
+        path: infile.to_path_buf(),
+    })?;
+    let mut decrypter = open_seekable(args, infile, buffer_size)?;
+
+    let journal = resumable_journal(&journal_path, JournalOp::Decrypt, &input)?;
+    let existing = match &journal {
+        Some(_) => open_existing(outfile)?,
+        None => None,
+    };
+    let resumed = match (&journal, existing) {
+        (Some(journal), Some(file)) => {
+            let resumed =
+                JournaledWriter::resume(file, journal.clone(), journal_path.clone(), buffer_size)
+                    .map_err(output_error)?;
+            if resumed.is_none() {
+                warn_not_resumable(outfile);
+            }
+            resumed
+        }
+        _ => None,
+    };
+    let mut writer = match resumed {
+        Some(writer) => writer,
+        None => {
+            let file = restart_output(outfile, &journal_path, args.force)?;
+            let journal = Journal::new(JournalOp::Decrypt, input);
+            JournaledWriter::create(file, journal, journal_path, buffer_size)
+                .map_err(output_error)?
+        }
+    };
+
+    decrypter
+        .seek(SeekFrom::Start(writer.len()))
+        .map_err(|error| CliError::StreamIoError { error })?;
+    io::copy(&mut decrypter, &mut writer).map_err(|error| CliError::StreamIoError { error })?;
+    writer.finish().map_err(output_error)
+}
+
+/// Opens the saltlick stream in the file at `path` for random access,
+/// finding its key as `decrypt` does.
+fn open_seekable(
+    args: &DecryptArgs,
+    path: &Path,
+    buffer_size: usize,
+) -> Result<SeekableDecrypter<BufReader<File>>, CliError> {
+    let stream_error = |error| CliError::StreamIoError { error };
+    let mut infile = BufReader::with_capacity(
+        buffer_size,
+        File::open(path).map_err(|error| CliError::InputFileIoError {
+            error,
+            path: path.to_path_buf(),
+        })?,
+    );
+    let header = StreamHeader::read_from(&mut infile).map_err(stream_error)?;
+    let key = if args.public.is_none() && args.key.is_none() {
+        find_stream_key(&header)?
     } else {
-        let public = get_public_key(args.public.as_ref(), args.key.as_ref())?;
-        let secret = get_secret_key(args.secret.as_ref(), args.key.as_ref())?;
-        SaltlickDecrypter::new(public, secret, infile)
+        let (public, secret) = decrypt_keys(args)?;
+        stream::open_stream_key(&header, &public, &secret).map_err(stream_error)?
     };
-    io::copy(&mut decrypter, &mut outfile).map_err(|error| CliError::StreamIoError { error })?;
+    SeekableDecrypter::new(&key, infile).map_err(stream_error)
+}
+
+/// Returns the stream key for `header` from the agent, if one is running and
+/// holds the key, or from the keychain as `keychain_lookup` finds it.
+fn find_stream_key(header: &StreamHeader) -> Result<StreamKey, CliError> {
+    #[cfg(unix)]
+    if let Some(key) = AgentClient::from_env().and_then(|client| agent_stream_key(&client, header))
+    {
+        return Ok(key);
+    }
+    let secret = keychain_lookup(Keychain::open()?)(&header.public).ok_or_else(|| {
+        CliError::StreamIoError {
+            error: SaltlickError::SecretKeyNotFound.into(),
+        }
+    })?;
+    stream::open_stream_key(header, &header.public, &secret)
+        .map_err(|error| CliError::StreamIoError { error })
+}
+
+/// Decrypts a batch of files, writing each alongside its input with the
+/// ".slk" extension removed. Without an explicit key, keychain lookups are
+/// cached across the batch.
+fn decrypt_batch(args: DecryptArgs, buffer_size: usize) -> Result<(), CliError> {
+    let explicit_key = if args.public.is_none() && args.key.is_none() {
+        None
+    } else {
+        Some(decrypt_keys(&args)?)
+    };
+    let cache = KeyCache::new(Keychain::open()?);
+    run_batch(&args.batch, |path| {
+        let output = match path.extension() {
+            Some(extension) if extension == BATCH_EXTENSION => path.with_extension(""),
+            _ => {
+                return Err(CliError::NoOutputName {
+                    path: path.to_path_buf(),
+                })
+            }
+        };
+        let infile = read_or_stdin_buffered(Some(path), buffer_size)?;
+        let expected_len = input_len(Some(path)).map(stream::max_plaintext_len);
+        let decrypter = match explicit_key.as_ref() {
+            Some((public, secret)) => {
+                SaltlickDecrypter::new(public.clone(), secret.clone(), infile)
+            }
+            None => {
+                let cache = Arc::clone(&cache);
+                SaltlickDecrypter::new_deferred(infile, move |public| cache.lookup(public))
+            }
+        };
+        let mut plaintext = metadata_reader(&args, decrypter);
+        warn_kept_block(&mut plaintext, Some(path))?;
+        let metadata = plaintext
+            .metadata()
+            .map_err(|error| CliError::StreamIoError { error })?
+            .cloned();
+        process_to_file(plaintext, &output, args.force, buffer_size, expected_len)?;
+        restore_metadata(&args, metadata.as_ref(), Some(&output))
+    })
+}
+
+/// Runs `job` on every file in a batch, reporting each failure. Returns an
+/// error if any file failed.
+fn run_batch<F>(args: &BatchArgs, job: F) -> Result<(), CliError>
+where
+    F: Fn(&Path) -> Result<(), CliError> + Sync,
+{
+    let mut files = args.files.clone();
+    if let Some(list) = args.files_from.as_ref() {
+        files.extend(
+            batch::read_file_list(list).map_err(|error| CliError::InputFileIoError {
+                error,
+                path: list.clone(),
+            })?,
+        );
+    }
+    let jobs = args.jobs.unwrap_or_else(batch::default_jobs);
+    let failures = batch::run(&files, jobs, args.fail_fast, job);
+    for (path, error) in &failures {
+        eprintln!("Error: {}: {}", path.to_string_lossy(), error);
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(CliError::BatchFailed {
+            failed: failures.len(),
+            total: files.len(),
+        })
+    }
+}
+
+/// Returns the journal at `path` if it records `operation` on `input` and
+/// can be resumed. Any other journal is reported and ignored.
+fn resumable_journal(
+    path: &Path,
+    operation: JournalOp,
+    input: &InputIdentity,
+) -> Result<Option<Journal>, CliError> {
+    let journal = match Journal::load(path) {
+        Ok(journal) => journal,
+        Err(error) if error.kind() == io::ErrorKind::InvalidData => {
+            eprintln!(
+                "Warning: ignoring unreadable journal {}: {}",
+                path.to_string_lossy(),
+                error
+            );
+            None
+        }
+        Err(error) => {
+            return Err(CliError::InputFileIoError {
+                error,
+                path: path.to_path_buf(),
+            })
+        }
+    };
+    Ok(journal.filter(|journal| {
+        let matches = journal.operation == operation && &journal.input == input;
+        if !matches {
+            eprintln!(
+                "Warning: journal {} is for a different input, starting again",
+                path.to_string_lossy()
+            );
+        }
+        matches
+    }))
+}
+
+/// Creates the output file at `path` for a `--resume` run that is starting
+/// from the beginning. If there is a journal at `journal_path`, the output
+/// was being written by an earlier run, so it is replaced without `force`.
+fn restart_output(path: &Path, journal_path: &Path, force: bool) -> Result<File, CliError> {
+    create_output(path, force || journal_path.exists())
+}
+
+/// Reports that the output at `path` cannot be resumed from its journal.
+fn warn_not_resumable(path: &Path) {
+    eprintln!(
+        "Warning: {} does not match its journal, starting again",
+        path.to_string_lossy()
+    );
+}
+
+/// Opens the existing file at `path` for reading and writing, returning
+/// `None` if there is no such file.
+fn open_existing(path: &Path) -> Result<Option<File>, CliError> {
+    match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(CliError::OutputFileIoError {
+            error,
+            path: path.to_path_buf(),
+        }),
+    }
+}
+
+/// Copies everything from `reader` into a new file at `path` as
+/// `copy_to_output` does, removing the file again if copying fails part way.
+fn process_to_file(
+    mut reader: impl Read,
+    path: &Path,
+    force: bool,
+    buffer_size: usize,
+    expected_len: Option<u64>,
+) -> Result<(), CliError> {
+    let outfile = create_output(path, force)?;
+    let result = copy_to_output(&mut reader, Some(outfile), buffer_size, expected_len);
+    if result.is_err() {
+        let _ = fs::remove_file(path);
+    }
+    result
+}
+
+/// Runs or controls the key agent.
+#[cfg(unix)]
+fn agent(args: AgentArgs) -> Result<(), CliError> {
+    use std::time::Duration;
+
+    let socket = args
+        .socket
+        .filter(|socket| !socket.as_os_str().is_empty())
+        .ok_or(AgentError::NoSocket)?;
+    let client = AgentClient::new(&socket);
+    match args.cmd {
+        AgentCommand::Add { name, ttl } => {
+            let keychain = Keychain::open()?;
+            let keypair = match name {
+                Some(name) => keychain.get(name)?,
+                None => keychain
+                    .default_keypair()?
+                    .ok_or(CliError::MissingKeyAndPath {
+                        type_: String::from("name"),
+                    })?,
+            };
+            client.add(
+                keypair.name(),
+                keypair.secret(),
+                ttl.map(Duration::from_secs),
+            )?;
+        }
+        AgentCommand::List => {
+            for key in client.list()? {
+                let expires = key
+                    .expires_in
+                    .map(|secs| format!("expires in {}s", secs))
+                    .unwrap_or_else(|| String::from("no expiry"));
+                println!("{}  {}  {}", key.name, key.fingerprint, expires);
+            }
+        }
+        AgentCommand::Lock => client.lock()?,
+        AgentCommand::Start { ttl } => {
+            println!(
+                "{}={}; export {};",
+                agent::SOCKET_ENV,
+                socket.to_string_lossy(),
+                agent::SOCKET_ENV
+            );
+            agent::run(&socket, ttl.map(Duration::from_secs))?;
+        }
+        AgentCommand::Stop => client.stop()?,
+    }
     Ok(())
 }
 
+/// Returns a decrypter for `infile` that finds the secret key itself. If an
+/// agent is running and holds the key `infile` was encrypted to, the agent
+/// opens the stream; otherwise the keychain is searched.
+fn deferred_decrypter(infile: Box<dyn BufRead>) -> Result<Box<dyn Read>, CliError> {
+    #[cfg(unix)]
+    let infile = match AgentClient::from_env() {
+        Some(client) => {
+            let mut infile = infile;
+            let header = StreamHeader::read_from(&mut infile)
+                .map_err(|error| CliError::StreamIoError { error })?;
+            if let Some(key) = agent_stream_key(&client, &header) {
+                let decrypter = StreamDecrypter::new(&key, infile)
+                    .map_err(|error| CliError::StreamIoError { error })?;
+                return Ok(Box::new(decrypter));
+            }
+            Box::new(io::Cursor::new(header.raw).chain(infile))
+        }
+        None => infile,
+    };
+    Ok(Box::new(SaltlickDecrypter::new_deferred(
+        infile,
+        keychain_lookup(Keychain::open()?),
+    )))
+}
+
+/// Opens the encrypted file at `path` for reading its plaintext, warning if
+/// it starts with a file metadata block, which is left in place.
+fn open_plaintext(path: &Path) -> Result<Box<dyn Read>, CliError> {
+    let mut plaintext =
+        MetadataReader::passthrough(deferred_decrypter(read_or_stdin(Some(path))?)?);
+    warn_kept_block(&mut plaintext, Some(path))?;
+    Ok(Box::new(plaintext))
+}
+
+/// Asks the agent to open the stream key in `header`, recording the use of
+/// its keypair in the audit log. Agent errors are reported as warnings.
+#[cfg(unix)]
+fn agent_stream_key(client: &AgentClient, header: &StreamHeader) -> Option<StreamKey> {
+    let opened = client.open(header).unwrap_or_else(|error| {
+        eprintln!("Warning: {}", CliError::from(error));
+        None
+    });
+    let (name, key) = opened?;
+    if let Ok(keychain) = Keychain::open() {
+        if let Err(error) = keychain.record_public_use(AuditOp::Decrypt, &name, &header.public) {
+            eprintln!("Warning: {}", error);
+        }
+    }
+    Some(key)
+}
+
 /// Encrypts input - either from stdin or an input file - and writes it to
-/// stdout or an output file. Request that the key is specified - there's no
-/// reasonable default for encryption, unlike decryption.
+/// stdout or an output file. If no key is specified, the keychain default
+/// keypair is used; it is an error if there is no default.
 fn encrypt(args: EncryptArgs) -> Result<(), CliError> {
-    let public = get_public_key(args.public.as_ref(), args.key.as_ref())?;
-    let infile = read_or_stdin(args.infile.as_ref())?;
-    let mut outfile = write_or_stdout(args.outfile.as_ref(), args.force)?;
-    let mut encrypter = SaltlickEncrypter::new(public, infile);
-    io::copy(&mut encrypter, &mut outfile).map_err(|error| CliError::StreamIoError { error })?;
+    let key = match (args.public.as_ref(), args.key.as_ref()) {
+        (None, None) => Keychain::open()?
+            .default_name()?
+            .map(|name| name.to_string()),
+        (_, key) => key.cloned(),
+    };
+    let public = get_public_key(args.public.as_ref(), key.as_ref())?;
+    if let Some(name) = key.as_ref() {
+        check_expiry(name, args.allow_expired)?;
+    }
+    if args.preserve_metadata && args.infile.is_none() && !args.batch.is_batch() {
+        return Err(CliError::MetadataRequiresFile {
+            option: "--preserve-metadata".to_string(),
+        });
+    }
+    let buffer_size = buffer_size(args.buffer_size)?;
+    let block_size = size_option(
+        "--block-size",
+        args.block_size,
+        buffer::DEFAULT_BLOCK_SIZE,
+        buffer::MIN_BLOCK_SIZE,
+        buffer::MAX_BLOCK_SIZE,
+    )?;
+    let encrypter = |infile| {
+        let mut encrypter = SaltlickEncrypter::new(public.clone(), infile);
+        encrypter.set_block_size(block_size);
+        encrypter
+    };
+    if args.batch.is_batch() {
+        return run_batch(&args.batch, |path| {
+            let mut output = path.as_os_str().to_owned();
+            output.push(format!(".{}", BATCH_EXTENSION));
+            let (infile, block_len) = encrypt_input(&args, Some(path), buffer_size)?;
+            process_to_file(
+                encrypter(infile),
+                Path::new(&output),
+                args.force,
+                buffer_size,
+                input_len(Some(path)).map(|len| stream::encrypted_len(len + block_len, block_size)),
+            )
+        });
+    }
+    if let (true, Some(infile), Some(outfile)) = (args.remove_source, &args.infile, &args.outfile) {
+        return encrypt_and_remove(&args, &public, infile, outfile, block_size, buffer_size);
+    }
+    if let (true, Some(infile), Some(outfile)) = (args.resume, &args.infile, &args.outfile) {
+        return encrypt_resumable(&args, &public, infile, outfile, block_size, buffer_size);
+    }
+    if let (Some(split_size), Some(outfile)) = (args.split_size, &args.outfile) {
+        let split_size = size_option(
+            "--split-size",
+            Some(split_size),
+            0,
+            volume::min_split_size(block_size) as usize,
+            usize::try_from(volume::MAX_SPLIT_SIZE).unwrap_or(usize::MAX),
+        )?;
+        let (mut infile, _) = encrypt_input(&args, args.infile.as_deref(), buffer_size)?;
+        let mut written = Vec::new();
+        let result = encrypt_split(
+            &public,
+            &mut infile,
+            outfile,
+            split_size as u64,
+            block_size,
+            buffer_size,
+            args.force,
+            &mut written,
+        );
+        if result.is_err() {
+            for path in written {
+                let _ = fs::remove_file(path);
+            }
+        }
+        return result;
+    }
+    let (infile, block_len) = encrypt_input(&args, args.infile.as_deref(), buffer_size)?;
+    let outfile = args
+        .outfile
+        .as_ref()
+        .map(|path| create_output(path, args.force))
+        .transpose()?;
+    let expected_len = input_len(args.infile.as_ref())
+        .map(|len| stream::encrypted_len(len + block_len, block_size));
+    copy_to_output(&mut encrypter(infile), outfile, buffer_size, expected_len)
+}
+
+/// Opens `path`, or stdin if it is `None`, for encryption. With
+/// `--preserve-metadata` the file's metadata block is read first; its length
+/// is returned along with the reader.
+fn encrypt_input(
+    args: &EncryptArgs,
+    path: Option<&Path>,
+    buffer_size: usize,
+) -> Result<(Box<dyn BufRead>, u64), CliError> {
+    let block = metadata_block(args, path)?;
+    let infile = read_or_stdin_buffered(path, buffer_size)?;
+    if block.is_empty() {
+        return Ok((infile, 0));
+    }
+    let len = block.len() as u64;
+    Ok((Box::new(io::Cursor::new(block).chain(infile)), len))
+}
+
+/// Returns the metadata block of the file at `path` to encrypt ahead of its
+/// contents with `--preserve-metadata`, or nothing without it.
+fn metadata_block(args: &EncryptArgs, path: Option<&Path>) -> Result<Vec<u8>, CliError> {
+    match path {
+        Some(path) if args.preserve_metadata => FileMetadata::read(path, args.xattrs)
+            .map(|metadata| metadata.to_block())
+            .map_err(|error| CliError::InputFileIoError {
+                error,
+                path: path.to_path_buf(),
+            }),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Encrypts `infile` to `outfile` to `public`, then removes `infile` once
+/// the output is synced to disk and decrypts back to it. An output that
+/// cannot be verified is removed instead.
+fn encrypt_and_remove(
+    args: &EncryptArgs,
+    public: &PublicKey,
+    infile: &Path,
+    outfile: &Path,
+    block_size: usize,
+    buffer_size: usize,
+) -> Result<(), CliError> {
+    let remove_error = |error| CliError::RemoveError {
+        error,
+        path: infile.to_path_buf(),
+    };
+    let input_error = |error| CliError::InputFileIoError {
+        error,
+        path: infile.to_path_buf(),
+    };
+    remove::check_removable(infile).map_err(remove_error)?;
+    let input = InputIdentity::of(infile).map_err(input_error)?;
+    let block = metadata_block(args, Some(infile))?;
+    let mut reader = io::Cursor::new(&block).chain(BufReader::with_capacity(
+        buffer_size,
+        File::open(infile).map_err(input_error)?,
+    ));
+    let file = create_output(outfile, args.force)?;
+
+    let key = StreamKey::generate();
+    let header = StreamHeader::seal(public, &key);
+    let result =
+        write_stream(&mut reader, file, &header, &key, block_size, buffer_size).and_then(|()| {
+            File::open(infile)
+                .and_then(|file| {
+                    let plaintext = io::Cursor::new(&block).chain(BufReader::new(file));
+                    remove::verify(outfile, &header, &key, plaintext, buffer_size)
+                })
+                .map_err(|error| remove_error(RemoveError::Unverified(error)))
+        });
+    if result.is_err() {
+        let _ = fs::remove_file(outfile);
+    }
+    result?;
+    remove::sync_parent(outfile).map_err(|error| CliError::OutputFileIoError {
+        error,
+        path: outfile.to_path_buf(),
+    })?;
+
+    if InputIdentity::of(infile).map_err(input_error)? != input {
+        return Err(remove_error(RemoveError::Changed));
+    }
+    remove::check_removable(infile).map_err(remove_error)?;
+    remove::remove(infile, args.shred.unwrap_or(0))
+        .map_err(|error| remove_error(RemoveError::Io(error)))
+}
+
+/// Writes `header` and then everything from `reader`, encrypted with `key`,
+/// to `file`, and syncs it to disk.
+fn write_stream(
+    reader: &mut impl Read,
+    mut file: File,
+    header: &StreamHeader,
+    key: &StreamKey,
+    block_size: usize,
+    buffer_size: usize,
+) -> Result<(), CliError> {
+    let stream_error = |error| CliError::StreamIoError { error };
+    file.write_all(&header.raw).map_err(stream_error)?;
+    let writer = BufWriter::with_capacity(buffer_size, file);
+    let mut encrypter = StreamEncrypter::new(key, writer, block_size).map_err(stream_error)?;
+    io::copy(reader, &mut encrypter).map_err(stream_error)?;
+    encrypter
+        .finish()
+        .and_then(|writer| writer.into_inner().map_err(|error| error.into_error()))
+        .and_then(|file| file.sync_all())
+        .map_err(stream_error)
+}
+
+/// Encrypts `infile` to `outfile` to `public`, recording the stream header
+/// and a hash of the input in a journal, and continues an earlier interrupted
+/// run from its last intact block if the input has not changed.
+fn encrypt_resumable(
+    args: &EncryptArgs,
+    public: &PublicKey,
+    infile: &Path,
+    outfile: &Path,
+    block_size: usize,
+    buffer_size: usize,
+) -> Result<(), CliError> {
+    let journal_path = resume::journal_path(outfile);
+    let input_error = |error| CliError::InputFileIoError {
+        error,
+        path: infile.to_path_buf(),
+    };
+    let output_error = |error| CliError::OutputFileIoError {
+        error,
+        path: outfile.to_path_buf(),
+    };
+    let input = InputIdentity::of(infile).map_err(input_error)?;
+    let open_input = || File::open(infile).map_err(input_error);
+
+    let journal = resumable_journal(&journal_path, JournalOp::Encrypt, &input)?;
+    let existing = match &journal {
+        Some(_) => open_existing(outfile)?,
+        None => None,
+    };
+    let recovered = match (&journal, existing) {
+        (Some(journal), Some(file)) => {
+            recover_encrypted(journal, public, file, outfile, buffer_size)?
+        }
+        _ => None,
+    };
+    let resumed = match (journal, recovered) {
+        (Some(journal), Some((file, recovered))) => {
+            if recovered.finished {
+                file.sync_all().map_err(output_error)?;
+                return fs::remove_file(&journal_path).map_err(output_error);
+            }
+            // The stream's nonces are only safe to reuse for the same input.
+            let reader = JournaledReader::resume(
+                open_input()?,
+                journal,
+                journal_path.clone(),
+                buffer_size,
+                recovered.plaintext_len(),
+            )
+            .map_err(input_error)?;
+            if reader.is_none() {
+                warn_not_resumable(infile);
+            }
+            reader.map(|reader| {
+                let writer = BufWriter::with_capacity(buffer_size, file);
+                (reader, StreamEncrypter::resume(recovered, writer))
+            })
+        }
+        _ => None,
+    };
+    let (mut reader, mut encrypter) = match resumed {
+        Some(resumed) => resumed,
+        None => {
+            let mut file = restart_output(outfile, &journal_path, args.force)?;
+            let key = StreamKey::generate();
+            let header = StreamHeader::seal(public, &key);
+            let mut journal = Journal::new(JournalOp::Encrypt, input);
+            journal.block_size = Some(block_size);
+            journal.header = Some(hex(&header.raw));
+            let reader =
+                JournaledReader::create(open_input()?, journal, journal_path.clone(), buffer_size)
+                    .map_err(|error| CliError::OutputFileIoError {
+                        error,
+                        path: journal_path.clone(),
+                    })?;
+            file.write_all(&header.raw).map_err(output_error)?;
+            let writer = BufWriter::with_capacity(buffer_size, file);
+            let encrypter = StreamEncrypter::new(&key, writer, block_size).map_err(output_error)?;
+            (reader, encrypter)
+        }
+    };
+
+    io::copy(&mut reader, &mut encrypter).map_err(|error| CliError::StreamIoError { error })?;
+    let file = encrypter
+        .finish()
+        .and_then(|writer| writer.into_inner().map_err(|error| error.into_error()))
+        .map_err(output_error)?;
+    file.sync_all().map_err(output_error)?;
+    fs::remove_file(&journal_path).map_err(output_error)
+}
+
+/// Encrypts `infile` to `public` as volumes of at most `split_size` bytes
+/// named after `outfile`, each with its own stream key, then writes the
+/// manifest listing them. Every file created is added to `written`.
+#[allow(clippy::too_many_arguments)]
+fn encrypt_split(
+    public: &PublicKey,
+    infile: &mut impl BufRead,
+    outfile: &Path,
+    split_size: u64,
+    block_size: usize,
+    buffer_size: usize,
+    force: bool,
+    written: &mut Vec<PathBuf>,
+) -> Result<(), CliError> {
+    let capacity = volume::volume_capacity(split_size, block_size)
+        .expect("split size is at least the minimum");
+    let manifest_path = volume::manifest_path(outfile);
+    let mut manifest_file = create_output(&manifest_path, force)?;
+    written.push(manifest_path.clone());
+
+    let mut hash = StreamHash::new();
+    let mut volumes = Vec::new();
+    let set_id = VolumeTrailer::generate_set_id();
+    let mut end = 0;
+    loop {
+        let path = volume::volume_path(outfile, volumes.len() + 1);
+        let file = create_output(&path, force)?;
+        written.push(path.clone());
+        let output_error = |error| CliError::OutputFileIoError {
+            error,
+            path: path.clone(),
+        };
+
+        let key = StreamKey::generate();
+        let header = StreamHeader::seal(public, &key);
+        let mut writer = HashingWriter::new(BufWriter::with_capacity(buffer_size, file), &mut hash);
+        writer.write_all(&header.raw).map_err(output_error)?;
+        let mut encrypter = StreamEncrypter::new(&key, writer, block_size).map_err(output_error)?;
+        let plaintext_len = io::copy(&mut (&mut *infile).take(capacity), &mut encrypter)
+            .map_err(|error| CliError::StreamIoError { error })?;
+        let last = infile
+            .fill_buf()
+            .map_err(|error| CliError::StreamIoError { error })?
+            .is_empty();
+        end += plaintext_len;
+        let trailer = VolumeTrailer {
+            set_id,
+            number: volumes.len() as u64 + 1,
+            last,
+            end,
+        };
+        encrypter
+            .write_all(&trailer.to_bytes())
+            .map_err(output_error)?;
+        let writer = encrypter.finish().map_err(output_error)?;
+        let len = writer.len();
+        writer
+            .into_inner()
+            .into_inner()
+            .map_err(|error| error.into_error())
+            .and_then(|file| file.sync_all())
+            .map_err(output_error)?;
+
+        volumes.push(VolumeEntry {
+            name: path
+                .file_name()
+                .expect("volume path has a file name")
+                .to_string_lossy()
+                .into_owned(),
+            len,
+            plaintext_len,
+            header_sha256: volume::header_sha256(&header.raw),
+        });
+        if last {
+            break;
+        }
+    }
+
+    let manifest = Manifest::new(volumes, hash);
+    manifest_file
+        .write_all(&manifest.to_vec())
+        .and_then(|()| manifest_file.sync_all())
+        .map_err(|error| CliError::OutputFileIoError {
+            error,
+            path: manifest_path,
+        })
+}
+
+/// Finds the intact blocks of the stream `journal` was recording in `file`,
+/// the output at `path`, truncating anything after them and leaving `file`
+/// positioned at the end. The stream key is opened from the header with the
+/// recipient's secret key, found as `decrypt` finds it. Returns `None`,
+/// after a warning, if `file` is not the stream the journal records, is not
+/// encrypted to `public`, or its key cannot be opened.
+fn recover_encrypted(
+    journal: &Journal,
+    public: &PublicKey,
+    mut file: File,
+    path: &Path,
+    buffer_size: usize,
+) -> Result<Option<(File, stream::RecoveredStream)>, CliError> {
+    let header = journal.header.as_deref().and_then(hex_decode);
+    let mut reader = BufReader::with_capacity(buffer_size, &mut file);
+    let written = StreamHeader::read_from(&mut reader).ok();
+    let (written, block_size) = match (header, written, journal.block_size) {
+        (Some(header), Some(written), Some(block_size))
+            if written.raw == header && &written.public == public =>
+        {
+            (written, block_size)
+        }
+        _ => {
+            warn_not_resumable(path);
+            return Ok(None);
+        }
+    };
+    let key = match find_stream_key(&written) {
+        Ok(key) => key,
+        Err(error) => {
+            eprintln!(
+                "Warning: unable to open the stream key of {} ({}), starting again",
+                path.display(),
+                error
+            );
+            return Ok(None);
+        }
+    };
+    let output_error = |error| CliError::OutputFileIoError {
+        error,
+        path: path.to_path_buf(),
+    };
+    let recovered = stream::recover(&key, &mut reader, block_size).map_err(output_error)?;
+    drop(reader);
+    if !recovered.finished {
+        let len = stream::HEADER_LEN as u64 + recovered.body_len();
+        file.set_len(len)
+            .and_then(|()| file.seek(SeekFrom::Start(len)))
+            .map_err(output_error)?;
+    }
+    Ok(Some((file, recovered)))
+}
+
+/// Measures encryption and decryption throughput and prints the results.
+fn bench(args: BenchArgs) -> Result<(), CliError> {
+    let sizes = if args.sizes.is_empty() {
+        bench::DEFAULT_SIZES.to_vec()
+    } else {
+        args.sizes.iter().map(|size| size.0).collect()
+    };
+    let buffer_sizes = if args.buffer_sizes.is_empty() {
+        bench::DEFAULT_BUFFER_SIZES.to_vec()
+    } else {
+        args.buffer_sizes
+            .iter()
+            .map(|&size| buffer_size(Some(size)))
+            .collect::<Result<_, _>>()?
+    };
+    let measurements = bench::run(&sizes, &buffer_sizes, args.iterations)
+        .map_err(|error| CliError::StreamIoError { error })?;
+    if args.json {
+        let json = serde_json::to_string_pretty(&measurements)
+            .expect("measurements can always be serialized");
+        println!("{}", json);
+        return Ok(());
+    }
+    println!(
+        "{:<9} {:>10} {:>10} {:>10} {:>12}",
+        "operation", "size", "buffer", "MB/s", "CPU/iter"
+    );
+    for measurement in &measurements {
+        let cpu = measurement
+            .cpu_seconds
+            .map(|cpu| format!("{:.3} ms", cpu * 1000.0))
+            .unwrap_or_else(|| String::from("-"));
+        println!(
+            "{:<9} {:>10} {:>10} {:>10.1} {:>12}",
+            measurement.operation,
+            format_size(measurement.input_size),
+            format_size(measurement.buffer_size),
+            measurement.megabytes_per_second,
+            cpu
+        );
+    }
     Ok(())
 }
 
+/// Decrypts each of `args.files` in turn to stdout.
+fn cat(args: CatArgs) -> Result<(), CliError> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for path in &args.files {
+        let mut decrypter = open_plaintext(path)?;
+        ignore_broken_pipe(io::copy(&mut decrypter, &mut out).map(|_| ()))?;
+    }
+    Ok(())
+}
+
+/// Prints the first `args.lines` lines of each file, with a header naming
+/// the file when there is more than one. Decryption stops once enough lines
+/// have been read.
+fn head(args: HeadArgs) -> Result<(), CliError> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for (index, path) in args.files.iter().enumerate() {
+        let decrypter = open_plaintext(path)?;
+        let result = (|| {
+            if args.files.len() > 1 {
+                let separator = if index > 0 { "\n" } else { "" };
+                writeln!(out, "{}==> {} <==", separator, path.to_string_lossy())?;
+            }
+            for line in BufReader::new(decrypter).split(b'\n').take(args.lines) {
+                out.write_all(&line?)?;
+                out.write_all(b"\n")?;
+            }
+            Ok(())
+        })();
+        ignore_broken_pipe(result)?;
+    }
+    Ok(())
+}
+
+/// Searches the decrypted contents of each file for lines matching
+/// `args.pattern`. Exits with status 1 if no lines match, like grep.
+fn grep(args: GrepArgs) -> Result<(), CliError> {
+    let pattern = RegexBuilder::new(&args.pattern)
+        .case_insensitive(args.ignore_case)
+        .build()
+        .map_err(|error| CliError::InvalidPattern { error })?;
+    let with_filename = args.with_filename || (args.files.len() > 1 && !args.no_filename);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut matched = false;
+    for path in &args.files {
+        let decrypter = open_plaintext(path)?;
+        let result = (|| {
+            let prefix = if with_filename {
+                format!("{}:", path.to_string_lossy())
+            } else {
+                String::new()
+            };
+            let mut count = 0;
+            for (index, line) in BufReader::new(decrypter).split(b'\n').enumerate() {
+                let line = line?;
+                if pattern.is_match(&line) == args.invert_match {
+                    continue;
+                }
+                count += 1;
+                if args.count {
+                    continue;
+                }
+                out.write_all(prefix.as_bytes())?;
+                if args.line_number {
+                    write!(out, "{}:", index + 1)?;
+                }
+                out.write_all(&line)?;
+                out.write_all(b"\n")?;
+            }
+            if args.count {
+                writeln!(out, "{}{}", prefix, count)?;
+            }
+            Ok(count > 0)
+        })();
+        if let Some(file_matched) = ignore_broken_pipe(result.map(Some))? {
+            matched |= file_matched;
+        } else {
+            break;
+        }
+    }
+    if !matched {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Prints a unified diff of the decrypted contents of two files, or runs an
+/// external diff tool on them. Exits with status 1 if they differ and 2 on
+/// errors, like diff.
+fn diff(args: DiffArgs) -> Result<(), CliError> {
+    let old = decrypt_to_memory(&args.old, args.max_size)?;
+    let new = decrypt_to_memory(&args.new, args.max_size)?;
+    if let Some(tool) = args.external.as_ref() {
+        return diff_external(tool, (&args.old, &old), (&args.new, &new));
+    }
+    if old == new {
+        return Ok(());
+    }
+    let old_name = args.old.to_string_lossy();
+    let new_name = args.new.to_string_lossy();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let written = match (std::str::from_utf8(&old), std::str::from_utf8(&new)) {
+        (Ok(old_text), Ok(new_text)) if !old_text.contains('\0') && !new_text.contains('\0') => {
+            let diff = TextDiff::from_lines(old_text, new_text);
+            write!(
+                out,
+                "{}",
+                diff.unified_diff()
+                    .context_radius(args.context)
+                    .header(&old_name, &new_name)
+            )
+        }
+        _ => writeln!(out, "Binary files {} and {} differ", old_name, new_name),
+    };
+    ignore_broken_pipe(written)?;
+    process::exit(1);
+}
+
+/// Writes decrypted contents to private temporary files and runs `tool` on
+/// them, exiting with its exit status. The files are removed afterwards.
+fn diff_external(
+    tool: &str,
+    (old_path, old): (&Path, &[u8]),
+    (new_path, new): (&Path, &[u8]),
+) -> Result<(), CliError> {
+    let temp_name = |prefix: &str, path: &Path| {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        format!("{}-{}", prefix, name)
+    };
+    let old_temp = write_private_temp(&temp_name("old", old_path), old)?;
+    let new_temp = match write_private_temp(&temp_name("new", new_path), new) {
+        Ok(path) => path,
+        Err(error) => {
+            let _ = fs::remove_file(&old_temp);
+            return Err(error);
+        }
+    };
+    let status = shell_words_command(tool)
+        .arg(&old_temp)
+        .arg(&new_temp)
+        .status();
+    let _ = fs::remove_file(&old_temp);
+    let _ = fs::remove_file(&new_temp);
+    let status = status.map_err(|error| CliError::ExecError {
+        command: tool.to_string(),
+        error,
+    })?;
+    process::exit(status.code().unwrap_or(2));
+}
+
+/// Decrypts `path` into memory, looking up the key in the keychain. It is an
+/// error if the decrypted contents are larger than `max_size` bytes.
+fn decrypt_to_memory(path: &Path, max_size: u64) -> Result<Vec<u8>, CliError> {
+    let mut contents = Vec::new();
+    open_plaintext(path)?
+        .take(max_size.saturating_add(1))
+        .read_to_end(&mut contents)
+        .map_err(|error| CliError::StreamIoError { error })?;
+    if contents.len() as u64 > max_size {
+        return Err(CliError::TooLarge {
+            path: path.to_path_buf(),
+            max_size,
+        });
+    }
+    Ok(contents)
+}
+
+/// Treats a closed stdout, such as when piping into `head`, as success
+/// rather than an error, returning `Default::default()` in that case.
+fn ignore_broken_pipe<T: Default>(result: io::Result<T>) -> Result<T, CliError> {
+    match result {
+        Err(ref error) if error.kind() == io::ErrorKind::BrokenPipe => Ok(T::default()),
+        result => result.map_err(|error| CliError::StreamIoError { error }),
+    }
+}
+
+/// Decrypts dotenv files in memory and runs a command with their variables
+/// added to its environment. On Unix the command replaces this process, so
+/// its exit status and signals are those of the command itself.
+fn exec(args: ExecArgs) -> Result<(), CliError> {
+    let mut variables: Vec<(String, String)> = Vec::new();
+    for path in &args.infiles {
+        let mut plaintext = Vec::new();
+        open_plaintext(path)?
+            .read_to_end(&mut plaintext)
+            .map_err(|error| CliError::StreamIoError { error })?;
+        let text = String::from_utf8(plaintext).map_err(|error| CliError::DotenvError {
+            error: DotenvError {
+                line: 0,
+                reason: format!("file is not valid UTF-8: {}", error.utf8_error()),
+            },
+            path: path.clone(),
+        })?;
+        let parsed = dotenv::parse(&text).map_err(|error| CliError::DotenvError {
+            error,
+            path: path.clone(),
+        })?;
+        for (name, value) in parsed {
+            let existing = variables.iter().position(|(existing, _)| *existing == name);
+            let defined = existing.is_some() || env::var_os(&name).is_some();
+            match (args.override_policy, existing) {
+                (OverridePolicy::Error, _) if defined => {
+                    return Err(CliError::VariableConflict { name })
+                }
+                (OverridePolicy::Never, _) if defined => {}
+                (_, Some(index)) => variables[index].1 = value,
+                (_, None) => variables.push((name, value)),
+            }
+        }
+    }
+
+    let program = args.command[0].clone();
+    let mut command = process::Command::new(&program);
+    secret::restore_core_dumps(&mut command)
+        .args(&args.command[1..])
+        .envs(variables);
+    let exec_error = |error| CliError::ExecError {
+        command: program.to_string_lossy().into_owned(),
+        error,
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // Only returns on failure.
+        Err(exec_error(command.exec()))
+    }
+    #[cfg(not(unix))]
+    {
+        let status = command.status().map_err(exec_error)?;
+        process::exit(status.code().unwrap_or(1));
+    }
+}
+
+/// Encrypts the values of a document to one or more recipients. If no
+/// recipients are specified, the keychain default keypair is used.
+fn encrypt_values(args: EncryptValuesArgs) -> Result<(), CliError> {
+    let format = values_format(args.format, args.infile.as_ref())?;
+    let mut recipients = Vec::new();
+    let mut names = args.keys;
+    if names.is_empty() && args.publics.is_empty() {
+        match Keychain::open()?.default_name()? {
+            Some(name) => names.push(name.to_string()),
+            None => {
+                return Err(CliError::MissingKeyAndPath {
+                    type_: String::from("public"),
+                })
+            }
+        }
+    }
+    for name in &names {
+        check_expiry(name, args.allow_expired)?;
+        recipients.push(get_public_key(None::<&Path>, Some(name))?);
+    }
+    for path in &args.publics {
+        recipients.push(get_public_key(Some(path), None::<&str>)?);
+    }
+
+    let document = format.parse(&read_document(args.infile.as_ref())?)?;
+    let envelope = Envelope::new(&recipients, args.encrypted_regex.as_deref())?;
+    let encrypted = format.render(&envelope.encrypt(&document)?)?;
+    write_or_stdout(args.outfile.as_ref(), args.force)?
+        .write_all(encrypted.as_bytes())
+        .map_err(|error| CliError::StreamIoError { error })
+}
+
+/// Decrypts the values of a document produced by `encrypt_values`.
+fn decrypt_values(args: DecryptValuesArgs) -> Result<(), CliError> {
+    let format = values_format(args.format, args.infile.as_ref())?;
+    let document = format.parse(&read_document(args.infile.as_ref())?)?;
+    let envelope = open_envelope(&document, args.key.as_ref())?;
+    let decrypted = format.render(&envelope.decrypt(&document)?)?;
+    write_or_stdout(args.outfile.as_ref(), args.force)?
+        .write_all(decrypted.as_bytes())
+        .map_err(|error| CliError::StreamIoError { error })
+}
+
+/// Decrypts a document into a temporary file, opens it in the user's editor
+/// and encrypts the result back into place with the same data key and
+/// recipients.
+fn edit_values(args: EditValuesArgs) -> Result<(), CliError> {
+    let format = values_format(args.format, Some(&args.file))?;
+    let document = format.parse(&read_document(Some(&args.file))?)?;
+    let envelope = open_envelope(&document, args.key.as_ref())?;
+    let plaintext = format.render(&envelope.decrypt(&document)?)?;
+    let edited = edit_in_editor(&plaintext, format.extension())?;
+    if edited == plaintext {
+        println!("No changes made");
+        return Ok(());
+    }
+    let encrypted = format.render(&envelope.encrypt(&format.parse(&edited)?)?)?;
+    write_replace(&args.file, encrypted).map_err(|error| CliError::OutputFileIoError {
+        error,
+        path: args.file.clone(),
+    })
+}
+
+/// Returns `format`, or the format matching the extension of `path`.
+fn values_format(format: Option<Format>, path: Option<&PathBuf>) -> Result<Format, CliError> {
+    format
+        .or_else(|| path.and_then(Format::from_path))
+        .ok_or_else(|| CliError::UnknownFormat {
+            path: path.cloned(),
+        })
+}
+
+/// Reads the whole of `path`, or stdin, as a string.
+fn read_document(path: Option<&PathBuf>) -> Result<String, CliError> {
+    let mut text = String::new();
+    read_or_stdin(path)?
+        .read_to_string(&mut text)
+        .map_err(|error| CliError::StreamIoError { error })?;
+    Ok(text)
+}
+
+/// Opens the envelope of an encrypted document with keychain keypair `name`,
+/// or the first keychain keypair that is one of the document's recipients.
+fn open_envelope(document: &Value, name: Option<&String>) -> Result<Envelope, CliError> {
+    let keychain = Keychain::open()?;
+    let keypair = match name {
+        Some(name) => keychain.get(name)?,
+        None => Envelope::recipients(document)?
+            .iter()
+            .find_map(|recipient| keychain.find_fingerprint(&recipient.fingerprint).ok())
+            .ok_or(ValuesError::NoMatchingKey)?,
+    };
+    let envelope = Envelope::open(document, keypair.public(), keypair.secret())?;
+    record_secret_use(&keychain, &keypair);
+    Ok(envelope)
+}
+
+/// Writes `contents` to a temporary file readable only by the current user,
+/// opens it in `$VISUAL` or `$EDITOR` (falling back to `vi`), and returns
+/// the edited contents. The temporary file is removed afterwards.
+fn edit_in_editor(contents: &str, extension: &str) -> Result<String, CliError> {
+    let path = write_private_temp(&format!("edit.{}", extension), contents.as_bytes())?;
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| String::from("vi"));
+    let status = shell_words_command(&editor).arg(&path).status();
+    let result = match status {
+        Ok(status) if status.success() => {
+            fs::read_to_string(&path).map_err(|error| CliError::InputFileIoError {
+                error,
+                path: path.clone(),
+            })
+        }
+        Ok(status) => Err(CliError::EditorFailed { editor, status }),
+        Err(error) => Err(CliError::EditorError { editor, error }),
+    };
+    let _ = fs::remove_file(&path);
+    result
+}
+
+/// Writes `contents` to a new temporary file, readable only by the current
+/// user, whose name ends with `suffix`. The caller must remove it.
+fn write_private_temp(suffix: &str, contents: &[u8]) -> Result<PathBuf, CliError> {
+    let path = env::temp_dir().join(format!("saltlick-{}-{}", hex(&randombytes(8)), suffix));
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let written = options
+        .open(&path)
+        .and_then(|mut file| file.write_all(contents));
+    if let Err(error) = written {
+        let _ = fs::remove_file(&path);
+        return Err(CliError::OutputFileIoError { error, path });
+    }
+    Ok(path)
+}
+
+/// Builds a command from a user-supplied string such as `$EDITOR`, where
+/// the program may be followed by whitespace-separated arguments.
+fn shell_words_command(command: &str) -> process::Command {
+    let mut words = command.split_whitespace();
+    let mut builder = process::Command::new(words.next().unwrap_or_default());
+    secret::restore_core_dumps(&mut builder).args(words);
+    builder
+}
+
+/// Returns an error if keychain key `name` has expired, or only prints a
+/// warning if `allow_expired` is set.
+fn check_expiry(name: impl AsRef<str>, allow_expired: bool) -> Result<(), CliError> {
+    let metadata = Keychain::open()?.metadata(name.as_ref())?;
+    match metadata.expires {
+        Some(expires) if metadata.is_expired() => {
+            if allow_expired {
+                eprintln!(
+                    "Warning: keypair \"{}\" expired on {}",
+                    name.as_ref(),
+                    expires
+                );
+                Ok(())
+            } else {
+                Err(CliError::KeyExpired {
+                    name: name.as_ref().to_string(),
+                    expires,
+                })
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
 /// Generates a brand new key pair and writes it to the paths provided.
 fn generate(args: GenerateArgs) -> Result<(), CliError> {
     let (public, secret) = saltlick::gen_keypair();
@@ -195,12 +1914,43 @@ fn keychain(args: KeychainArgs) -> Result<(), CliError> {
     use self::KeychainArgs::*;
     let keychain = Keychain::open()?;
     match args {
+        Combine { name, shares } => {
+            let shares = shares
+                .iter()
+                .map(|path| {
+                    fs::read_to_string(path)
+                        .map_err(|error| CliError::InputFileIoError {
+                            error,
+                            path: path.clone(),
+                        })
+                        .and_then(|text| {
+                            KeyShare::from_text(text).map_err(|error| CliError::ShareError {
+                                error,
+                                path: Some(path.clone()),
+                            })
+                        })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let (public, secret) = shamir::combine(&shares)?;
+            keychain.import(&name, public, secret)?;
+            println!(
+                "Recovered keypair \"{}\" from {} shares",
+                name,
+                shares.len()
+            );
+            Ok(())
+        }
         Export {
             name,
             public,
             secret,
+            paper,
+            qr_svg,
         } => {
             let keypair = keychain.get(name)?;
+            if public.is_some() || secret.is_some() || paper {
+                keychain.record_use(AuditOp::Export, &keypair)?;
+            }
             if let Some(path) = public {
                 keypair.public().to_file(&path)?;
                 println!("Exported public key \"{}\"", path.to_string_lossy());
@@ -209,11 +1959,47 @@ fn keychain(args: KeychainArgs) -> Result<(), CliError> {
                 keypair.secret().to_file(&path)?;
                 println!("Exported secret key \"{}\"", path.to_string_lossy());
             }
+            if paper {
+                print!("{}", paper::render_text(keypair.name(), keypair.secret()));
+                println!();
+                println!("{}", paper::render_qr_terminal(keypair.secret()));
+            }
+            if let Some(path) = qr_svg {
+                create_private_output(&path)?
+                    .write_all(paper::render_qr_svg(keypair.secret()).as_bytes())
+                    .map_err(|error| CliError::OutputFileIoError {
+                        error,
+                        path: path.clone(),
+                    })?;
+                eprintln!("Wrote QR code \"{}\"", path.to_string_lossy());
+            }
             Ok(())
         }
-        Generate { name } => {
-            let (public, secret) = saltlick::gen_keypair();
-            keychain.create(&name, public, secret)?;
+        Generate {
+            name,
+            from_mnemonic,
+            from_passphrase,
+            show_mnemonic,
+            meta,
+        } => {
+            let (public, secret) = if from_mnemonic {
+                let phrase = read_secret_input("Mnemonic: ", false)?;
+                derive::keypair_from_mnemonic(&name, phrase)?
+            } else if from_passphrase {
+                let passphrase = read_secret_input("Passphrase: ", true)?;
+                derive::keypair_from_passphrase(&name, passphrase)?
+            } else if show_mnemonic {
+                let phrase = derive::generate_mnemonic();
+                let keypair = derive::keypair_from_mnemonic(&name, &phrase)?;
+                println!("Mnemonic for keypair \"{}\":", name);
+                println!("{}", phrase);
+                keypair
+            } else {
+                saltlick::gen_keypair()
+            };
+            let mut metadata = KeyMetadata::now();
+            apply_metadata_args(&mut metadata, meta);
+            keychain.create_with_metadata(&name, public, secret, metadata)?;
             println!("Created keypair \"{}\"", name);
             Ok(())
         }
@@ -221,16 +2007,52 @@ fn keychain(args: KeychainArgs) -> Result<(), CliError> {
             name,
             public,
             secret,
+            paper,
         } => {
-            let public = get_public_key(Some(public), None as Option<&str>)?;
-            let secret = get_secret_key(Some(secret), None as Option<&str>)?;
-            keychain.create(&name, public, secret)?;
+            let (public, secret) = if paper {
+                let mut text = String::new();
+                io::stdin()
+                    .read_to_string(&mut text)
+                    .map_err(|error| CliError::StreamIoError { error })?;
+                paper::parse(text)?
+            } else {
+                (
+                    get_public_key(public, None as Option<&str>)?,
+                    get_secret_key(secret, None as Option<&str>)?,
+                )
+            };
+            keychain.import(&name, public, secret)?;
             println!("Imported keypair \"{}\"", name);
             Ok(())
         }
-        List => {
-            for keypair in keychain.iter()? {
-                println!("{}", keypair.name());
+        List { long } => {
+            let default_name = keychain.default_name()?;
+            for name in keychain.names()? {
+                if default_name.as_ref() == Some(&name) {
+                    println!("{} (default)", name);
+                } else {
+                    println!("{}", name);
+                }
+                if long {
+                    print_metadata(&keychain.metadata(&name)?);
+                }
+            }
+            Ok(())
+        }
+        Log { key, op, verify } => {
+            let audit_log = keychain.audit_log();
+            if verify {
+                let count = audit_log.verify()?;
+                println!("Verified {} audit log entries", count);
+                return Ok(());
+            }
+            let entries = audit_log
+                .entries()?
+                .into_iter()
+                .filter(|entry| key.is_none() || key.as_ref() == Some(&entry.key))
+                .filter(|entry| op.is_none() || op == Some(entry.op));
+            for entry in entries {
+                print_audit_entry(&entry);
             }
             Ok(())
         }
@@ -244,16 +2066,177 @@ fn keychain(args: KeychainArgs) -> Result<(), CliError> {
             println!("Renamed \"{}\" -> \"{}\"", old_name, new_name);
             Ok(())
         }
+        Split {
+            name,
+            shares,
+            threshold,
+            outdir,
+        } => {
+            let keypair = keychain.get(&name)?;
+            let key_shares = shamir::split(keypair.name(), keypair.secret(), shares, threshold)?;
+            keychain.record_use(AuditOp::Export, &keypair)?;
+            fs::create_dir_all(&outdir).map_err(|error| CliError::OutputFileIoError {
+                error,
+                path: outdir.clone(),
+            })?;
+            for share in key_shares {
+                let path = outdir.join(format!("{}.share{}", keypair.name(), share.index));
+                create_private_output(&path)?
+                    .write_all(share.to_text().as_bytes())
+                    .map_err(|error| CliError::OutputFileIoError {
+                        error,
+                        path: path.clone(),
+                    })?;
+                println!("Wrote share \"{}\"", path.to_string_lossy());
+            }
+            Ok(())
+        }
+        SetDefault { name, clear } => {
+            if clear {
+                keychain.clear_default()?;
+                println!("Cleared default keypair");
+            } else if let Some(name) = name {
+                keychain.set_default(&name)?;
+                println!("Default keypair is now \"{}\"", name);
+            }
+            Ok(())
+        }
+        SetMeta {
+            name,
+            clear_expires,
+            meta,
+        } => {
+            let mut metadata = keychain.metadata(&name)?;
+            if clear_expires {
+                metadata.expires = None;
+            }
+            apply_metadata_args(&mut metadata, meta);
+            keychain.set_metadata(&name, &metadata)?;
+            println!("Updated metadata for keypair \"{}\"", name);
+            Ok(())
+        }
     }
 }
 
+/// Reads a secret such as a passphrase. On a terminal the user is prompted
+/// with `prompt` and input is not echoed; if `confirm` is set it must be
+/// entered twice. Otherwise a single line is read from stdin.
+fn read_secret_input(prompt: &str, confirm: bool) -> Result<String, CliError> {
+    let stream_error = |error| CliError::StreamIoError { error };
+    if !io::stdin().is_terminal() {
+        let mut line = String::new();
+        io::stdin().read_line(&mut line).map_err(stream_error)?;
+        return Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string());
+    }
+    let input = rpassword::prompt_password(prompt).map_err(stream_error)?;
+    if confirm {
+        let again = rpassword::prompt_password("Confirm: ").map_err(stream_error)?;
+        if again != input {
+            return Err(DeriveError::PassphraseMismatch.into());
+        }
+    }
+    Ok(input)
+}
+
+/// Overwrites fields in `metadata` with any values provided on the command
+/// line.
+fn apply_metadata_args(metadata: &mut KeyMetadata, args: MetadataArgs) {
+    if args.comment.is_some() {
+        metadata.comment = args.comment;
+    }
+    if args.expires.is_some() {
+        metadata.expires = args.expires;
+    }
+    if args.owner.is_some() {
+        metadata.owner = args.owner;
+    }
+    if !args.tags.is_empty() {
+        metadata.tags = args.tags;
+    }
+}
+
+/// Prints a single audit log entry for `keychain log`.
+fn print_audit_entry(entry: &AuditEntry) {
+    let detail = entry
+        .detail
+        .as_ref()
+        .map(|detail| format!(" -> {}", detail))
+        .unwrap_or_default();
+    let uid = entry
+        .uid
+        .map(|uid| uid.to_string())
+        .unwrap_or_else(|| String::from("-"));
+    println!(
+        "{:>5} {} {:<13} {}{} {} uid={} host={}",
+        entry.seq,
+        entry.time.format("%Y-%m-%d %H:%M:%S UTC"),
+        entry.op,
+        entry.key,
+        detail,
+        &entry.fingerprint[..16.min(entry.fingerprint.len())],
+        uid,
+        entry.host,
+    );
+}
+
+/// Prints metadata fields for `keychain list --long`, indented beneath the
+/// keypair name.
+fn print_metadata(metadata: &KeyMetadata) {
+    if let Some(created) = metadata.created {
+        println!("    created: {}", created.format("%Y-%m-%d %H:%M:%S UTC"));
+    }
+    if let Some(expires) = metadata.expires {
+        let marker = if metadata.is_expired() {
+            " (expired)"
+        } else {
+            ""
+        };
+        println!("    expires: {}{}", expires, marker);
+    }
+    if let Some(owner) = metadata.owner.as_ref() {
+        println!("    owner: {}", owner);
+    }
+    if !metadata.tags.is_empty() {
+        println!("    tags: {}", metadata.tags.join(", "));
+    }
+    if let Some(comment) = metadata.comment.as_ref() {
+        println!("    comment: {}", comment);
+    }
+}
+
+// `setup_panic!` expands to code using `std::panic::PanicInfo`, which is
+// deprecated on newer compilers.
+#[allow(deprecated)]
 fn main() {
     setup_panic!();
+    let cmd = Cli::from_args().cmd;
+    // Exit status 1 from grep means nothing matched, and from diff that the
+    // files differ, so their errors use 2 as grep(1) and diff(1) do.
+    let error_status = match cmd {
+        Command::Diff(_) | Command::Grep(_) => 2,
+        _ => 1,
+    };
+    // Benchmarks only use throwaway keys; every other command handles secret
+    // keys or stream keys.
+    if !matches!(cmd, Command::Bench(_)) {
+        secret::disable_core_dumps();
+    }
 
-    let result = match Cli::from_args().cmd {
+    let result = match cmd {
+        #[cfg(unix)]
+        Command::Agent(args) => agent(args),
+        Command::Bench(args) => bench(args),
+        Command::Cat(args) => cat(args),
         Command::Decrypt(args) => decrypt(args),
+        Command::DecryptValues(args) => decrypt_values(args),
+        Command::Diff(args) => diff(args),
+        Command::EditValues(args) => edit_values(args),
         Command::Encrypt(args) => encrypt(args),
+        Command::EncryptValues(args) => encrypt_values(args),
+        Command::Exec(args) => exec(args),
         Command::Generate(args) => generate(args),
+        Command::Grep(args) => grep(args),
+        Command::Head(args) => head(args),
         Command::Keychain(args) => keychain(args),
     };
 
@@ -261,7 +2244,7 @@ fn main() {
         Ok(()) => ::std::process::exit(0),
         Err(e) => {
             eprintln!("Error: {}", e);
-            ::std::process::exit(1);
+            ::std::process::exit(error_status);
         }
     }
 }
@@ -0,0 +1,75 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Sidecar metadata stored alongside keychain keypairs.
+
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::error::MetadataError;
+use crate::keychain::write_replace;
+
+/// Descriptive information about a keychain keypair.
+///
+/// Metadata is optional - keypairs created before metadata was supported
+/// have none, and every field defaults to empty.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct KeyMetadata {
+    /// Time the keypair was created or imported.
+    pub created: Option<DateTime<Utc>>,
+
+    /// Free-text comment.
+    pub comment: Option<String>,
+
+    /// Email address of the key owner.
+    pub owner: Option<String>,
+
+    /// Date after which the key should no longer be used to encrypt.
+    pub expires: Option<NaiveDate>,
+
+    /// Arbitrary labels.
+    pub tags: Vec<String>,
+}
+
+impl KeyMetadata {
+    /// Create metadata with the creation time set to now.
+    pub fn now() -> KeyMetadata {
+        KeyMetadata {
+            created: Some(Utc::now()),
+            ..KeyMetadata::default()
+        }
+    }
+
+    /// Returns true if the key has an expiry date that is earlier than
+    /// today.
+    pub fn is_expired(&self) -> bool {
+        self.expires
+            .map(|expires| expires < Utc::now().date_naive())
+            .unwrap_or(false)
+    }
+
+    /// Load metadata from `path`, returning default metadata if the file does
+    /// not exist.
+    pub fn load(path: impl AsRef<Path>) -> Result<KeyMetadata, MetadataError> {
+        if !path.as_ref().is_file() {
+            return Ok(KeyMetadata::default());
+        }
+        let contents = fs::read_to_string(path).map_err(MetadataError::Io)?;
+        toml::from_str(&contents).map_err(MetadataError::Parse)
+    }
+
+    /// Write metadata to `path`, replacing any existing file.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), MetadataError> {
+        let contents = toml::to_string(self).map_err(MetadataError::Serialize)?;
+        write_replace(path, contents).map_err(MetadataError::Io)
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Human-typable paper backups of secret keys.
+//!
+//! The raw secret key is base32-encoded and printed as numbered lines of
+//! four-character groups. Each line ends with a two-character checksum
+//! covering the line number and its contents, so a typo when entering a
+//! backup by hand is pinned to the line it occurs on. A final line carries
+//! the start of the public key fingerprint, which is checked after the
+//! secret key is reconstructed.
+
+use qrcode::render::{svg, unicode};
+use qrcode::QrCode;
+use saltlick::{PublicKey, SecretKey};
+
+use crate::error::PaperError;
+use crate::keychain::{self, fingerprint, public_from_secret};
+use crate::secret::SecretBytes;
+
+const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+const GROUP_LEN: usize = 4;
+const GROUPS_PER_LINE: usize = 4;
+const LINE_LEN: usize = GROUP_LEN * GROUPS_PER_LINE;
+const FINGERPRINT_LEN: usize = 8;
+
+/// Prefix identifying the compact form of a backup, as encoded in QR codes.
+const QR_PREFIX: &str = "SALTLICK-SK1:";
+
+/// Render `secret` as a paper backup for the keypair named `name`.
+pub fn render_text(name: impl AsRef<str>, secret: &SecretKey) -> String {
+    let encoded = base32_encode(&keychain::secret_key_bytes(secret));
+    let public = public_from_secret(secret);
+    let mut text = String::new();
+    text.push_str("saltlick secret key backup\n");
+    text.push_str(&format!("name: {}\n", name.as_ref()));
+    text.push_str(&format!("fingerprint: {}\n\n", fingerprint(&public)));
+    for (index, chunk) in encoded.as_bytes().chunks(LINE_LEN).enumerate() {
+        let line = String::from_utf8_lossy(chunk);
+        let groups = chunk
+            .chunks(GROUP_LEN)
+            .map(|group| String::from_utf8_lossy(group).into_owned())
+            .collect::<Vec<_>>()
+            .join(" ");
+        text.push_str(&format!(
+            "{:>2}: {:<19}  {}\n",
+            index + 1,
+            groups,
+            line_checksum(index + 1, &line)
+        ));
+    }
+    text.push_str(&format!(
+        " F: {}\n",
+        &fingerprint(&public)[..FINGERPRINT_LEN].to_uppercase()
+    ));
+    text
+}
+
+/// Render `secret` as a QR code drawn with Unicode block characters, for
+/// display on a terminal.
+pub fn render_qr_terminal(secret: &SecretKey) -> String {
+    qr_code(secret)
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build()
+}
+
+/// Render `secret` as a QR code in SVG format.
+pub fn render_qr_svg(secret: &SecretKey) -> String {
+    qr_code(secret)
+        .render::<svg::Color>()
+        .min_dimensions(256, 256)
+        .build()
+}
+
+fn qr_code(secret: &SecretKey) -> QrCode {
+    let contents = format!(
+        "{}{}",
+        QR_PREFIX,
+        base32_encode(&keychain::secret_key_bytes(secret))
+    );
+    QrCode::new(contents.as_bytes()).expect("secret key always fits in a QR code")
+}
+
+/// Parse a paper backup, returning the reconstructed keypair.
+///
+/// Accepts either the text produced by `render_text` (header lines are
+/// ignored) or the compact form encoded in the QR code. Commonly confused
+/// characters are corrected, and checksums are verified line by line so
+/// that errors can be reported with the line they occur on.
+pub fn parse(text: impl AsRef<str>) -> Result<(PublicKey, SecretKey), PaperError> {
+    let mut encoded = String::new();
+    let mut expected_fingerprint = None;
+    let mut expected_line = 1;
+    for raw_line in text.as_ref().lines() {
+        let raw_line = raw_line.trim();
+        if let Some(compact) = raw_line.strip_prefix(QR_PREFIX) {
+            encoded = normalize(compact);
+            break;
+        }
+        let (label, rest) = match raw_line.split_once(':') {
+            Some(parts) => parts,
+            None => continue,
+        };
+        let label = label.trim();
+        if label.eq_ignore_ascii_case("F") {
+            expected_fingerprint = Some(rest.trim().to_lowercase());
+            continue;
+        }
+        let number = match label.parse::<usize>() {
+            Ok(number) => number,
+            // Header lines such as "name:" are informational only.
+            Err(_) => continue,
+        };
+        if number != expected_line {
+            return Err(PaperError::MissingLine {
+                line: expected_line,
+            });
+        }
+        let mut fields = rest.split_whitespace().collect::<Vec<_>>();
+        let checksum = fields.pop().map(normalize).unwrap_or_default();
+        let line = normalize(&fields.concat());
+        if line.is_empty() || checksum != line_checksum(number, &line) {
+            return Err(PaperError::ChecksumMismatch { line: number });
+        }
+        encoded.push_str(&line);
+        expected_line += 1;
+    }
+
+    let bytes = SecretBytes::from_vec(base32_decode(&encoded).ok_or(PaperError::InvalidData)?);
+    let secret = SecretKey::from_raw_curve25519(&bytes).map_err(|_| PaperError::InvalidData)?;
+    let public = public_from_secret(&secret);
+    if let Some(expected) = expected_fingerprint {
+        if !fingerprint(&public).starts_with(&expected) {
+            return Err(PaperError::FingerprintMismatch);
+        }
+    }
+    Ok((public, secret))
+}
+
+/// Uppercases `s`, strips whitespace and maps characters outside the base32
+/// alphabet that are easily confused with characters inside it.
+fn normalize(s: &str) -> String {
+    s.chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| match c.to_ascii_uppercase() {
+            '0' => 'O',
+            '1' => 'I',
+            '8' => 'B',
+            c => c,
+        })
+        .collect()
+}
+
+/// Two base32 characters encoding 10 bits of a CRC-16 over the line number
+/// and contents.
+fn line_checksum(number: usize, line: &str) -> String {
+    let mut data = vec![number as u8];
+    data.extend_from_slice(line.as_bytes());
+    let crc = crc16(&data);
+    let high = ALPHABET[usize::from((crc >> 5) & 0x1f)] as char;
+    let low = ALPHABET[usize::from(crc & 0x1f)] as char;
+    format!("{}{}", high, low)
+}
+
+/// CRC-16/CCITT-FALSE.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for &byte in data {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// RFC 4648 base32 encoding without padding.
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+/// RFC 4648 base32 decoding without padding. Returns `None` on any
+/// character outside the alphabet.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let value = ALPHABET.iter().position(|&a| a == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base32_decode, base32_encode, parse, render_text};
+    use crate::error::PaperError;
+
+    #[test]
+    fn base32_roundtrip_test() {
+        for len in 0..40 {
+            let bytes = (0..len).map(|i| (i * 37 + 11) as u8).collect::<Vec<_>>();
+            assert_eq!(base32_decode(&base32_encode(&bytes)).unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn paper_roundtrip_test() {
+        let (public, secret) = saltlick::gen_keypair();
+        let text = render_text("paper", &secret);
+        let (parsed_public, parsed_secret) = parse(&text).unwrap();
+        assert_eq!(parsed_public, public);
+        assert_eq!(parsed_secret, secret);
+
+        // Lowercase input with collapsed spacing is accepted.
+        let (_, parsed_secret) = parse(text.to_lowercase().replace("  ", " ")).unwrap();
+        assert_eq!(parsed_secret, secret);
+    }
+
+    #[test]
+    fn paper_typo_is_located_test() {
+        let (_, secret) = saltlick::gen_keypair();
+        let text = render_text("paper", &secret);
+        let typo = text
+            .lines()
+            .map(|line| {
+                if line.starts_with(" 3:") {
+                    let mut chars = line.chars().collect::<Vec<_>>();
+                    chars[4] = if chars[4] == 'A' { 'B' } else { 'A' };
+                    chars.into_iter().collect()
+                } else {
+                    line.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        match parse(typo) {
+            Err(PaperError::ChecksumMismatch { line: 3 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let missing = text
+            .lines()
+            .filter(|line| !line.starts_with(" 2:"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        match parse(missing) {
+            Err(PaperError::MissingLine { line: 2 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
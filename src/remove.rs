@@ -0,0 +1,227 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Removing the input of `encrypt --remove-source` once its output is known
+//! to be good.
+//!
+//! The input is only removed after the output has been synced to disk and
+//! decrypted again with its stream key, comparing every byte with the input.
+//! Inputs that are symbolic links or have other hard links are refused, as
+//! removing them would leave the plaintext behind.
+//!
+//! Overwriting the input before removing it only helps on filesystems that
+//! write in place. Copy-on-write and log-structured filesystems, snapshots
+//! and SSD wear levelling can all keep old copies of the data.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::Path;
+
+use sodiumoxide::randombytes::randombytes_into;
+
+use crate::error::RemoveError;
+use crate::stream::{StreamDecrypter, StreamHeader, StreamKey};
+
+/// Size of the buffer of random data written by each overwrite pass.
+const SHRED_BUFFER_SIZE: usize = 1 << 20;
+
+/// Checks that `path` is a regular file that removing would really remove:
+/// not a symbolic link, and with no other hard links.
+pub fn check_removable(path: &Path) -> Result<(), RemoveError> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.file_type().is_symlink() {
+        return Err(RemoveError::Symlink);
+    }
+    if !metadata.is_file() {
+        return Err(RemoveError::NotAFile);
+    }
+    #[cfg(unix)]
+    if metadata.nlink() > 1 {
+        return Err(RemoveError::HardLinked {
+            links: metadata.nlink(),
+        });
+    }
+    Ok(())
+}
+
+/// Checks that `output` holds the stream `header` introduces, and that it
+/// decrypts with `key` to exactly the plaintext read from `input`.
+pub fn verify(
+    output: &Path,
+    header: &StreamHeader,
+    key: &StreamKey,
+    mut input: impl Read,
+    buffer_size: usize,
+) -> io::Result<()> {
+    let mut output = BufReader::with_capacity(buffer_size, File::open(output)?);
+    if StreamHeader::read_from(&mut output)?.raw != header.raw {
+        return Err(mismatch("the output has a different header"));
+    }
+
+    let mut decrypter = StreamDecrypter::new(key, &mut output)?;
+    let mut decrypted = vec![0; buffer_size];
+    let mut expected = vec![0; buffer_size];
+    loop {
+        let n = decrypter.read(&mut decrypted)?;
+        if n == 0 {
+            break;
+        }
+        input
+            .read_exact(&mut expected[..n])
+            .map_err(|_| mismatch("the output is longer than the input"))?;
+        if decrypted[..n] != expected[..n] {
+            return Err(mismatch("the output does not decrypt to the input"));
+        }
+    }
+    if input.read(&mut [0])? != 0 {
+        return Err(mismatch("the output is shorter than the input"));
+    }
+    if !output.fill_buf()?.is_empty() {
+        return Err(mismatch("the output has data after the end of the stream"));
+    }
+    Ok(())
+}
+
+fn mismatch(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Syncs the directory holding `path`, so that a newly created file there
+/// survives a crash. Does nothing where directories cannot be synced.
+pub fn sync_parent(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Removes the file at `path`, first overwriting it with random data
+/// `passes` times and syncing after each pass.
+pub fn remove(path: &Path, passes: u32) -> io::Result<()> {
+    if passes > 0 {
+        let mut options = OpenOptions::new();
+        options.write(true);
+        #[cfg(unix)]
+        options.custom_flags(libc::O_NOFOLLOW);
+        let mut file = options.open(path)?;
+        let len = file.metadata()?.len();
+        let mut buffer = vec![0; SHRED_BUFFER_SIZE.min(len as usize)];
+        for _ in 0..passes {
+            file.seek(SeekFrom::Start(0))?;
+            let mut remaining = len;
+            while remaining > 0 {
+                let n = remaining.min(buffer.len() as u64) as usize;
+                randombytes_into(&mut buffer[..n]);
+                file.write_all(&buffer[..n])?;
+                remaining -= n as u64;
+            }
+            file.sync_all()?;
+        }
+        file.set_len(0)?;
+        file.sync_all()?;
+    }
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+
+    use super::{check_removable, remove, verify};
+    use crate::error::RemoveError;
+    use crate::stream::{StreamEncrypter, StreamHeader, StreamKey};
+
+    #[test]
+    fn check_removable_test() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let file = temp.path().join("file");
+        fs::write(&file, b"plaintext").unwrap();
+        assert!(check_removable(&file).is_ok());
+        assert!(matches!(
+            check_removable(temp.path()),
+            Err(RemoveError::NotAFile)
+        ));
+        assert!(matches!(
+            check_removable(&temp.path().join("missing")),
+            Err(RemoveError::Io(_))
+        ));
+
+        #[cfg(unix)]
+        {
+            let link = temp.path().join("link");
+            std::os::unix::fs::symlink(&file, &link).unwrap();
+            assert!(matches!(check_removable(&link), Err(RemoveError::Symlink)));
+
+            let hard_link = temp.path().join("hard-link");
+            fs::hard_link(&file, &hard_link).unwrap();
+            assert!(matches!(
+                check_removable(&file),
+                Err(RemoveError::HardLinked { links: 2 })
+            ));
+            fs::remove_file(&hard_link).unwrap();
+            assert!(check_removable(&file).is_ok());
+        }
+    }
+
+    #[test]
+    fn verify_test() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let output = temp.path().join("output");
+        let plaintext = (0..5000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        let (public, _) = saltlick::gen_keypair();
+        let key = StreamKey::generate();
+        let header = StreamHeader::seal(&public, &key);
+        let mut encrypter = StreamEncrypter::new(&key, header.raw.clone(), 1024).unwrap();
+        encrypter.write_all(&plaintext).unwrap();
+        let ciphertext = encrypter.finish().unwrap();
+        fs::write(&output, &ciphertext).unwrap();
+        verify(&output, &header, &key, &plaintext[..], 100).unwrap();
+
+        // A different input, a damaged or truncated output, and trailing
+        // data all fail.
+        assert!(verify(&output, &header, &key, &plaintext[..4999], 100).is_err());
+        let mut damaged = ciphertext.clone();
+        damaged[2000] ^= 1;
+        fs::write(&output, &damaged).unwrap();
+        assert!(verify(&output, &header, &key, &plaintext[..], 100).is_err());
+        fs::write(&output, &ciphertext[..ciphertext.len() - 1]).unwrap();
+        assert!(verify(&output, &header, &key, &plaintext[..], 100).is_err());
+        let mut extended = ciphertext.clone();
+        extended.push(0);
+        fs::write(&output, &extended).unwrap();
+        assert!(verify(&output, &header, &key, &plaintext[..], 100).is_err());
+        let other = StreamHeader::seal(&public, &key);
+        fs::write(&output, &ciphertext).unwrap();
+        assert!(verify(&output, &other, &key, &plaintext[..], 100).is_err());
+    }
+
+    #[test]
+    fn remove_test() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let file = temp.path().join("file");
+        for &passes in &[0, 3] {
+            fs::write(&file, vec![7; 3000]).unwrap();
+            remove(&file, passes).unwrap();
+            assert!(!file.exists());
+        }
+        fs::write(&file, b"").unwrap();
+        remove(&file, 1).unwrap();
+        assert!(!file.exists());
+    }
+}
@@ -0,0 +1,586 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Journals that let an interrupted `encrypt --resume` or
+//! `decrypt --resume` continue where it stopped.
+//!
+//! The journal is a small JSON file next to the output, removed once the
+//! output is complete. It identifies the input by length and modification
+//! time, so a journal for a different input is never resumed.
+//!
+//! Encrypting records the stream header, which holds the stream key sealed
+//! to the recipient. On resume, the key is opened with the recipient's
+//! secret key from the agent or keychain, as when decrypting, every whole
+//! block already in the output is authenticated with it, and encryption
+//! continues from the first block that is missing or damaged.
+//!
+//! Continuing a stream reuses its key and nonces, which is only safe if the
+//! input is the same as before. Encrypting therefore hashes the input
+//! `CHECKPOINT_INTERVAL` bytes ahead of what it encrypts, recording a hash
+//! chain in the journal before any of the hashed input is used. On resume,
+//! the input is hashed again up to the recorded length, and a new stream is
+//! started if it has changed.
+//!
+//! Decrypting cannot authenticate its output, so it records checkpoints
+//! instead. Every `CHECKPOINT_INTERVAL` bytes the output is synced to disk
+//! and the journal updated with its length and a hash chain over it. On
+//! resume, the output up to the last checkpoint is hashed again and
+//! decryption continues from there if it matches.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::hash::sha256;
+
+use crate::audit::hex;
+
+/// Extension added to the output file name to name its journal.
+pub const JOURNAL_EXTENSION: &str = "saltlick-journal";
+
+/// Plaintext written between decryption checkpoints.
+pub const CHECKPOINT_INTERVAL: u64 = 64 * 1024 * 1024;
+
+const JOURNAL_VERSION: u32 = 1;
+
+/// Returns the path of the journal for `output`.
+pub fn journal_path(output: impl AsRef<Path>) -> PathBuf {
+    let mut path = output.as_ref().as_os_str().to_owned();
+    path.push(format!(".{}", JOURNAL_EXTENSION));
+    PathBuf::from(path)
+}
+
+/// Operation a journal records.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalOp {
+    Encrypt,
+    Decrypt,
+}
+
+/// Identifies the contents of an input file without reading it.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct InputIdentity {
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+}
+
+impl InputIdentity {
+    /// Returns the identity of the file at `path`.
+    pub fn of(path: impl AsRef<Path>) -> io::Result<InputIdentity> {
+        let metadata = fs::metadata(path)?;
+        Ok(InputIdentity {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+/// Output length and hash chain at a decryption checkpoint.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Checkpoint {
+    pub len: u64,
+    pub hash: String,
+}
+
+/// Progress of an encryption or decryption to a file.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Journal {
+    pub version: u32,
+    pub operation: JournalOp,
+    pub input: InputIdentity,
+
+    /// Block size of the stream being written, when encrypting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_size: Option<usize>,
+
+    /// Header of the stream being written, in hex, when encrypting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header: Option<String>,
+
+    /// Last checkpoint: the input hashed so far when encrypting, or the
+    /// output synced so far when decrypting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checkpoint: Option<Checkpoint>,
+}
+
+impl Journal {
+    /// Creates an empty journal for `operation` on `input`.
+    pub fn new(operation: JournalOp, input: InputIdentity) -> Journal {
+        Journal {
+            version: JOURNAL_VERSION,
+            operation,
+            input,
+            block_size: None,
+            header: None,
+            checkpoint: None,
+        }
+    }
+
+    /// Reads the journal at `path`, returning `None` if there is none.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Option<Journal>> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        let journal: Journal = serde_json::from_slice(&contents)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        if journal.version != JOURNAL_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unsupported journal version",
+            ));
+        }
+        Ok(Some(journal))
+    }
+
+    /// Writes the journal to `path`, readable only by the current user, by
+    /// replacing it so that it is never seen partly written.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut temp_name = path.as_os_str().to_owned();
+        temp_name.push(".tmp");
+        let temp_path = PathBuf::from(temp_name);
+        // A temporary file left by an interrupted save may have been created
+        // with other permissions, which opening it would keep.
+        match fs::remove_file(&temp_path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let contents = serde_json::to_vec_pretty(self).expect("journal always serializes");
+        let mut file = options.open(&temp_path)?;
+        file.write_all(&contents)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)
+    }
+}
+
+/// Writer for decrypted output that records a checkpoint in its journal
+/// every `CHECKPOINT_INTERVAL` bytes.
+pub struct JournaledWriter {
+    file: BufWriter<File>,
+    journal: Journal,
+    path: PathBuf,
+    /// Hash chain up to the last checkpoint.
+    chain: [u8; sha256::DIGESTBYTES],
+    /// Hash of the output since the last checkpoint.
+    chunk: sha256::State,
+    len: u64,
+}
+
+impl JournaledWriter {
+    /// Starts writing `file`, which must be empty, saving `journal` to
+    /// `path` first.
+    pub fn create(
+        file: File,
+        journal: Journal,
+        path: PathBuf,
+        buffer_size: usize,
+    ) -> io::Result<JournaledWriter> {
+        let mut journal = journal;
+        journal.checkpoint = None;
+        journal.save(&path)?;
+        Ok(JournaledWriter {
+            file: BufWriter::with_capacity(buffer_size, file),
+            journal,
+            path,
+            chain: [0; sha256::DIGESTBYTES],
+            chunk: sha256::State::new(),
+            len: 0,
+        })
+    }
+
+    /// Continues writing `file` after the last checkpoint in `journal`,
+    /// which is kept at `path`. Returns `None` if `file` no longer holds the
+    /// output up to the checkpoint.
+    pub fn resume(
+        mut file: File,
+        journal: Journal,
+        path: PathBuf,
+        buffer_size: usize,
+    ) -> io::Result<Option<JournaledWriter>> {
+        let checkpoint = last_checkpoint(&journal);
+        let chain = match verify_prefix(&mut file, &checkpoint, buffer_size)? {
+            Some(chain) => chain,
+            None => return Ok(None),
+        };
+        file.set_len(checkpoint.len)?;
+        file.seek(SeekFrom::Start(checkpoint.len))?;
+        Ok(Some(JournaledWriter {
+            file: BufWriter::with_capacity(buffer_size, file),
+            journal,
+            path,
+            chain,
+            chunk: sha256::State::new(),
+            len: checkpoint.len,
+        }))
+    }
+
+    /// Returns the length of the output written so far.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns true if nothing has been written.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn checkpoint(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        let chunk = mem::replace(&mut self.chunk, sha256::State::new());
+        self.chain = next_link(&self.chain, chunk);
+        self.journal.checkpoint = Some(Checkpoint {
+            len: self.len,
+            hash: hex(&self.chain),
+        });
+        self.journal.save(&self.path)
+    }
+
+    /// Syncs the output to disk and removes the journal.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+        fs::remove_file(&self.path)
+    }
+}
+
+impl Write for JournaledWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let room = CHECKPOINT_INTERVAL - self.len % CHECKPOINT_INTERVAL;
+        let n = self.file.write(&buf[..buf.len().min(room as usize)])?;
+        self.chunk.update(&buf[..n]);
+        self.len += n as u64;
+        if n as u64 == room {
+            self.checkpoint()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Reader for the input of an encryption that hashes the input ahead of
+/// what it returns, saving the hash chain in its journal before returning
+/// any of the input it covers.
+pub struct JournaledReader {
+    file: BufReader<File>,
+    journal: Journal,
+    path: PathBuf,
+    /// Hash chain over the input hashed so far.
+    chain: [u8; sha256::DIGESTBYTES],
+    /// Length of the input hashed so far.
+    hashed: u64,
+    /// Length of the input returned so far.
+    position: u64,
+}
+
+impl JournaledReader {
+    /// Starts reading `file` from the beginning, saving `journal` to `path`
+    /// first.
+    pub fn create(
+        file: File,
+        journal: Journal,
+        path: PathBuf,
+        buffer_size: usize,
+    ) -> io::Result<JournaledReader> {
+        let mut journal = journal;
+        journal.checkpoint = None;
+        journal.save(&path)?;
+        Ok(JournaledReader {
+            file: BufReader::with_capacity(buffer_size, file),
+            journal,
+            path,
+            chain: [0; sha256::DIGESTBYTES],
+            hashed: 0,
+            position: 0,
+        })
+    }
+
+    /// Continues reading `file` at `position`, with `journal` kept at
+    /// `path`. Returns `None` if `file` no longer starts with the input
+    /// hashed in `journal`, or `position` is past it.
+    pub fn resume(
+        mut file: File,
+        journal: Journal,
+        path: PathBuf,
+        buffer_size: usize,
+        position: u64,
+    ) -> io::Result<Option<JournaledReader>> {
+        let checkpoint = last_checkpoint(&journal);
+        if position > checkpoint.len {
+            return Ok(None);
+        }
+        let chain = match verify_prefix(&mut file, &checkpoint, buffer_size)? {
+            Some(chain) => chain,
+            None => return Ok(None),
+        };
+        file.seek(SeekFrom::Start(position))?;
+        Ok(Some(JournaledReader {
+            file: BufReader::with_capacity(buffer_size, file),
+            journal,
+            path,
+            chain,
+            hashed: checkpoint.len,
+            position,
+        }))
+    }
+
+    /// Hashes up to `CHECKPOINT_INTERVAL` bytes of input after what has
+    /// been hashed so far, and saves the journal.
+    fn hash_ahead(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.hashed))?;
+        let mut chunk = sha256::State::new();
+        let mut len = 0;
+        let mut input = (&mut self.file).take(CHECKPOINT_INTERVAL);
+        loop {
+            let buffer = input.fill_buf()?;
+            if buffer.is_empty() {
+                break;
+            }
+            chunk.update(buffer);
+            let n = buffer.len();
+            len += n as u64;
+            input.consume(n);
+        }
+        self.file.seek(SeekFrom::Start(self.position))?;
+        if len == 0 {
+            return Ok(());
+        }
+        self.chain = next_link(&self.chain, chunk);
+        self.hashed += len;
+        self.journal.checkpoint = Some(Checkpoint {
+            len: self.hashed,
+            hash: hex(&self.chain),
+        });
+        self.journal.save(&self.path)
+    }
+}
+
+impl Read for JournaledReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.hashed {
+            self.hash_ahead()?;
+        }
+        let available = buf.len().min((self.hashed - self.position) as usize);
+        let n = self.file.read(&mut buf[..available])?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+/// Returns the last checkpoint in `journal`, or the start of the file if
+/// there is none.
+fn last_checkpoint(journal: &Journal) -> Checkpoint {
+    journal.checkpoint.clone().unwrap_or(Checkpoint {
+        len: 0,
+        hash: hex(&[0; sha256::DIGESTBYTES]),
+    })
+}
+
+/// Hashes the start of `file` up to `checkpoint`, returning the hash chain
+/// if it matches the one recorded, or `None` if it does not or `file` is
+/// too short.
+fn verify_prefix(
+    file: &mut File,
+    checkpoint: &Checkpoint,
+    buffer_size: usize,
+) -> io::Result<Option<[u8; sha256::DIGESTBYTES]>> {
+    if file.metadata()?.len() < checkpoint.len {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(0))?;
+    let mut chain = [0; sha256::DIGESTBYTES];
+    let mut buffer = vec![0u8; buffer_size];
+    let mut remaining = checkpoint.len;
+    while remaining > 0 {
+        let mut chunk = sha256::State::new();
+        let mut chunk_remaining = remaining.min(CHECKPOINT_INTERVAL);
+        remaining -= chunk_remaining;
+        while chunk_remaining > 0 {
+            let n = buffer.len().min(chunk_remaining as usize);
+            file.read_exact(&mut buffer[..n])?;
+            chunk.update(&buffer[..n]);
+            chunk_remaining -= n as u64;
+        }
+        chain = next_link(&chain, chunk);
+    }
+    Ok(Some(chain).filter(|chain| hex(chain) == checkpoint.hash))
+}
+
+/// Extends the hash chain `chain` with the hash of the next chunk.
+fn next_link(chain: &[u8; sha256::DIGESTBYTES], chunk: sha256::State) -> [u8; sha256::DIGESTBYTES] {
+    let mut link = sha256::State::new();
+    link.update(chain);
+    link.update(&chunk.finalize()[..]);
+    link.finalize().0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        journal_path, InputIdentity, Journal, JournalOp, JournaledReader, JournaledWriter,
+        CHECKPOINT_INTERVAL,
+    };
+
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{Read, Write};
+
+    #[test]
+    fn journal_round_trip_test() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let input = temp.path().join("input");
+        fs::write(&input, b"plaintext").unwrap();
+        let path = journal_path(temp.path().join("output.slk"));
+        assert_eq!(path, temp.path().join("output.slk.saltlick-journal"));
+        assert!(Journal::load(&path).unwrap().is_none());
+
+        let mut journal = Journal::new(JournalOp::Encrypt, InputIdentity::of(&input).unwrap());
+        journal.block_size = Some(1024);
+        journal.header = Some("00ff".to_string());
+        // A stale temporary file from an interrupted save is replaced, not
+        // reused with its permissions.
+        let temp_path = temp.path().join("output.slk.saltlick-journal.tmp");
+        fs::write(&temp_path, b"stale").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&temp_path, fs::Permissions::from_mode(0o644)).unwrap();
+        }
+        journal.save(&path).unwrap();
+        assert!(!temp_path.exists());
+        assert_eq!(Journal::load(&path).unwrap(), Some(journal));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::write(&path, b"{").unwrap();
+        assert!(Journal::load(&path).is_err());
+    }
+
+    #[test]
+    fn journaled_writer_test() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let output = temp.path().join("output");
+        let path = journal_path(&output);
+        let data = (0..CHECKPOINT_INTERVAL * 2 + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let journal = Journal::new(
+            JournalOp::Decrypt,
+            InputIdentity {
+                len: 1,
+                modified: None,
+            },
+        );
+
+        // Write past the second checkpoint, then stop as if interrupted.
+        let file = fs::File::create(&output).unwrap();
+        let mut writer = JournaledWriter::create(file, journal, path.clone(), 4096).unwrap();
+        writer
+            .write_all(&data[..CHECKPOINT_INTERVAL as usize * 2 + 10])
+            .unwrap();
+        drop(writer);
+        let journal = Journal::load(&path).unwrap().unwrap();
+        assert_eq!(
+            journal.checkpoint.as_ref().unwrap().len,
+            CHECKPOINT_INTERVAL * 2
+        );
+
+        // Resuming continues from the checkpoint.
+        let open = || {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&output)
+                .unwrap()
+        };
+        let mut writer = JournaledWriter::resume(open(), journal.clone(), path.clone(), 4096)
+            .unwrap()
+            .unwrap();
+        assert_eq!(writer.len(), CHECKPOINT_INTERVAL * 2);
+        writer
+            .write_all(&data[CHECKPOINT_INTERVAL as usize * 2..])
+            .unwrap();
+        writer.finish().unwrap();
+        assert!(fs::read(&output).unwrap() == data);
+        assert!(!path.exists());
+
+        // Output that changed before the checkpoint is not resumed.
+        let mut damaged = data.clone();
+        damaged[100] ^= 1;
+        fs::write(&output, &damaged).unwrap();
+        assert!(JournaledWriter::resume(open(), journal, path, 4096)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn journaled_reader_test() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let input = temp.path().join("input");
+        let path = journal_path(temp.path().join("output"));
+        let data = (0..CHECKPOINT_INTERVAL + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        fs::write(&input, &data).unwrap();
+        let journal = Journal::new(JournalOp::Encrypt, InputIdentity::of(&input).unwrap());
+
+        // The input is hashed into the journal before it is returned.
+        let file = File::open(&input).unwrap();
+        let mut reader = JournaledReader::create(file, journal, path.clone(), 4096).unwrap();
+        let mut start = [0; 100];
+        reader.read_exact(&mut start).unwrap();
+        assert_eq!(start[..], data[..100]);
+        drop(reader);
+        let journal = Journal::load(&path).unwrap().unwrap();
+        assert_eq!(
+            journal.checkpoint.as_ref().unwrap().len,
+            CHECKPOINT_INTERVAL
+        );
+
+        // Resuming checks the input and continues from the given position.
+        let resume = |journal: &Journal, position| {
+            let file = File::open(&input).unwrap();
+            JournaledReader::resume(file, journal.clone(), path.clone(), 4096, position).unwrap()
+        };
+        let mut rest = Vec::new();
+        resume(&journal, 50)
+            .unwrap()
+            .read_to_end(&mut rest)
+            .unwrap();
+        assert!(rest[..] == data[50..]);
+        let finished = Journal::load(&path).unwrap().unwrap();
+        assert_eq!(finished.checkpoint.unwrap().len, data.len() as u64);
+
+        // A position past the hashed input, or input that has changed, is
+        // not resumed.
+        assert!(resume(&journal, CHECKPOINT_INTERVAL + 1).is_none());
+        let mut changed = data.clone();
+        changed[CHECKPOINT_INTERVAL as usize - 1] ^= 1;
+        fs::write(&input, &changed).unwrap();
+        assert!(resume(&journal, 50).is_none());
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Handling of secret key material in memory.
+
+use std::fmt;
+use std::ops::Deref;
+use std::process;
+#[cfg(unix)]
+use std::sync::OnceLock;
+
+use sodiumoxide::utils::{memzero, mlock, munlock};
+
+/// Secret bytes held in memory that is locked against swapping where
+/// possible, and zeroed when dropped.
+pub struct SecretBytes(Box<[u8]>);
+
+impl SecretBytes {
+    /// Copies `bytes` into a new locked buffer. The caller remains
+    /// responsible for zeroing `bytes`.
+    pub fn new(bytes: &[u8]) -> SecretBytes {
+        let mut buffer = vec![0u8; bytes.len()].into_boxed_slice();
+        // Locking can fail if the process exceeds its locked memory limit.
+        // The bytes are still zeroed on drop, so carry on.
+        let _ = mlock(&mut buffer);
+        buffer.copy_from_slice(bytes);
+        SecretBytes(buffer)
+    }
+
+    /// Moves `bytes` into a new locked buffer, zeroing the original.
+    pub fn from_vec(mut bytes: Vec<u8>) -> SecretBytes {
+        let secret = SecretBytes::new(&bytes);
+        memzero(&mut bytes);
+        secret
+    }
+}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretBytes({} bytes)", self.0.len())
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        // munlock zeroes the memory before unlocking it.
+        if munlock(&mut self.0).is_err() {
+            memzero(&mut self.0);
+        }
+    }
+}
+
+/// Core dump limit of the process before `disable_core_dumps` lowered it.
+#[cfg(unix)]
+static CORE_LIMIT: OnceLock<libc::rlimit> = OnceLock::new();
+
+/// Stops the process from writing a core dump, which could contain secret
+/// keys, if it crashes. On Linux this also stops other processes of the
+/// same user from attaching to it with a debugger.
+///
+/// Only the soft limit is lowered, and `restore_core_dumps` puts it back for
+/// child processes, so programs run by saltlick keep their own limits.
+/// Failures are ignored, as there is nothing more useful to do about them.
+pub fn disable_core_dumps() {
+    #[cfg(unix)]
+    {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: getrlimit only writes to the limit passed to it, and
+        // setrlimit only reads it.
+        unsafe {
+            if libc::getrlimit(libc::RLIMIT_CORE, &mut limit) == 0 {
+                let _ = CORE_LIMIT.set(limit);
+                limit.rlim_cur = 0;
+                libc::setrlimit(libc::RLIMIT_CORE, &limit);
+            }
+        }
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        // SAFETY: PR_SET_DUMPABLE takes no pointer arguments.
+        unsafe {
+            libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0);
+        }
+    }
+}
+
+/// Makes `command` start its program with the core dump limit the process
+/// had before `disable_core_dumps` was called. The kernel already resets
+/// whether a process is dumpable when it executes a new program.
+pub fn restore_core_dumps(command: &mut process::Command) -> &mut process::Command {
+    #[cfg(unix)]
+    if let Some(&limit) = CORE_LIMIT.get() {
+        use std::os::unix::process::CommandExt;
+        // SAFETY: setrlimit is async-signal-safe and only reads the limit
+        // passed to it, which is copied into the closure.
+        unsafe {
+            command.pre_exec(move || {
+                libc::setrlimit(libc::RLIMIT_CORE, &limit);
+                Ok(())
+            });
+        }
+    }
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SecretBytes;
+
+    #[test]
+    fn secret_bytes_test() {
+        let secret = SecretBytes::from_vec(vec![1, 2, 3]);
+        assert_eq!(&*secret, &[1, 2, 3]);
+        assert_eq!(format!("{:?}", secret), "SecretBytes(3 bytes)");
+    }
+}
@@ -0,0 +1,300 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Shamir secret sharing of keychain secret keys.
+//!
+//! Each byte of the secret is shared independently over GF(2^8), using a
+//! random polynomial of degree `threshold - 1` whose constant term is the
+//! secret byte. Share `x` holds the polynomial evaluated at `x`, so any
+//! `threshold` shares recover the secret by Lagrange interpolation at zero,
+//! while fewer reveal nothing about it.
+
+use std::collections::HashSet;
+use std::fmt::Write as _;
+
+use saltlick::{PublicKey, SecretKey};
+use sodiumoxide::randombytes::randombytes;
+
+use crate::audit::{hex, hex_decode};
+use crate::error::ShareError;
+use crate::keychain::{fingerprint, public_from_secret, secret_key_bytes};
+use crate::secret::SecretBytes;
+
+const SHARE_HEADER: &str = "saltlick key share";
+
+/// One share of a split secret key, along with the information needed to
+/// recombine it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeyShare {
+    /// Name of the keypair the share was taken from.
+    pub name: String,
+
+    /// Evaluation point of this share, from 1 to `shares`.
+    pub index: u8,
+
+    /// Number of shares needed to recover the key.
+    pub threshold: u8,
+
+    /// Total number of shares created.
+    pub shares: u8,
+
+    /// Fingerprint of the public key matching the shared secret key.
+    pub fingerprint: String,
+
+    /// Share data, the same length as the secret key.
+    pub data: Vec<u8>,
+}
+
+impl KeyShare {
+    /// Serialize the share as text suitable for writing to a file.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        writeln!(text, "{}", SHARE_HEADER).unwrap();
+        writeln!(text, "name: {}", self.name).unwrap();
+        writeln!(text, "index: {}", self.index).unwrap();
+        writeln!(text, "threshold: {}", self.threshold).unwrap();
+        writeln!(text, "shares: {}", self.shares).unwrap();
+        writeln!(text, "fingerprint: {}", self.fingerprint).unwrap();
+        writeln!(text, "data: {}", hex(&self.data)).unwrap();
+        text
+    }
+
+    /// Parse a share from the text produced by `to_text`.
+    pub fn from_text(text: impl AsRef<str>) -> Result<KeyShare, ShareError> {
+        let mut lines = text.as_ref().lines();
+        if lines.next().map(str::trim) != Some(SHARE_HEADER) {
+            return Err(ShareError::InvalidShare);
+        }
+        let mut fields = lines.filter_map(|line| line.split_once(':'));
+        let mut field = |expected: &str| -> Result<String, ShareError> {
+            match fields.next() {
+                Some((label, value)) if label.trim() == expected => Ok(value.trim().to_string()),
+                _ => Err(ShareError::InvalidShare),
+            }
+        };
+        let name = field("name")?;
+        let index = field("index")?
+            .parse()
+            .map_err(|_| ShareError::InvalidShare)?;
+        let threshold = field("threshold")?
+            .parse()
+            .map_err(|_| ShareError::InvalidShare)?;
+        let shares = field("shares")?
+            .parse()
+            .map_err(|_| ShareError::InvalidShare)?;
+        let fingerprint = field("fingerprint")?;
+        let data = hex_decode(&field("data")?).ok_or(ShareError::InvalidShare)?;
+        if index == 0 || threshold == 0 || data.is_empty() {
+            return Err(ShareError::InvalidShare);
+        }
+        Ok(KeyShare {
+            name,
+            index,
+            threshold,
+            shares,
+            fingerprint,
+            data,
+        })
+    }
+}
+
+/// Split `secret` into `shares` shares, any `threshold` of which can be
+/// combined to recover it.
+pub fn split(
+    name: impl AsRef<str>,
+    secret: &SecretKey,
+    shares: u8,
+    threshold: u8,
+) -> Result<Vec<KeyShare>, ShareError> {
+    if threshold < 2 || shares < threshold {
+        return Err(ShareError::BadParameters { shares, threshold });
+    }
+    let secret_bytes = secret_key_bytes(secret);
+    let fingerprint = fingerprint(&public_from_secret(secret));
+    let mut data = vec![Vec::with_capacity(secret_bytes.len()); usize::from(shares)];
+    for &byte in secret_bytes.iter() {
+        let mut coefficients = randombytes(usize::from(threshold));
+        coefficients[0] = byte;
+        for (x, share_data) in (1..=shares).zip(data.iter_mut()) {
+            share_data.push(evaluate(&coefficients, x));
+        }
+    }
+    Ok((1..=shares)
+        .zip(data)
+        .map(|(index, data)| KeyShare {
+            name: name.as_ref().to_string(),
+            index,
+            threshold,
+            shares,
+            fingerprint: fingerprint.clone(),
+            data,
+        })
+        .collect())
+}
+
+/// Recover the keypair from `shares`.
+///
+/// The shares must agree on their threshold and fingerprint, have distinct
+/// indices, and number at least the threshold. The recovered secret is
+/// checked against the fingerprint before being returned.
+pub fn combine(shares: &[KeyShare]) -> Result<(PublicKey, SecretKey), ShareError> {
+    let first = shares.first().ok_or(ShareError::NotEnoughShares {
+        found: 0,
+        threshold: 0,
+    })?;
+    let consistent = shares.iter().all(|share| {
+        share.threshold == first.threshold
+            && share.fingerprint == first.fingerprint
+            && share.data.len() == first.data.len()
+    });
+    if !consistent {
+        return Err(ShareError::MismatchedShares);
+    }
+    let indices = shares
+        .iter()
+        .map(|share| share.index)
+        .collect::<HashSet<_>>();
+    if indices.len() != shares.len() {
+        return Err(ShareError::DuplicateShare);
+    }
+    if shares.len() < usize::from(first.threshold) {
+        return Err(ShareError::NotEnoughShares {
+            found: shares.len(),
+            threshold: first.threshold,
+        });
+    }
+
+    let shares = &shares[..usize::from(first.threshold)];
+    let secret_bytes = SecretBytes::from_vec(
+        (0..first.data.len())
+            .map(|i| {
+                let points = shares
+                    .iter()
+                    .map(|share| (share.index, share.data[i]))
+                    .collect::<Vec<_>>();
+                interpolate_at_zero(&points)
+            })
+            .collect(),
+    );
+    let secret =
+        SecretKey::from_raw_curve25519(&secret_bytes).map_err(|_| ShareError::InvalidShare)?;
+    let public = public_from_secret(&secret);
+    if fingerprint(&public) != first.fingerprint {
+        return Err(ShareError::FingerprintMismatch);
+    }
+    Ok((public, secret))
+}
+
+/// Evaluates the polynomial with `coefficients` (constant term first) at
+/// `x` using Horner's method.
+fn evaluate(coefficients: &[u8], x: u8) -> u8 {
+    coefficients
+        .iter()
+        .rev()
+        .fold(0, |acc, &coefficient| gf_mul(acc, x) ^ coefficient)
+}
+
+/// Lagrange interpolation of `points` evaluated at zero.
+fn interpolate_at_zero(points: &[(u8, u8)]) -> u8 {
+    points.iter().fold(0, |acc, &(xi, yi)| {
+        let basis = points
+            .iter()
+            .filter(|&&(xj, _)| xj != xi)
+            .fold(1, |basis, &(xj, _)| {
+                // In GF(2^8) subtraction is xor, so (0 - xj) / (xi - xj) is
+                // xj / (xi ^ xj).
+                gf_mul(basis, gf_div(xj, xi ^ xj))
+            });
+        acc ^ gf_mul(yi, basis)
+    })
+}
+
+/// Multiplication in GF(2^8) with the AES reduction polynomial.
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+/// Division in GF(2^8). `b` must be non-zero.
+fn gf_div(a: u8, b: u8) -> u8 {
+    // b^254 is the multiplicative inverse of b.
+    let mut inverse = 1;
+    for _ in 0..254 {
+        inverse = gf_mul(inverse, b);
+    }
+    gf_mul(a, inverse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{combine, split, KeyShare};
+    use crate::error::ShareError;
+
+    #[test]
+    fn split_combine_test() {
+        let (public, secret) = saltlick::gen_keypair();
+        let shares = split("escrow", &secret, 5, 3).unwrap();
+        assert_eq!(shares.len(), 5);
+
+        // Any three shares, in any order, recover the key.
+        for picks in &[[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+            let subset = picks.iter().map(|&i| shares[i].clone()).collect::<Vec<_>>();
+            let (recovered_public, recovered_secret) = combine(&subset).unwrap();
+            assert_eq!(recovered_public, public);
+            assert_eq!(recovered_secret, secret);
+        }
+
+        // Two are not enough.
+        match combine(&shares[..2]) {
+            Err(ShareError::NotEnoughShares {
+                found: 2,
+                threshold: 3,
+            }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // Repeating a share does not count twice.
+        let repeated = vec![shares[0].clone(), shares[0].clone(), shares[1].clone()];
+        match combine(&repeated) {
+            Err(ShareError::DuplicateShare) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn corrupt_share_test() {
+        let (_, secret) = saltlick::gen_keypair();
+        let mut shares = split("escrow", &secret, 3, 2).unwrap();
+        shares[1].data[0] ^= 0x01;
+        match combine(&shares[..2]) {
+            Err(ShareError::FingerprintMismatch) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn share_text_roundtrip_test() {
+        let (_, secret) = saltlick::gen_keypair();
+        let shares = split("escrow", &secret, 3, 2).unwrap();
+        for share in shares {
+            assert_eq!(KeyShare::from_text(share.to_text()).unwrap(), share);
+        }
+        KeyShare::from_text("not a share").unwrap_err();
+    }
+}
@@ -0,0 +1,1090 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Direct access to the parts of a saltlick v1 stream.
+//!
+//! A stream starts with a header naming the recipient public key and holding
+//! a sealed box that only the matching secret key can open. The box contains
+//! the symmetric key and header for a libsodium secretstream, which encrypts
+//! the rest of the stream as pairs of messages - a 4-byte big-endian block
+//! length, then the block itself, with the final block tagged `Final`.
+//!
+//! The `saltlick` crate only decrypts given the secret key. Splitting header
+//! opening from body decryption lets the secret key stay somewhere else,
+//! such as in the agent process, which hands back only the stream key.
+//!
+//! The `saltlick` crate also only decrypts from the start. Every block but
+//! the last holds the same amount of plaintext, so the position of any
+//! byte of plaintext in the stream is known in advance. Each message
+//! changes the secretstream state by its MAC, which is stored in the clear,
+//! so `SeekableDecrypter` can reach a block by reading only the MACs before
+//! it. Tampering with a skipped MAC changes the state and makes the blocks
+//! it does decrypt fail authentication, but the ciphertext of skipped blocks
+//! is never read, so changes to it go unnoticed. Only the blocks actually
+//! decrypted, and the order of the MACs before them, are authenticated.
+//!
+//! For the same reason, the state reached after authenticating the start
+//! of a partly written stream is the state needed to write the rest, so
+//! `StreamEncrypter` can continue a stream whose key is known.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ptr;
+
+use libsodium_sys as ffi;
+use saltlick::{PublicKey, SaltlickError, PUBLICKEYBYTES};
+use sodiumoxide::crypto::box_;
+use sodiumoxide::crypto::sealedbox::{self, SEALBYTES};
+use sodiumoxide::crypto::secretstream::{
+    self, Header, Key, Pull, Stream, Tag, ABYTES, HEADERBYTES, KEYBYTES,
+};
+use sodiumoxide::utils::memzero;
+
+const MAGIC: &[u8] = b"SALTLICK";
+const VERSION_V1: u8 = 1;
+const PREHEADER_LEN: usize = MAGIC.len() + 1;
+const SEALED_LEN: usize = KEYBYTES + HEADERBYTES + SEALBYTES;
+
+/// Length of an encrypted block length field.
+const LENGTH_LEN: usize = 4 + ABYTES;
+
+/// Total length of a v1 stream header.
+pub const HEADER_LEN: usize = PREHEADER_LEN + PUBLICKEYBYTES + SEALED_LEN;
+
+/// Bytes added to each block by its encrypted length and authentication tag.
+pub const BLOCK_OVERHEAD: usize = LENGTH_LEN + ABYTES;
+
+/// Returns the length of the stream produced by encrypting `plaintext_len`
+/// bytes with blocks of `block_size` bytes.
+///
+/// Every full block is followed by a final block holding the remainder,
+/// which is empty if the plaintext is a whole number of blocks.
+pub fn encrypted_len(plaintext_len: u64, block_size: usize) -> u64 {
+    let blocks = plaintext_len / block_size as u64 + 1;
+    HEADER_LEN as u64 + plaintext_len + blocks * BLOCK_OVERHEAD as u64
+}
+
+/// Returns an upper bound on the plaintext length of a stream of
+/// `encrypted_len` bytes.
+pub fn max_plaintext_len(encrypted_len: u64) -> u64 {
+    encrypted_len.saturating_sub((HEADER_LEN + BLOCK_OVERHEAD) as u64)
+}
+
+/// The unencrypted header at the start of a stream.
+#[derive(Clone, Debug)]
+pub struct StreamHeader {
+    /// Public key the stream was encrypted to.
+    pub public: PublicKey,
+
+    /// Sealed box holding the stream key.
+    pub sealed: Vec<u8>,
+
+    /// The header exactly as read from the stream.
+    pub raw: Vec<u8>,
+}
+
+impl StreamHeader {
+    /// Reads a stream header from the start of `reader`.
+    pub fn read_from(reader: &mut impl Read) -> io::Result<StreamHeader> {
+        let mut raw = vec![0u8; HEADER_LEN];
+        reader.read_exact(&mut raw)?;
+        if &raw[..MAGIC.len()] != MAGIC {
+            return Err(invalid_data("input is not a saltlick stream"));
+        }
+        if raw[MAGIC.len()] != VERSION_V1 {
+            return Err(invalid_data("unsupported saltlick stream version"));
+        }
+        let public_bytes = &raw[PREHEADER_LEN..PREHEADER_LEN + PUBLICKEYBYTES];
+        let public = PublicKey::from_raw_curve25519(public_bytes)
+            .expect("public key is always the correct length");
+        let sealed = raw[PREHEADER_LEN + PUBLICKEYBYTES..].to_vec();
+        Ok(StreamHeader {
+            public,
+            sealed,
+            raw,
+        })
+    }
+
+    /// Builds the header of a new stream to `public`, sealing `key` so that
+    /// only the matching secret key can open it.
+    pub fn seal(public: &PublicKey, key: &StreamKey) -> StreamHeader {
+        let public_bytes = crate::keychain::public_key_bytes(public);
+        let recipient = box_::PublicKey::from_slice(&public_bytes)
+            .expect("public key is always the correct length");
+        let sealed = sealedbox::seal(&key.to_bytes(), &recipient);
+        let mut raw = Vec::with_capacity(HEADER_LEN);
+        raw.extend_from_slice(MAGIC);
+        raw.push(VERSION_V1);
+        raw.extend_from_slice(&public_bytes);
+        raw.extend_from_slice(&sealed);
+        StreamHeader {
+            public: public.clone(),
+            sealed,
+            raw,
+        }
+    }
+}
+
+/// Symmetric key and secretstream header recovered from a stream header.
+pub struct StreamKey {
+    key: Key,
+    header: Header,
+}
+
+impl StreamKey {
+    /// Number of bytes in the serialized form of a stream key.
+    pub const BYTES: usize = KEYBYTES + HEADERBYTES;
+
+    /// Opens the sealed box from a stream header with the given raw
+    /// curve25519 keys. Returns `None` if the keys do not match the stream.
+    pub fn open(sealed: &[u8], public: &[u8], secret: &[u8]) -> Option<StreamKey> {
+        let public = box_::PublicKey::from_slice(public)?;
+        let secret = box_::SecretKey::from_slice(secret)?;
+        let contents = sealedbox::open(sealed, &public, &secret).ok()?;
+        StreamKey::from_bytes(&contents)
+    }
+
+    /// Generates a random key and secretstream header for a new stream.
+    pub fn generate() -> StreamKey {
+        let key = secretstream::gen_key();
+        let (_, header) = Stream::init_push(&key).expect("a generated key is always valid");
+        StreamKey { key, header }
+    }
+
+    /// Parses the form produced by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Option<StreamKey> {
+        if bytes.len() != StreamKey::BYTES {
+            return None;
+        }
+        Some(StreamKey {
+            key: Key::from_slice(&bytes[..KEYBYTES])?,
+            header: Header::from_slice(&bytes[KEYBYTES..])?,
+        })
+    }
+
+    /// Serializes the key and header for transfer from the agent or storage
+    /// in a resume journal.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(StreamKey::BYTES);
+        bytes.extend_from_slice(&self.key[..]);
+        bytes.extend_from_slice(&self.header[..]);
+        bytes
+    }
+}
+
+/// Reader that decrypts the body of a stream, following its header, with
+/// an already opened stream key.
+pub struct StreamDecrypter<R> {
+    inner: R,
+    stream: Stream<Pull>,
+    block: Vec<u8>,
+    pos: usize,
+    finalized: bool,
+}
+
+impl<R: Read> StreamDecrypter<R> {
+    /// Creates a decrypter for `inner`, which must be positioned just after
+    /// the stream header.
+    pub fn new(key: &StreamKey, inner: R) -> io::Result<StreamDecrypter<R>> {
+        let stream = Stream::init_pull(&key.header, &key.key)
+            .map_err(|()| invalid_data("invalid stream key"))?;
+        Ok(StreamDecrypter {
+            inner,
+            stream,
+            block: Vec::new(),
+            pos: 0,
+            finalized: false,
+        })
+    }
+
+    /// Decrypts the next block into `self.block`.
+    fn next_block(&mut self) -> io::Result<()> {
+        let mut length = [0u8; LENGTH_LEN];
+        read_exact_or_truncated(&mut self.inner, &mut length)?;
+        let (length, tag) = self
+            .stream
+            .pull(&length, None)
+            .map_err(|()| decryption_failure())?;
+        if tag != Tag::Message {
+            return Err(decryption_failure());
+        }
+        let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+        if length > saltlick::crypter::MAX_BLOCK_SIZE {
+            return Err(decryption_failure());
+        }
+        let mut ciphertext = vec![0u8; length + ABYTES];
+        read_exact_or_truncated(&mut self.inner, &mut ciphertext)?;
+        let tag = self
+            .stream
+            .pull_to_vec(&ciphertext, None, &mut self.block)
+            .map_err(|()| decryption_failure())?;
+        self.pos = 0;
+        match tag {
+            // Anything after the final block was not written by the
+            // encrypter.
+            Tag::Final if !at_end(&mut self.inner)? => {
+                return Err(invalid_data("data after the end of the stream"))
+            }
+            Tag::Final => self.finalized = true,
+            Tag::Message if length > 0 => {}
+            _ => return Err(decryption_failure()),
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for StreamDecrypter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.block.len() {
+            if self.finalized || buf.is_empty() {
+                return Ok(0);
+            }
+            self.next_block()?;
+        }
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Secretstream tags, as libsodium numbers them. Saltlick streams only use
+/// these two.
+const TAG_MESSAGE: u8 = 0;
+const TAG_FINAL: u8 = 3;
+
+/// Length of the part of the nonce that each MAC is folded into.
+const INONCE_LEN: usize = 8;
+
+/// Length of the message counter at the start of the nonce.
+const COUNTER_LEN: usize = 4;
+
+/// A secretstream state. libsodium uses the same state to push and pull
+/// messages, so unlike sodiumoxide's `Stream` it can be copied, advanced
+/// past a message without decrypting it, and used to continue writing a
+/// stream whose start has been read back.
+#[derive(Clone)]
+struct StreamState(ffi::crypto_secretstream_xchacha20poly1305_state);
+
+impl StreamState {
+    fn new(key: &StreamKey) -> io::Result<StreamState> {
+        let mut state = ffi::crypto_secretstream_xchacha20poly1305_state {
+            k: [0; 32],
+            nonce: [0; 12],
+            _pad: [0; 8],
+        };
+        // SAFETY: the header and key are the lengths libsodium expects.
+        let result = unsafe {
+            ffi::crypto_secretstream_xchacha20poly1305_init_pull(
+                &mut state,
+                key.header[..].as_ptr(),
+                key.key[..].as_ptr(),
+            )
+        };
+        if result == 0 {
+            Ok(StreamState(state))
+        } else {
+            Err(invalid_data("invalid stream key"))
+        }
+    }
+
+    /// Decrypts and authenticates one message, returning its plaintext and
+    /// tag. The state is unchanged if this fails.
+    fn pull(&mut self, ciphertext: &[u8]) -> io::Result<(Vec<u8>, u8)> {
+        if ciphertext.len() < ABYTES {
+            return Err(decryption_failure());
+        }
+        let mut plaintext = vec![0u8; ciphertext.len() - ABYTES];
+        let mut tag = 0u8;
+        // SAFETY: the plaintext buffer has room for the message, which is
+        // ABYTES shorter than its ciphertext, and there is no additional
+        // data.
+        let result = unsafe {
+            ffi::crypto_secretstream_xchacha20poly1305_pull(
+                &mut self.0,
+                plaintext.as_mut_ptr(),
+                ptr::null_mut(),
+                &mut tag,
+                ciphertext.as_ptr(),
+                ciphertext.len() as u64,
+                ptr::null(),
+                0,
+            )
+        };
+        if result == 0 {
+            Ok((plaintext, tag))
+        } else {
+            Err(decryption_failure())
+        }
+    }
+
+    /// Encrypts one message with `tag`.
+    fn push(&mut self, plaintext: &[u8], tag: u8) -> Vec<u8> {
+        let mut ciphertext = vec![0u8; plaintext.len() + ABYTES];
+        // SAFETY: the ciphertext buffer has room for the message and its
+        // ABYTES of overhead, and there is no additional data.
+        let result = unsafe {
+            ffi::crypto_secretstream_xchacha20poly1305_push(
+                &mut self.0,
+                ciphertext.as_mut_ptr(),
+                ptr::null_mut(),
+                plaintext.as_ptr(),
+                plaintext.len() as u64,
+                ptr::null(),
+                0,
+                tag,
+            )
+        };
+        // Pushing only fails for messages far larger than any block.
+        assert_eq!(result, 0, "secretstream push failed");
+        ciphertext
+    }
+
+    /// Advances the state past a message tagged `TAG_MESSAGE` whose MAC
+    /// starts with `mac`, exactly as pulling it would.
+    fn skip(&mut self, mac: &[u8; INONCE_LEN]) {
+        let (counter, inonce) = self.0.nonce.split_at_mut(COUNTER_LEN);
+        for (byte, mac) in inonce.iter_mut().zip(mac) {
+            *byte ^= mac;
+        }
+        // The counter is little endian, and the key is replaced whenever it
+        // wraps around.
+        for byte in counter.iter_mut() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                break;
+            }
+        }
+        if counter.iter().all(|&byte| byte == 0) {
+            // SAFETY: the state was initialized by init_pull.
+            unsafe { ffi::crypto_secretstream_xchacha20poly1305_rekey(&mut self.0) };
+        }
+    }
+}
+
+impl Drop for StreamState {
+    fn drop(&mut self) {
+        memzero(&mut self.0.k);
+        memzero(&mut self.0.nonce);
+    }
+}
+
+/// Reader that decrypts any part of the body of a stream on demand, for
+/// streams that can seek.
+pub struct SeekableDecrypter<R> {
+    inner: R,
+    /// Offset of the first block in `inner`.
+    body_start: u64,
+    /// Plaintext bytes in every block but the last.
+    block_size: u64,
+    blocks: u64,
+    final_len: u64,
+    /// State at the start of the first block.
+    start: StreamState,
+    /// State at the start of block `state_block`.
+    state: StreamState,
+    state_block: u64,
+    /// Plaintext of block `block_index`.
+    block: Vec<u8>,
+    block_index: Option<u64>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> SeekableDecrypter<R> {
+    /// Creates a decrypter for `inner`, which must be positioned just after
+    /// the stream header. Reads the first block length to work out the
+    /// layout of the stream.
+    pub fn new(key: &StreamKey, mut inner: R) -> io::Result<SeekableDecrypter<R>> {
+        let start = StreamState::new(key)?;
+        let body_start = inner.stream_position()?;
+        let body_len = inner.seek(SeekFrom::End(0))? - body_start;
+        let overhead = BLOCK_OVERHEAD as u64;
+        if body_len < overhead {
+            return Err(truncated());
+        }
+        inner.seek(SeekFrom::Start(body_start))?;
+        let mut length = [0u8; LENGTH_LEN];
+        read_exact_or_truncated(&mut inner, &mut length)?;
+        let first_len = match start.clone().pull(&length)? {
+            (length, TAG_MESSAGE) => {
+                u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as u64
+            }
+            _ => return Err(decryption_failure()),
+        };
+        let (block_size, blocks, final_len) = if body_len == first_len + overhead {
+            // The first block is the only one.
+            (first_len.max(1), 1, first_len)
+        } else {
+            if first_len == 0 || first_len > saltlick::crypter::MAX_BLOCK_SIZE as u64 {
+                return Err(decryption_failure());
+            }
+            let stride = first_len + overhead;
+            let rest = body_len - overhead;
+            if rest < stride || rest % stride >= first_len {
+                return Err(truncated());
+            }
+            (first_len, rest / stride + 1, rest % stride)
+        };
+        Ok(SeekableDecrypter {
+            inner,
+            body_start,
+            block_size,
+            blocks,
+            final_len,
+            state: start.clone(),
+            start,
+            state_block: 0,
+            block: Vec::new(),
+            block_index: None,
+            pos: 0,
+        })
+    }
+
+    /// Returns the length of the plaintext.
+    pub fn plaintext_len(&self) -> u64 {
+        (self.blocks - 1) * self.block_size + self.final_len
+    }
+
+    /// Authenticates the final block, so that a truncated stream is
+    /// detected even when reading stops at the end of the plaintext rather
+    /// than reaching it.
+    pub fn authenticate_end(&mut self) -> io::Result<()> {
+        if self.block_index != Some(self.blocks - 1) {
+            self.load_block(self.blocks - 1)?;
+        }
+        Ok(())
+    }
+
+    fn block_offset(&self, index: u64) -> u64 {
+        self.body_start + index * (self.block_size + BLOCK_OVERHEAD as u64)
+    }
+
+    fn read_mac(&mut self, offset: u64) -> io::Result<[u8; INONCE_LEN]> {
+        let mut mac = [0u8; INONCE_LEN];
+        self.inner.seek(SeekFrom::Start(offset))?;
+        read_exact_or_truncated(&mut self.inner, &mut mac)?;
+        Ok(mac)
+    }
+
+    /// Decrypts block `index` into `self.block`, first skipping the state
+    /// forward from the nearest known point.
+    fn load_block(&mut self, index: u64) -> io::Result<()> {
+        if self.state_block > index {
+            self.state = self.start.clone();
+            self.state_block = 0;
+        }
+        while self.state_block < index {
+            // Each MAC follows the encrypted tag byte and the message.
+            let offset = self.block_offset(self.state_block);
+            let length_mac = self.read_mac(offset + 1 + 4)?;
+            let block_mac = self.read_mac(offset + LENGTH_LEN as u64 + 1 + self.block_size)?;
+            self.state.skip(&length_mac);
+            self.state.skip(&block_mac);
+            self.state_block += 1;
+        }
+
+        let last = index == self.blocks - 1;
+        let expected_len = if last {
+            self.final_len
+        } else {
+            self.block_size
+        };
+        let offset = self.block_offset(index);
+        self.inner.seek(SeekFrom::Start(offset))?;
+        let mut state = self.state.clone();
+        let mut length = [0u8; LENGTH_LEN];
+        read_exact_or_truncated(&mut self.inner, &mut length)?;
+        match state.pull(&length)? {
+            (length, TAG_MESSAGE)
+                if u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as u64
+                    == expected_len => {}
+            _ => return Err(decryption_failure()),
+        }
+        let mut ciphertext = vec![0u8; expected_len as usize + ABYTES];
+        read_exact_or_truncated(&mut self.inner, &mut ciphertext)?;
+        let (block, tag) = state.pull(&ciphertext)?;
+        if tag != if last { TAG_FINAL } else { TAG_MESSAGE } {
+            return Err(decryption_failure());
+        }
+        self.state = state;
+        self.state_block = index + 1;
+        self.block = block;
+        self.block_index = Some(index);
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for SeekableDecrypter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.plaintext_len();
+        if self.pos >= len {
+            // Reaching the end authenticates the final block, even if it
+            // holds no plaintext, so a truncated stream is never mistaken
+            // for a complete one.
+            if self.pos == len && !buf.is_empty() {
+                self.authenticate_end()?;
+            }
+            return Ok(0);
+        }
+        let index = self.pos / self.block_size;
+        if self.block_index != Some(index) {
+            self.load_block(index)?;
+        }
+        let start = (self.pos - index * self.block_size) as usize;
+        let n = buf.len().min(self.block.len() - start);
+        buf[..n].copy_from_slice(&self.block[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for SeekableDecrypter<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (self.plaintext_len(), offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        match base.checked_add_signed(offset) {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// Writer that encrypts the body of a stream in blocks of a fixed size, as
+/// the `saltlick` crate does. Unlike the `saltlick` crate, it can continue a
+/// stream whose writing was interrupted, from the state found by `recover`.
+pub struct StreamEncrypter<W: Write> {
+    inner: W,
+    state: StreamState,
+    block_size: usize,
+    block: Vec<u8>,
+}
+
+impl<W: Write> StreamEncrypter<W> {
+    /// Creates an encrypter writing to `inner`, which must already hold the
+    /// stream header.
+    pub fn new(key: &StreamKey, inner: W, block_size: usize) -> io::Result<StreamEncrypter<W>> {
+        if !(saltlick::crypter::MIN_BLOCK_SIZE..=saltlick::crypter::MAX_BLOCK_SIZE)
+            .contains(&block_size)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "block size out of range",
+            ));
+        }
+        Ok(StreamEncrypter::resume(
+            RecoveredStream {
+                state: StreamState::new(key)?,
+                block_size,
+                blocks: 0,
+                finished: false,
+            },
+            inner,
+        ))
+    }
+
+    /// Creates an encrypter that continues the stream found by `recover`,
+    /// writing to `inner`, which must be positioned just after the intact
+    /// blocks.
+    pub fn resume(recovered: RecoveredStream, inner: W) -> StreamEncrypter<W> {
+        StreamEncrypter {
+            inner,
+            state: recovered.state,
+            block_size: recovered.block_size,
+            block: Vec::with_capacity(recovered.block_size),
+        }
+    }
+
+    fn write_block(&mut self, tag: u8) -> io::Result<()> {
+        let length = (self.block.len() as u32).to_be_bytes();
+        let length = self.state.push(&length, TAG_MESSAGE);
+        self.inner.write_all(&length)?;
+        let block = self.state.push(&self.block, tag);
+        self.inner.write_all(&block)?;
+        memzero(&mut self.block);
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Encrypts the remaining plaintext as the final block, and returns the
+    /// inner writer. Dropping the encrypter without finishing leaves a
+    /// stream that fails to decrypt, but can be recovered.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_block(TAG_FINAL)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for StreamEncrypter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.block_size - self.block.len());
+        self.block.extend_from_slice(&buf[..n]);
+        if self.block.len() == self.block_size {
+            self.write_block(TAG_MESSAGE)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The intact start of the body of a stream that was being written with
+/// `StreamEncrypter`.
+pub struct RecoveredStream {
+    state: StreamState,
+    block_size: usize,
+    /// Number of intact blocks, not counting the final block.
+    pub blocks: u64,
+    /// Whether the final block is also intact, so the stream is complete.
+    pub finished: bool,
+}
+
+impl RecoveredStream {
+    /// Returns the length of the plaintext held by the intact full blocks.
+    pub fn plaintext_len(&self) -> u64 {
+        self.blocks * self.block_size as u64
+    }
+
+    /// Returns the length of the intact full blocks in the stream body.
+    pub fn body_len(&self) -> u64 {
+        self.blocks * (self.block_size + BLOCK_OVERHEAD) as u64
+    }
+}
+
+/// Reads `body`, the body of a stream written by `StreamEncrypter` with
+/// `block_size`, authenticating blocks until the first that is missing,
+/// incomplete or damaged.
+pub fn recover(
+    key: &StreamKey,
+    body: &mut impl Read,
+    block_size: usize,
+) -> io::Result<RecoveredStream> {
+    let mut recovered = RecoveredStream {
+        state: StreamState::new(key)?,
+        block_size,
+        blocks: 0,
+        finished: false,
+    };
+    let mut ciphertext = vec![0u8; block_size + ABYTES];
+    loop {
+        let mut state = recovered.state.clone();
+        let mut length = [0u8; LENGTH_LEN];
+        if !read_full(body, &mut length)? {
+            break;
+        }
+        let length = match state.pull(&length) {
+            Ok((length, TAG_MESSAGE)) => {
+                u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize
+            }
+            _ => break,
+        };
+        if length > block_size {
+            break;
+        }
+        let ciphertext = &mut ciphertext[..length + ABYTES];
+        if !read_full(body, ciphertext)? {
+            break;
+        }
+        match state.pull(ciphertext) {
+            Ok((mut block, tag)) => {
+                memzero(&mut block);
+                match tag {
+                    TAG_MESSAGE if length == block_size => recovered.blocks += 1,
+                    TAG_FINAL if length < block_size => recovered.finished = true,
+                    _ => break,
+                }
+            }
+            Err(_) => break,
+        }
+        recovered.state = state;
+        if recovered.finished {
+            break;
+        }
+    }
+    Ok(recovered)
+}
+
+/// Fills `buf` from `reader`, returning false if it ends first.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+/// Opens the stream key in `header` with a keypair, returning the same
+/// errors as the `saltlick` crate when the keys do not match.
+pub fn open_stream_key(
+    header: &StreamHeader,
+    public: &PublicKey,
+    secret: &saltlick::SecretKey,
+) -> io::Result<StreamKey> {
+    if &header.public != public {
+        return Err(SaltlickError::PublicKeyMismatch.into());
+    }
+    StreamKey::open(
+        &header.sealed,
+        &crate::keychain::public_key_bytes(public),
+        &crate::keychain::secret_key_bytes(secret),
+    )
+    .ok_or_else(|| SaltlickError::DecryptionFailure.into())
+}
+
+fn read_exact_or_truncated(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<()> {
+    reader.read_exact(buf).map_err(|error| {
+        if error.kind() == io::ErrorKind::UnexpectedEof {
+            truncated()
+        } else {
+            error
+        }
+    })
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "saltlick stream is truncated")
+}
+
+/// Returns true if `reader` has nothing left to read.
+fn at_end(reader: &mut impl Read) -> io::Result<bool> {
+    loop {
+        match reader.read(&mut [0]) {
+            Ok(n) => return Ok(n == 0),
+            Err(ref error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+fn decryption_failure() -> io::Error {
+    invalid_data("decryption failed")
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        encrypted_len, max_plaintext_len, open_stream_key, recover, SeekableDecrypter,
+        StreamDecrypter, StreamEncrypter, StreamHeader, StreamKey, StreamState, HEADER_LEN,
+        INONCE_LEN, LENGTH_LEN,
+    };
+    use crate::keychain::{public_key_bytes, secret_key_bytes};
+
+    use saltlick::read::{SaltlickDecrypter, SaltlickEncrypter};
+    use sodiumoxide::crypto::secretstream::ABYTES;
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    fn encrypt(public: &saltlick::PublicKey, plaintext: &[u8]) -> Vec<u8> {
+        encrypt_blocks(public, plaintext, 1024)
+    }
+
+    fn encrypt_blocks(
+        public: &saltlick::PublicKey,
+        plaintext: &[u8],
+        block_size: usize,
+    ) -> Vec<u8> {
+        let mut encrypter = SaltlickEncrypter::new(public.clone(), plaintext);
+        encrypter.set_block_size(block_size);
+        let mut ciphertext = Vec::new();
+        encrypter.read_to_end(&mut ciphertext).unwrap();
+        ciphertext
+    }
+
+    #[test]
+    fn encrypted_len_test() {
+        let (public, _) = saltlick::gen_keypair();
+        for &len in &[0, 1, 1023, 1024, 1025, 5000] {
+            let ciphertext = encrypt(&public, &vec![0u8; len]);
+            assert_eq!(encrypted_len(len as u64, 1024), ciphertext.len() as u64);
+            assert!(max_plaintext_len(ciphertext.len() as u64) >= len as u64);
+        }
+        assert_eq!(max_plaintext_len(10), 0);
+    }
+
+    #[test]
+    fn open_and_decrypt_test() {
+        let (public, secret) = saltlick::gen_keypair();
+        let plaintext = (0..5000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let ciphertext = encrypt(&public, &plaintext);
+
+        let mut reader = Cursor::new(&ciphertext);
+        let header = StreamHeader::read_from(&mut reader).unwrap();
+        assert_eq!(header.public, public);
+        let key = StreamKey::open(
+            &header.sealed,
+            &public_key_bytes(&public),
+            &secret_key_bytes(&secret),
+        )
+        .unwrap();
+        let key = StreamKey::from_bytes(&key.to_bytes()).unwrap();
+        let mut decrypted = Vec::new();
+        StreamDecrypter::new(&key, reader)
+            .unwrap()
+            .read_to_end(&mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, plaintext);
+
+        // The wrong key does not open the header.
+        let (other_public, other_secret) = saltlick::gen_keypair();
+        assert!(StreamKey::open(
+            &header.sealed,
+            &public_key_bytes(&other_public),
+            &secret_key_bytes(&other_secret),
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn truncated_stream_test() {
+        let (public, secret) = saltlick::gen_keypair();
+        let ciphertext = encrypt(&public, &[7u8; 3000]);
+        let truncated = &ciphertext[..ciphertext.len() - 10];
+
+        let mut reader = Cursor::new(truncated);
+        let header = StreamHeader::read_from(&mut reader).unwrap();
+        let key = StreamKey::open(
+            &header.sealed,
+            &public_key_bytes(&public),
+            &secret_key_bytes(&secret),
+        )
+        .unwrap();
+        let mut decrypted = Vec::new();
+        StreamDecrypter::new(&key, reader)
+            .unwrap()
+            .read_to_end(&mut decrypted)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn trailing_data_test() {
+        let (public, secret) = saltlick::gen_keypair();
+        let mut ciphertext = encrypt(&public, &[7u8; 3000]);
+        ciphertext.push(0);
+
+        let mut reader = Cursor::new(ciphertext);
+        let header = StreamHeader::read_from(&mut reader).unwrap();
+        let key = open_stream_key(&header, &public, &secret).unwrap();
+        let mut decrypted = Vec::new();
+        StreamDecrypter::new(&key, reader)
+            .unwrap()
+            .read_to_end(&mut decrypted)
+            .unwrap_err();
+    }
+
+    fn seekable(
+        public: &saltlick::PublicKey,
+        secret: &saltlick::SecretKey,
+        ciphertext: &[u8],
+    ) -> std::io::Result<SeekableDecrypter<Cursor<Vec<u8>>>> {
+        let mut reader = Cursor::new(ciphertext.to_vec());
+        let header = StreamHeader::read_from(&mut reader)?;
+        let key = open_stream_key(&header, public, secret)?;
+        SeekableDecrypter::new(&key, reader)
+    }
+
+    fn read_range(
+        decrypter: &mut SeekableDecrypter<Cursor<Vec<u8>>>,
+        start: u64,
+        len: u64,
+    ) -> std::io::Result<Vec<u8>> {
+        decrypter.seek(SeekFrom::Start(start))?;
+        let mut output = Vec::new();
+        decrypter.take(len).read_to_end(&mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn stream_state_skip_test() {
+        let (public, secret) = saltlick::gen_keypair();
+        let ciphertext = encrypt(&public, &[1u8; 3000]);
+        let mut reader = Cursor::new(&ciphertext);
+        let header = StreamHeader::read_from(&mut reader).unwrap();
+        let key = open_stream_key(&header, &public, &secret).unwrap();
+
+        let mut pulled = StreamState::new(&key).unwrap();
+        let mut skipped = StreamState::new(&key).unwrap();
+        let mut offset = HEADER_LEN;
+        for len in [LENGTH_LEN, 1024 + ABYTES, LENGTH_LEN, 1024 + ABYTES] {
+            let message = &ciphertext[offset..offset + len];
+            pulled.pull(message).unwrap();
+            let mut mac = [0u8; INONCE_LEN];
+            mac.copy_from_slice(&message[len - ABYTES + 1..][..INONCE_LEN]);
+            skipped.skip(&mac);
+            assert_eq!(pulled.0.k, skipped.0.k);
+            assert_eq!(pulled.0.nonce, skipped.0.nonce);
+            offset += len;
+        }
+    }
+
+    #[test]
+    fn seekable_decrypter_test() {
+        let (public, secret) = saltlick::gen_keypair();
+        for &(len, block_size) in &[
+            (0, 1024),
+            (1, 1024),
+            (1023, 1024),
+            (4096, 1024),
+            (5000, 1024),
+        ] {
+            let plaintext = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let ciphertext = encrypt_blocks(&public, &plaintext, block_size);
+            let mut decrypter = seekable(&public, &secret, &ciphertext).unwrap();
+            assert_eq!(decrypter.plaintext_len(), len as u64);
+
+            let mut all = Vec::new();
+            decrypter.read_to_end(&mut all).unwrap();
+            assert_eq!(all, plaintext);
+
+            // Ranges within a block, across blocks, and going backwards.
+            for &(start, range_len) in &[(0, 10), (1000, 100), (3000, 2000), (5, 4090), (2, 1)] {
+                let got = read_range(&mut decrypter, start, range_len).unwrap();
+                let start = (start as usize).min(len);
+                let end = (start + range_len as usize).min(len);
+                assert_eq!(got, &plaintext[start..end]);
+            }
+            assert_eq!(
+                decrypter.seek(SeekFrom::End(-1)).ok(),
+                (len as u64).checked_sub(1)
+            );
+        }
+    }
+
+    #[test]
+    fn seekable_decrypter_tamper_test() {
+        let (public, secret) = saltlick::gen_keypair();
+        let plaintext = vec![9u8; 5000];
+        let ciphertext = encrypt(&public, &plaintext);
+        let stride = 1024 + super::BLOCK_OVERHEAD;
+
+        // A MAC in a skipped block breaks every later block.
+        let mut tampered = ciphertext.clone();
+        tampered[HEADER_LEN + LENGTH_LEN + 1024 + 5] ^= 1;
+        let mut decrypter = seekable(&public, &secret, &tampered).unwrap();
+        read_range(&mut decrypter, 3000, 10).unwrap_err();
+
+        // Ciphertext in the block being read fails authentication.
+        let mut tampered = ciphertext.clone();
+        tampered[HEADER_LEN + 2 * stride + LENGTH_LEN + 10] ^= 1;
+        let mut decrypter = seekable(&public, &secret, &tampered).unwrap();
+        assert_eq!(read_range(&mut decrypter, 0, 10).unwrap(), &plaintext[..10]);
+        read_range(&mut decrypter, 2100, 10).unwrap_err();
+
+        // A stream cut short within the final block still decrypts earlier
+        // blocks, but fails once the final block is reached. Lengths that
+        // no stream can have are rejected up front.
+        let truncated = &ciphertext[..ciphertext.len() - 10];
+        let mut decrypter = seekable(&public, &secret, truncated).unwrap();
+        assert_eq!(
+            read_range(&mut decrypter, 100, 10).unwrap(),
+            &plaintext[..10]
+        );
+        let mut output = Vec::new();
+        decrypter.read_to_end(&mut output).unwrap_err();
+        assert!(seekable(&public, &secret, &ciphertext[..HEADER_LEN + 4 * stride]).is_err());
+        assert!(seekable(&public, &secret, &ciphertext[..HEADER_LEN + 20]).is_err());
+
+        // The wrong keypair is reported as the saltlick crate reports it.
+        let (other_public, other_secret) = saltlick::gen_keypair();
+        assert!(seekable(&other_public, &other_secret, &ciphertext).is_err());
+    }
+
+    /// Encrypts `plaintext` with `StreamEncrypter`, returning the stream and
+    /// its key.
+    fn encrypt_resumable(
+        public: &saltlick::PublicKey,
+        plaintext: &[u8],
+        block_size: usize,
+    ) -> (Vec<u8>, StreamKey) {
+        let key = StreamKey::generate();
+        let header = StreamHeader::seal(public, &key);
+        let mut encrypter = StreamEncrypter::new(&key, header.raw, block_size).unwrap();
+        // Uneven writes cross block boundaries.
+        for chunk in plaintext.chunks(700) {
+            encrypter.write_all(chunk).unwrap();
+        }
+        (encrypter.finish().unwrap(), key)
+    }
+
+    fn decrypt(
+        public: &saltlick::PublicKey,
+        secret: &saltlick::SecretKey,
+        ciphertext: &[u8],
+    ) -> std::io::Result<Vec<u8>> {
+        let mut decrypted = Vec::new();
+        SaltlickDecrypter::new(public.clone(), secret.clone(), ciphertext)
+            .read_to_end(&mut decrypted)?;
+        Ok(decrypted)
+    }
+
+    #[test]
+    fn stream_encrypter_test() {
+        let (public, secret) = saltlick::gen_keypair();
+        for &len in &[0, 1, 1023, 1024, 1025, 5000] {
+            let plaintext = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let (ciphertext, _) = encrypt_resumable(&public, &plaintext, 1024);
+            assert_eq!(ciphertext.len() as u64, encrypted_len(len as u64, 1024));
+            assert_eq!(decrypt(&public, &secret, &ciphertext).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn recover_and_resume_test() {
+        let (public, secret) = saltlick::gen_keypair();
+        let plaintext = (0..5000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let (ciphertext, key) = encrypt_resumable(&public, &plaintext, 1024);
+        let stride = 1024 + super::BLOCK_OVERHEAD;
+
+        // Cut at every kind of place - in a length, in a block, at a block
+        // boundary - and with the last block damaged.
+        let mut damaged = ciphertext.clone();
+        damaged[HEADER_LEN + 2 * stride + 100] ^= 1;
+        let cases = [
+            (&ciphertext[..HEADER_LEN], 0),
+            (&ciphertext[..HEADER_LEN + 10], 0),
+            (&ciphertext[..HEADER_LEN + stride], 1),
+            (&ciphertext[..HEADER_LEN + 3 * stride - 1], 2),
+            (&damaged[..HEADER_LEN + 3 * stride], 2),
+        ];
+        for &(partial, blocks) in &cases {
+            let mut body = &partial[HEADER_LEN..];
+            let recovered = recover(&key, &mut body, 1024).unwrap();
+            assert_eq!(recovered.blocks, blocks);
+            assert!(!recovered.finished);
+
+            let mut resumed = partial[..HEADER_LEN + recovered.body_len() as usize].to_vec();
+            let offset = recovered.plaintext_len() as usize;
+            let mut encrypter = StreamEncrypter::resume(recovered, &mut resumed);
+            encrypter.write_all(&plaintext[offset..]).unwrap();
+            encrypter.finish().unwrap();
+            assert_eq!(resumed, ciphertext);
+            assert_eq!(decrypt(&public, &secret, &resumed).unwrap(), plaintext);
+        }
+
+        // A complete stream is recognised as finished.
+        let mut body = &ciphertext[HEADER_LEN..];
+        let recovered = recover(&key, &mut body, 1024).unwrap();
+        assert_eq!(recovered.blocks, 4);
+        assert!(recovered.finished);
+    }
+}
@@ -0,0 +1,523 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Encryption of individual values inside structured documents.
+//!
+//! Keys and document structure stay readable, and each scalar value is
+//! replaced by an armored string such as `ENC[saltlick,str,...]`. Values are
+//! encrypted with a random data key using XChaCha20-Poly1305, with the path
+//! to the value as additional data so values cannot be moved around the
+//! document. The data key is sealed to each recipient public key and stored,
+//! along with an HMAC over every value in the document, in a `saltlick`
+//! metadata entry at the top level.
+
+use std::fmt::{self, Display};
+use std::path::Path;
+use std::str::FromStr;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use regex::Regex;
+use saltlick::{PublicKey, SecretKey};
+use serde::{Deserialize, Serialize};
+use serde_json::{Number, Value};
+use sodiumoxide::crypto::aead::xchacha20poly1305_ietf as aead;
+use sodiumoxide::crypto::auth::hmacsha256;
+use sodiumoxide::crypto::{box_, sealedbox};
+
+use crate::audit::{hex, hex_decode};
+use crate::error::ValuesError;
+use crate::keychain::{fingerprint, public_key_bytes, secret_key_bytes};
+
+/// Top-level key holding the encryption metadata.
+pub const METADATA_KEY: &str = "saltlick";
+
+const ARMOR_PREFIX: &str = "ENC[saltlick,";
+const ARMOR_SUFFIX: &str = "]";
+const METADATA_VERSION: u32 = 1;
+
+/// Document formats supported for value encryption.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    /// Guesses the format of `path` from its extension.
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Format> {
+        let extension = path.as_ref().extension()?.to_str()?;
+        extension.parse().ok()
+    }
+
+    /// Conventional file extension for the format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Toml => "toml",
+            Format::Yaml => "yaml",
+        }
+    }
+
+    /// Parses `text` into a document.
+    pub fn parse(self, text: &str) -> Result<Value, ValuesError> {
+        let parse_error = |message: String| ValuesError::Parse {
+            format: self,
+            message,
+        };
+        match self {
+            Format::Json => serde_json::from_str(text).map_err(|e| parse_error(e.to_string())),
+            Format::Toml => toml::from_str(text).map_err(|e| parse_error(e.to_string())),
+            Format::Yaml => serde_yaml::from_str(text).map_err(|e| parse_error(e.to_string())),
+        }
+    }
+
+    /// Renders `document` as text.
+    pub fn render(self, document: &Value) -> Result<String, ValuesError> {
+        let render_error = |message: String| ValuesError::Render {
+            format: self,
+            message,
+        };
+        match self {
+            Format::Json => serde_json::to_string_pretty(document)
+                .map(|text| text + "\n")
+                .map_err(|e| render_error(e.to_string())),
+            // Going through `toml::Value` puts plain values ahead of tables,
+            // as TOML requires.
+            Format::Toml => toml::Value::try_from(document)
+                .and_then(|value| toml::to_string_pretty(&value))
+                .map_err(|e| render_error(e.to_string())),
+            Format::Yaml => {
+                serde_yaml::to_string(document).map_err(|e| render_error(e.to_string()))
+            }
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.extension())
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "toml" => Ok(Format::Toml),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            _ => Err(format!("unknown format \"{}\"", s)),
+        }
+    }
+}
+
+/// A recipient entry in the document metadata.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Recipient {
+    /// Fingerprint of the recipient public key.
+    pub fingerprint: String,
+
+    /// Data key sealed to the recipient, base64 encoded.
+    pub key: String,
+}
+
+/// The `saltlick` metadata entry of an encrypted document.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct Metadata {
+    version: u32,
+    recipients: Vec<Recipient>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encrypted_regex: Option<String>,
+    mac: String,
+}
+
+/// Data key and settings used to encrypt the values of a document.
+pub struct Envelope {
+    key: aead::Key,
+    recipients: Vec<Recipient>,
+    encrypted_regex: Option<Regex>,
+}
+
+impl Envelope {
+    /// Creates an envelope with a new data key sealed to `recipients`. If
+    /// `encrypted_regex` is given, only values whose path matches it are
+    /// encrypted.
+    pub fn new(
+        recipients: &[PublicKey],
+        encrypted_regex: Option<&str>,
+    ) -> Result<Envelope, ValuesError> {
+        let key = aead::gen_key();
+        let recipients = recipients
+            .iter()
+            .map(|public| {
+                let box_public = box_::PublicKey::from_slice(&public_key_bytes(public))
+                    .expect("public key is always the correct length");
+                Recipient {
+                    fingerprint: fingerprint(public),
+                    key: BASE64.encode(sealedbox::seal(&key[..], &box_public)),
+                }
+            })
+            .collect();
+        let encrypted_regex = encrypted_regex
+            .map(Regex::new)
+            .transpose()
+            .map_err(ValuesError::InvalidRegex)?;
+        Ok(Envelope {
+            key,
+            recipients,
+            encrypted_regex,
+        })
+    }
+
+    /// Fingerprints of the recipients in an encrypted `document`.
+    pub fn recipients(document: &Value) -> Result<Vec<Recipient>, ValuesError> {
+        Ok(metadata(document)?.recipients)
+    }
+
+    /// Recovers the envelope from an encrypted `document` with a keypair
+    /// matching one of its recipients, and verifies the document MAC.
+    pub fn open(
+        document: &Value,
+        public: &PublicKey,
+        secret: &SecretKey,
+    ) -> Result<Envelope, ValuesError> {
+        let metadata = metadata(document)?;
+        let recipient = metadata
+            .recipients
+            .iter()
+            .find(|recipient| recipient.fingerprint == fingerprint(public))
+            .ok_or(ValuesError::NoMatchingKey)?;
+        let sealed = BASE64
+            .decode(&recipient.key)
+            .map_err(|_| ValuesError::BadMetadata(String::from("invalid recipient key")))?;
+        let box_public = box_::PublicKey::from_slice(&public_key_bytes(public))
+            .expect("public key is always the correct length");
+        let box_secret = box_::SecretKey::from_slice(&secret_key_bytes(secret))
+            .expect("secret key is always the correct length");
+        let key = sealedbox::open(&sealed, &box_public, &box_secret)
+            .ok()
+            .and_then(|key| aead::Key::from_slice(&key))
+            .ok_or(ValuesError::NoMatchingKey)?;
+        let encrypted_regex = metadata
+            .encrypted_regex
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .map_err(ValuesError::InvalidRegex)?;
+        let envelope = Envelope {
+            key,
+            recipients: metadata.recipients.clone(),
+            encrypted_regex,
+        };
+        let expected = hex_decode(&metadata.mac)
+            .and_then(|mac| hmacsha256::Tag::from_slice(&mac))
+            .ok_or(ValuesError::MacMismatch)?;
+        let mut body = document.clone();
+        remove_metadata(&mut body);
+        if !hmacsha256::verify(&expected, &envelope.mac_input(&body), &envelope.mac_key()) {
+            return Err(ValuesError::MacMismatch);
+        }
+        Ok(envelope)
+    }
+
+    /// Encrypts the values of a plaintext `document`.
+    pub fn encrypt(&self, document: &Value) -> Result<Value, ValuesError> {
+        if metadata(document).is_ok() {
+            return Err(ValuesError::AlreadyEncrypted);
+        }
+        let mut encrypted = document.clone();
+        if !encrypted.is_object() {
+            return Err(ValuesError::NotATable);
+        }
+        walk(&mut encrypted, &mut Vec::new(), &mut |path, value| {
+            if self.should_encrypt(path) {
+                *value = Value::String(self.encrypt_value(path, value));
+            }
+            Ok(())
+        })?;
+        let mac = hmacsha256::authenticate(&self.mac_input(&encrypted), &self.mac_key());
+        let metadata = Metadata {
+            version: METADATA_VERSION,
+            recipients: self.recipients.clone(),
+            encrypted_regex: self
+                .encrypted_regex
+                .as_ref()
+                .map(|regex| regex.as_str().to_string()),
+            mac: hex(&mac[..]),
+        };
+        encrypted.as_object_mut().expect("checked above").insert(
+            String::from(METADATA_KEY),
+            serde_json::to_value(metadata).expect("metadata serialization cannot fail"),
+        );
+        Ok(encrypted)
+    }
+
+    /// Decrypts the values of an encrypted `document`, removing its
+    /// metadata.
+    pub fn decrypt(&self, document: &Value) -> Result<Value, ValuesError> {
+        let mut decrypted = document.clone();
+        remove_metadata(&mut decrypted);
+        walk(&mut decrypted, &mut Vec::new(), &mut |path, value| {
+            if let Some(armored) = value.as_str().filter(|s| s.starts_with(ARMOR_PREFIX)) {
+                *value = self.decrypt_value(path, armored)?;
+            }
+            Ok(())
+        })?;
+        Ok(decrypted)
+    }
+
+    fn should_encrypt(&self, path: &str) -> bool {
+        self.encrypted_regex
+            .as_ref()
+            .map(|regex| regex.is_match(path))
+            .unwrap_or(true)
+    }
+
+    fn encrypt_value(&self, path: &str, value: &Value) -> String {
+        let (type_, plaintext) = match value {
+            Value::String(s) => ("str", s.clone()),
+            Value::Bool(b) => ("bool", b.to_string()),
+            Value::Number(n) if n.is_f64() => ("float", n.to_string()),
+            Value::Number(n) => ("int", n.to_string()),
+            _ => unreachable!("only scalars are encrypted"),
+        };
+        let nonce = aead::gen_nonce();
+        let mut data = nonce[..].to_vec();
+        data.extend(aead::seal(
+            plaintext.as_bytes(),
+            Some(path.as_bytes()),
+            &nonce,
+            &self.key,
+        ));
+        format!(
+            "{}{},{}{}",
+            ARMOR_PREFIX,
+            type_,
+            BASE64.encode(data),
+            ARMOR_SUFFIX
+        )
+    }
+
+    fn decrypt_value(&self, path: &str, armored: &str) -> Result<Value, ValuesError> {
+        let invalid = || ValuesError::InvalidValue {
+            path: path.to_string(),
+        };
+        let inner = armored
+            .strip_prefix(ARMOR_PREFIX)
+            .and_then(|s| s.strip_suffix(ARMOR_SUFFIX))
+            .ok_or_else(invalid)?;
+        let (type_, data) = inner.split_once(',').ok_or_else(invalid)?;
+        let data = BASE64.decode(data).map_err(|_| invalid())?;
+        if data.len() < aead::NONCEBYTES {
+            return Err(invalid());
+        }
+        let (nonce, ciphertext) = data.split_at(aead::NONCEBYTES);
+        let nonce = aead::Nonce::from_slice(nonce).ok_or_else(invalid)?;
+        let plaintext = aead::open(ciphertext, Some(path.as_bytes()), &nonce, &self.key)
+            .map_err(|()| invalid())?;
+        let plaintext = String::from_utf8(plaintext).map_err(|_| invalid())?;
+        match type_ {
+            "str" => Ok(Value::String(plaintext)),
+            "bool" => plaintext.parse().map(Value::Bool).map_err(|_| invalid()),
+            "int" | "float" => serde_json::from_str::<Number>(&plaintext)
+                .map(Value::Number)
+                .map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Key for the document MAC, derived from the data key so that it is
+    /// never used directly for both purposes.
+    fn mac_key(&self) -> hmacsha256::Key {
+        let derived = hmacsha256::authenticate(
+            b"saltlick-cli values mac",
+            &hmacsha256::Key::from_slice(&self.key[..]).expect("data key is the MAC key size"),
+        );
+        hmacsha256::Key::from_slice(&derived[..]).expect("tag is the MAC key size")
+    }
+
+    /// Every scalar in `document` along with its path, in document order.
+    fn mac_input(&self, document: &Value) -> Vec<u8> {
+        let mut input = Vec::new();
+        if let Some(regex) = self.encrypted_regex.as_ref() {
+            input.extend_from_slice(regex.as_str().as_bytes());
+        }
+        input.push(0);
+        walk_all(document, &mut Vec::new(), &mut |path, value| {
+            input.extend_from_slice(path.as_bytes());
+            input.push(0);
+            input.extend_from_slice(value.to_string().as_bytes());
+            input.push(0);
+        });
+        input
+    }
+}
+
+fn metadata(document: &Value) -> Result<Metadata, ValuesError> {
+    let metadata = document
+        .get(METADATA_KEY)
+        .cloned()
+        .ok_or(ValuesError::NotEncrypted)?;
+    let metadata: Metadata = serde_json::from_value(metadata)
+        .map_err(|error| ValuesError::BadMetadata(error.to_string()))?;
+    if metadata.version != METADATA_VERSION {
+        return Err(ValuesError::BadMetadata(format!(
+            "unsupported version {}",
+            metadata.version
+        )));
+    }
+    Ok(metadata)
+}
+
+fn remove_metadata(document: &mut Value) {
+    if let Some(map) = document.as_object_mut() {
+        map.shift_remove(METADATA_KEY);
+    }
+}
+
+/// Calls `f` with the dotted path of every non-null scalar in `value`.
+fn walk(
+    value: &mut Value,
+    path: &mut Vec<String>,
+    f: &mut dyn FnMut(&str, &mut Value) -> Result<(), ValuesError>,
+) -> Result<(), ValuesError> {
+    match value {
+        Value::Object(map) => {
+            for (key, item) in map.iter_mut() {
+                path.push(key.clone());
+                walk(item, path, f)?;
+                path.pop();
+            }
+            Ok(())
+        }
+        Value::Array(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                path.push(index.to_string());
+                walk(item, path, f)?;
+                path.pop();
+            }
+            Ok(())
+        }
+        Value::Null => Ok(()),
+        scalar => f(&path.join("."), scalar),
+    }
+}
+
+/// Like `walk`, but also visits nulls and does not modify `value`.
+fn walk_all(value: &Value, path: &mut Vec<String>, f: &mut dyn FnMut(&str, &Value)) {
+    match value {
+        Value::Object(map) => {
+            for (key, item) in map {
+                path.push(key.clone());
+                walk_all(item, path, f);
+                path.pop();
+            }
+        }
+        Value::Array(items) => {
+            for (index, item) in items.iter().enumerate() {
+                path.push(index.to_string());
+                walk_all(item, path, f);
+                path.pop();
+            }
+        }
+        scalar => f(&path.join("."), scalar),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Envelope, Format, METADATA_KEY};
+    use crate::error::ValuesError;
+
+    const YAML: &str = "\
+database:
+  host: db.example.com
+  port: 5432
+  password: hunter2
+  replicas:
+    - one
+    - two
+debug: false
+ratio: 0.5
+";
+
+    #[test]
+    fn values_roundtrip_test() {
+        let (public, secret) = saltlick::gen_keypair();
+        let (other_public, other_secret) = saltlick::gen_keypair();
+        for format in &[Format::Json, Format::Toml, Format::Yaml] {
+            let document = Format::Yaml.parse(YAML).unwrap();
+            let text = format.render(&document).unwrap();
+            let document = format.parse(&text).unwrap();
+
+            let envelope = Envelope::new(&[public.clone(), other_public.clone()], None).unwrap();
+            let encrypted = envelope.encrypt(&document).unwrap();
+            let encrypted_text = format.render(&encrypted).unwrap();
+            assert!(encrypted_text.contains("database"));
+            assert!(encrypted_text.contains("replicas"));
+            assert!(!encrypted_text.contains("hunter2"));
+            assert!(!encrypted_text.contains("5432"));
+
+            // Either recipient can decrypt.
+            let encrypted = format.parse(&encrypted_text).unwrap();
+            for (public, secret) in &[(&public, &secret), (&other_public, &other_secret)] {
+                let opened = Envelope::open(&encrypted, public, secret).unwrap();
+                assert_eq!(opened.decrypt(&encrypted).unwrap(), document);
+            }
+        }
+    }
+
+    #[test]
+    fn encrypted_regex_test() {
+        let (public, secret) = saltlick::gen_keypair();
+        let document = Format::Yaml.parse(YAML).unwrap();
+        let envelope = Envelope::new(std::slice::from_ref(&public), Some("password$")).unwrap();
+        let encrypted = envelope.encrypt(&document).unwrap();
+        assert_eq!(encrypted["database"]["host"], "db.example.com");
+        assert!(encrypted["database"]["password"]
+            .as_str()
+            .unwrap()
+            .starts_with("ENC[saltlick,str,"));
+        assert!(encrypted[METADATA_KEY]["encrypted_regex"].is_string());
+
+        let opened = Envelope::open(&encrypted, &public, &secret).unwrap();
+        assert_eq!(opened.decrypt(&encrypted).unwrap(), document);
+    }
+
+    #[test]
+    fn tampering_is_detected_test() {
+        let (public, secret) = saltlick::gen_keypair();
+        let document = Format::Yaml.parse(YAML).unwrap();
+        let envelope = Envelope::new(std::slice::from_ref(&public), Some("password$")).unwrap();
+        let encrypted = envelope.encrypt(&document).unwrap();
+
+        // Unencrypted values are covered by the MAC.
+        let mut altered = encrypted.clone();
+        altered["database"]["host"] = "evil.example.com".into();
+        match Envelope::open(&altered, &public, &secret) {
+            Err(ValuesError::MacMismatch) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+
+        // The wrong key cannot open the document.
+        let (other_public, other_secret) = saltlick::gen_keypair();
+        match Envelope::open(&encrypted, &other_public, &other_secret) {
+            Err(ValuesError::NoMatchingKey) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+
+        // Encrypting twice is refused.
+        match envelope.encrypt(&encrypted) {
+            Err(ValuesError::AlreadyEncrypted) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,585 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Encrypted output split into volumes of a fixed maximum size.
+//!
+//! `encrypt --split-size` writes `out.slk.001`, `out.slk.002` and so on,
+//! each a complete saltlick stream holding a whole number of blocks of the
+//! plaintext, so each volume can be decrypted and verified on its own. The
+//! manifest `out.slk.manifest` lists the volumes in order with the hash of
+//! each stream header, which is unique to its volume, along with the total
+//! plaintext length and a hash of the ciphertext of every volume in turn.
+//!
+//! Before decrypting anything, `decrypt` checks that every volume is present
+//! and has the length and header the manifest records, so a missing,
+//! swapped or foreign volume is reported up front.
+//!
+//! The manifest is not keyed, so anyone who can write it can also rewrite
+//! it to match whatever volumes they like. What binds the volumes together
+//! is the trailer that ends the plaintext of each volume, encrypted along
+//! with it: a random set id shared by every volume of the split, the
+//! volume's number, the plaintext length up to the end of the volume and
+//! whether it is the last. Only someone holding the secret key can read the
+//! set id, so decrypting checks the trailers rather than the manifest to
+//! catch a volume dropped, reordered or taken from another set.
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::randombytes::randombytes_into;
+
+use crate::audit::hex;
+use crate::error::VolumeError;
+use crate::stream::{BLOCK_OVERHEAD, HEADER_LEN};
+
+/// Extension added to the output file name to name the manifest.
+pub const MANIFEST_EXTENSION: &str = "manifest";
+
+/// Largest `--split-size` accepted.
+pub const MAX_SPLIT_SIZE: u64 = 1 << 40;
+
+const MANIFEST_VERSION: u32 = 1;
+
+/// Magic bytes that start a volume trailer.
+const TRAILER_MAGIC: &[u8; 8] = b"slkvol\x00\x01";
+
+/// Length of a volume set id.
+const SET_ID_LEN: usize = 16;
+
+/// Length of the trailer that ends the plaintext of each volume.
+pub const TRAILER_LEN: usize = TRAILER_MAGIC.len() + SET_ID_LEN + 8 + 1 + 8;
+
+/// Returns the path of the manifest for split output `output`.
+pub fn manifest_path(output: impl AsRef<Path>) -> PathBuf {
+    with_suffix(output, MANIFEST_EXTENSION)
+}
+
+/// Returns the path of volume `number`, counting from 1, of split output
+/// `output`.
+pub fn volume_path(output: impl AsRef<Path>, number: usize) -> PathBuf {
+    with_suffix(output, &format!("{:03}", number))
+}
+
+fn with_suffix(path: impl AsRef<Path>, suffix: &str) -> PathBuf {
+    let mut path = path.as_ref().as_os_str().to_owned();
+    path.push(".");
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Returns the manifest for `input` if it names a manifest, or a volume
+/// whose manifest exists.
+pub fn find_manifest(input: &Path) -> Option<PathBuf> {
+    let extension = input.extension().and_then(OsStr::to_str)?;
+    if extension == MANIFEST_EXTENSION {
+        return Some(input.to_path_buf());
+    }
+    if extension.is_empty() || !extension.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let manifest = manifest_path(input.with_extension(""));
+    if manifest.is_file() {
+        Some(manifest)
+    } else {
+        None
+    }
+}
+
+/// Returns true if `input` looks like the first of several volumes, that is
+/// it ends in ".001" and a ".002" volume sits alongside it.
+pub fn is_first_volume(input: &Path) -> bool {
+    input.extension() == Some(OsStr::new("001"))
+        && volume_path(input.with_extension(""), 2).is_file()
+}
+
+/// Returns the largest amount of plaintext a volume of at most `split_size`
+/// bytes holds with `block_size`, or `None` if not even one block fits.
+/// Volumes hold whole blocks, the last ending with the trailer, followed by
+/// an empty final block.
+pub fn volume_capacity(split_size: u64, block_size: usize) -> Option<u64> {
+    let overhead = (HEADER_LEN + BLOCK_OVERHEAD) as u64;
+    let blocks = split_size.checked_sub(overhead)? / (block_size + BLOCK_OVERHEAD) as u64;
+    if blocks == 0 {
+        None
+    } else {
+        Some(blocks * block_size as u64 - TRAILER_LEN as u64)
+    }
+}
+
+/// Smallest `split_size` that holds a block of `block_size`.
+pub fn min_split_size(block_size: usize) -> usize {
+    HEADER_LEN + block_size + 2 * BLOCK_OVERHEAD
+}
+
+/// Returns the hash of a stream header as recorded in a manifest.
+pub fn header_sha256(header: &[u8]) -> String {
+    hex(&sha256::hash(header)[..])
+}
+
+/// One volume as listed in a manifest.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct VolumeEntry {
+    /// File name of the volume, in the same directory as the manifest.
+    pub name: String,
+    /// Length of the volume.
+    pub len: u64,
+    /// Length of the plaintext the volume holds.
+    pub plaintext_len: u64,
+    /// SHA-256 of the volume's stream header, in hex.
+    pub header_sha256: String,
+}
+
+/// Order and contents of a set of volumes.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Manifest {
+    pub version: u32,
+    /// Length of the whole plaintext.
+    pub total_len: u64,
+    /// SHA-256 of the ciphertext of every volume in order, in hex.
+    pub sha256: String,
+    pub volumes: Vec<VolumeEntry>,
+}
+
+impl Manifest {
+    /// Creates the manifest for `volumes`, whose ciphertext has the hash
+    /// `sha256`.
+    pub fn new(volumes: Vec<VolumeEntry>, sha256: StreamHash) -> Manifest {
+        Manifest {
+            version: MANIFEST_VERSION,
+            total_len: volumes.iter().map(|volume| volume.plaintext_len).sum(),
+            sha256: sha256.finish(),
+            volumes,
+        }
+    }
+
+    /// Parses a manifest.
+    pub fn parse(contents: &[u8]) -> Result<Manifest, VolumeError> {
+        let manifest: Manifest = serde_json::from_slice(contents)
+            .map_err(|error| VolumeError::InvalidManifest(error.to_string()))?;
+        if manifest.version != MANIFEST_VERSION {
+            return Err(VolumeError::InvalidManifest(format!(
+                "unsupported version {}",
+                manifest.version
+            )));
+        }
+        if manifest.volumes.is_empty() {
+            return Err(VolumeError::InvalidManifest("no volumes".to_string()));
+        }
+        let total_len = manifest.volumes.iter().try_fold(0u64, |total, volume| {
+            total.checked_add(volume.plaintext_len)
+        });
+        if total_len != Some(manifest.total_len) {
+            return Err(VolumeError::InvalidManifest(
+                "volume lengths do not add up to the total length".to_string(),
+            ));
+        }
+        Ok(manifest)
+    }
+
+    /// Serializes the manifest.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut contents = serde_json::to_vec_pretty(self).expect("manifest always serializes");
+        contents.push(b'\n');
+        contents
+    }
+
+    /// Returns the paths of the volumes, which are next to `manifest`,
+    /// checking that each exists and has the length and stream header
+    /// recorded for its position.
+    pub fn check_volumes(&self, manifest: &Path) -> Result<Vec<PathBuf>, VolumeError> {
+        let dir = manifest.parent().unwrap_or_else(|| Path::new(""));
+        let mut paths = Vec::with_capacity(self.volumes.len());
+        for (index, entry) in self.volumes.iter().enumerate() {
+            // Names come from the manifest, so only bare file names are
+            // accepted.
+            if Path::new(&entry.name).file_name() != Some(OsStr::new(&entry.name)) {
+                return Err(VolumeError::InvalidManifest(format!(
+                    "invalid volume name \"{}\"",
+                    entry.name
+                )));
+            }
+            let path = dir.join(&entry.name);
+            let missing = |_| VolumeError::Missing {
+                name: entry.name.clone(),
+            };
+            let mut file = File::open(&path).map_err(missing)?;
+            let mut header = [0u8; HEADER_LEN];
+            let len = file.metadata().map_err(missing)?.len();
+            let header_hash = file
+                .read_exact(&mut header)
+                .ok()
+                .map(|()| header_sha256(&header));
+            if len == entry.len && header_hash.as_ref() == Some(&entry.header_sha256) {
+                paths.push(path);
+                continue;
+            }
+            let found = self
+                .volumes
+                .iter()
+                .position(|other| header_hash.as_ref() == Some(&other.header_sha256));
+            return Err(match found {
+                Some(found) => VolumeError::OutOfOrder {
+                    name: entry.name.clone(),
+                    expected: index + 1,
+                    found: found + 1,
+                },
+                None => VolumeError::Mismatch {
+                    name: entry.name.clone(),
+                },
+            });
+        }
+        Ok(paths)
+    }
+}
+
+/// Trailer that ends the plaintext of a volume, tying it to its place in
+/// the set.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VolumeTrailer {
+    /// Random id shared by every volume of the set.
+    pub set_id: [u8; SET_ID_LEN],
+    /// Number of the volume, counting from 1.
+    pub number: u64,
+    /// Whether this is the last volume of the set.
+    pub last: bool,
+    /// Length of the plaintext of this volume and every one before it.
+    pub end: u64,
+}
+
+impl VolumeTrailer {
+    /// Generates a new set id.
+    pub fn generate_set_id() -> [u8; SET_ID_LEN] {
+        let mut set_id = [0u8; SET_ID_LEN];
+        randombytes_into(&mut set_id);
+        set_id
+    }
+
+    /// Serializes the trailer.
+    pub fn to_bytes(&self) -> [u8; TRAILER_LEN] {
+        let mut bytes = [0u8; TRAILER_LEN];
+        let (magic, rest) = bytes.split_at_mut(TRAILER_MAGIC.len());
+        magic.copy_from_slice(TRAILER_MAGIC);
+        let (set_id, rest) = rest.split_at_mut(SET_ID_LEN);
+        set_id.copy_from_slice(&self.set_id);
+        rest[..8].copy_from_slice(&self.number.to_be_bytes());
+        rest[8] = self.last as u8;
+        rest[9..].copy_from_slice(&self.end.to_be_bytes());
+        bytes
+    }
+
+    /// Parses a trailer, returning `None` if `bytes` is not one.
+    pub fn parse(bytes: &[u8]) -> Option<VolumeTrailer> {
+        if bytes.len() != TRAILER_LEN || !bytes.starts_with(TRAILER_MAGIC) {
+            return None;
+        }
+        let rest = &bytes[TRAILER_MAGIC.len()..];
+        let mut set_id = [0u8; SET_ID_LEN];
+        set_id.copy_from_slice(&rest[..SET_ID_LEN]);
+        let rest = &rest[SET_ID_LEN..];
+        let mut number = [0u8; 8];
+        number.copy_from_slice(&rest[..8]);
+        let last = match rest[8] {
+            0 => false,
+            1 => true,
+            _ => return None,
+        };
+        let mut end = [0u8; 8];
+        end.copy_from_slice(&rest[9..]);
+        Some(VolumeTrailer {
+            set_id,
+            number: u64::from_be_bytes(number),
+            last,
+            end: u64::from_be_bytes(end),
+        })
+    }
+}
+
+/// Reader over the decrypted plaintext of a volume that holds back the
+/// trailer, failing at the end if the plaintext does not end with one.
+pub struct VolumeReader<R> {
+    inner: R,
+    pending: Vec<u8>,
+    trailer: Option<VolumeTrailer>,
+}
+
+impl<R: Read> VolumeReader<R> {
+    pub fn new(inner: R) -> VolumeReader<R> {
+        VolumeReader {
+            inner,
+            pending: Vec::new(),
+            trailer: None,
+        }
+    }
+
+    /// Returns the trailer once the volume has been read to the end.
+    pub fn trailer(&self) -> Option<&VolumeTrailer> {
+        self.trailer.as_ref()
+    }
+}
+
+impl<R: Read> Read for VolumeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.trailer.is_some() {
+            return Ok(0);
+        }
+        while self.pending.len() <= TRAILER_LEN {
+            let start = self.pending.len();
+            self.pending.resize(start + buf.len().max(TRAILER_LEN), 0);
+            let n = self.inner.read(&mut self.pending[start..])?;
+            self.pending.truncate(start + n);
+            if n == 0 {
+                self.trailer = Some(VolumeTrailer::parse(&self.pending).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "volume has no trailer")
+                })?);
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.pending.len() - TRAILER_LEN);
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+/// Hash of the ciphertext of a set of volumes, in order.
+pub struct StreamHash(sha256::State);
+
+impl StreamHash {
+    pub fn new() -> StreamHash {
+        StreamHash(sha256::State::new())
+    }
+
+    /// Returns the hash in hex.
+    pub fn finish(self) -> String {
+        hex(&self.0.finalize()[..])
+    }
+}
+
+impl Default for StreamHash {
+    fn default() -> StreamHash {
+        StreamHash::new()
+    }
+}
+
+/// Writer that adds everything written through it to a `StreamHash`.
+pub struct HashingWriter<'a, W> {
+    inner: W,
+    hash: &'a mut StreamHash,
+    len: u64,
+}
+
+impl<'a, W: Write> HashingWriter<'a, W> {
+    pub fn new(inner: W, hash: &'a mut StreamHash) -> HashingWriter<'a, W> {
+        HashingWriter {
+            inner,
+            hash,
+            len: 0,
+        }
+    }
+
+    /// Returns the number of bytes written.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns true if nothing has been written.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<'a, W: Write> Write for HashingWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hash.0.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader that adds everything read through it to a `StreamHash`.
+pub struct HashingReader<'a, R> {
+    inner: R,
+    hash: &'a mut StreamHash,
+}
+
+impl<'a, R: Read> HashingReader<'a, R> {
+    pub fn new(inner: R, hash: &'a mut StreamHash) -> HashingReader<'a, R> {
+        HashingReader { inner, hash }
+    }
+}
+
+impl<'a, R: Read> Read for HashingReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hash.0.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        find_manifest, header_sha256, is_first_volume, manifest_path, min_split_size,
+        volume_capacity, volume_path, Manifest, StreamHash, VolumeEntry, VolumeReader,
+        VolumeTrailer, HEADER_LEN, TRAILER_LEN,
+    };
+    use crate::error::VolumeError;
+    use crate::stream::encrypted_len;
+
+    use std::fs;
+    use std::io::Read;
+
+    #[test]
+    fn volume_capacity_test() {
+        for &split_size in &[min_split_size(1024) as u64, 5000, 1 << 20, 4 << 30] {
+            let capacity = volume_capacity(split_size, 1024).unwrap() + TRAILER_LEN as u64;
+            assert_eq!(capacity % 1024, 0);
+            assert!(encrypted_len(capacity, 1024) <= split_size);
+            assert!(encrypted_len(capacity + 1024, 1024) > split_size);
+        }
+        assert_eq!(volume_capacity(min_split_size(1024) as u64 - 1, 1024), None);
+        assert_eq!(volume_capacity(10, 1024), None);
+    }
+
+    #[test]
+    fn volume_reader_test() {
+        let trailer = VolumeTrailer {
+            set_id: VolumeTrailer::generate_set_id(),
+            number: 3,
+            last: true,
+            end: 1 << 40,
+        };
+        assert_eq!(
+            VolumeTrailer::parse(&trailer.to_bytes()),
+            Some(trailer.clone())
+        );
+
+        for &len in &[0, 1, TRAILER_LEN, 5000] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut plaintext = data.clone();
+            plaintext.extend_from_slice(&trailer.to_bytes());
+            let mut reader = VolumeReader::new(&plaintext[..]);
+            let mut read = Vec::new();
+            let mut buf = [0u8; 7];
+            loop {
+                let n = reader.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                read.extend_from_slice(&buf[..n]);
+            }
+            assert_eq!(read, data);
+            assert_eq!(reader.trailer(), Some(&trailer));
+        }
+
+        // Plaintext that does not end with a trailer.
+        let mut reader = VolumeReader::new(&[0u8; 100][..]);
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+        let mut bytes = trailer.to_bytes();
+        bytes[TRAILER_LEN - 9] = 2;
+        assert_eq!(VolumeTrailer::parse(&bytes), None);
+    }
+
+    #[test]
+    fn paths_test() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let output = temp.path().join("out.slk");
+        assert_eq!(volume_path(&output, 2), temp.path().join("out.slk.002"));
+        assert_eq!(volume_path(&output, 1234), temp.path().join("out.slk.1234"));
+        let manifest = manifest_path(&output);
+        assert_eq!(manifest, temp.path().join("out.slk.manifest"));
+
+        assert_eq!(find_manifest(&manifest), Some(manifest.clone()));
+        assert_eq!(find_manifest(&volume_path(&output, 1)), None);
+        fs::write(&manifest, b"{}").unwrap();
+        assert_eq!(find_manifest(&volume_path(&output, 1)), Some(manifest));
+        assert_eq!(find_manifest(&output), None);
+
+        assert!(!is_first_volume(&volume_path(&output, 1)));
+        fs::write(volume_path(&output, 2), b"").unwrap();
+        assert!(is_first_volume(&volume_path(&output, 1)));
+        assert!(!is_first_volume(&volume_path(&output, 2)));
+    }
+
+    #[test]
+    fn check_volumes_test() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let entry = |name: &str, contents: &[u8]| {
+            fs::write(temp.path().join(name), contents).unwrap();
+            VolumeEntry {
+                name: name.to_string(),
+                len: contents.len() as u64,
+                plaintext_len: 0,
+                header_sha256: header_sha256(&contents[..HEADER_LEN]),
+            }
+        };
+        let mut manifest = Manifest::new(
+            vec![entry("a.001", &[1; 200]), entry("a.002", &[2; 200])],
+            StreamHash::new(),
+        );
+        let path = temp.path().join("a.manifest");
+        fs::write(&path, manifest.to_vec()).unwrap();
+        let parsed = Manifest::parse(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(parsed, manifest);
+        assert_eq!(parsed.check_volumes(&path).unwrap().len(), 2);
+
+        // Swapped volumes.
+        let rename = |from: &str, to: &str| {
+            fs::rename(temp.path().join(from), temp.path().join(to)).unwrap()
+        };
+        rename("a.001", "a.tmp");
+        rename("a.002", "a.001");
+        rename("a.tmp", "a.002");
+        assert!(matches!(
+            manifest.check_volumes(&path),
+            Err(VolumeError::OutOfOrder {
+                expected: 1,
+                found: 2,
+                ..
+            })
+        ));
+
+        rename("a.001", "a.tmp");
+        rename("a.002", "a.001");
+        rename("a.tmp", "a.002");
+
+        // Damaged, missing and escaping volumes.
+        fs::write(temp.path().join("a.002"), [3; 200]).unwrap();
+        assert!(matches!(
+            manifest.check_volumes(&path),
+            Err(VolumeError::Mismatch { .. })
+        ));
+        fs::remove_file(temp.path().join("a.002")).unwrap();
+        assert!(matches!(
+            manifest.check_volumes(&path),
+            Err(VolumeError::Missing { .. })
+        ));
+        manifest.volumes[0].name = "../a.001".to_string();
+        assert!(matches!(
+            manifest.check_volumes(&path),
+            Err(VolumeError::InvalidManifest(_))
+        ));
+
+        assert!(Manifest::parse(b"{").is_err());
+        manifest.total_len += 1;
+        assert!(Manifest::parse(&manifest.to_vec()).is_err());
+    }
+}
@@ -0,0 +1,1084 @@
+// Copyright (c) 2020, Nick Stevens <nick@bitcurry.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! End-to-end tests running the `saltlick` binary.
+//!
+//! Each test gets its own temporary directory to work in, which also serves
+//! as `HOME` so that the keychain is private to the test. Keychain location
+//! follows `HOME` only on Unix.
+
+#![cfg(unix)]
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::PathBuf;
+use std::process;
+
+use assert_cmd::cargo::cargo_bin;
+use assert_cmd::Command;
+use assert_fs::TempDir;
+use predicates::prelude::*;
+use predicates::str::contains;
+use saltlick_cli::volume::{HashingWriter, Manifest, StreamHash};
+
+const PLAINTEXT: &str = "the quick brown fox\njumps over\nthe lazy dog\n";
+
+/// A temporary working directory and keychain.
+struct Env {
+    temp: TempDir,
+}
+
+impl Env {
+    fn new() -> Env {
+        Env {
+            temp: TempDir::new().unwrap(),
+        }
+    }
+
+    /// Returns a `saltlick` command running in the temporary directory with
+    /// the whitespace-separated arguments `args`.
+    fn saltlick(&self, args: &str) -> Command {
+        Command::from_std(self.std_command(args))
+    }
+
+    fn std_command(&self, args: &str) -> process::Command {
+        let mut command = process::Command::new(cargo_bin("saltlick"));
+        command
+            .args(args.split_whitespace())
+            .current_dir(self.temp.path())
+            .env("HOME", self.temp.path())
+            .env_remove("XDG_CONFIG_HOME")
+            .env_remove("SALTLICK_AUTH_SOCK")
+            .env_remove("VISUAL")
+            .env_remove("EDITOR");
+        command
+    }
+
+    /// Runs `saltlick` with `args` until it writes past `limit` bytes into a
+    /// file, where it is killed as if interrupted.
+    fn interrupted(&self, args: &str, limit: u64) {
+        let mut command = self.std_command(args);
+        // SAFETY: setrlimit is async-signal-safe.
+        unsafe {
+            command.pre_exec(move || {
+                let rlimit = libc::rlimit {
+                    rlim_cur: limit,
+                    rlim_max: limit,
+                };
+                if libc::setrlimit(libc::RLIMIT_FSIZE, &rlimit) == 0 {
+                    Ok(())
+                } else {
+                    Err(io::Error::last_os_error())
+                }
+            });
+        }
+        let status = command.status().unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGXFSZ));
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.temp.path().join(name)
+    }
+
+    fn write(&self, name: &str, contents: impl AsRef<[u8]>) {
+        fs::write(self.path(name), contents).unwrap();
+    }
+
+    fn read(&self, name: &str) -> Vec<u8> {
+        fs::read(self.path(name)).unwrap()
+    }
+
+    /// Creates keychain keypair `name`.
+    fn keypair(&self, name: &str) {
+        self.saltlick("keychain generate")
+            .arg(name)
+            .assert()
+            .success();
+    }
+
+    /// Encrypts `plaintext` to keychain keypair `key`, writing it to `name`.
+    fn encrypt(&self, key: &str, name: &str, plaintext: impl AsRef<[u8]>) {
+        self.saltlick(&format!("encrypt -k {} -o {}", key, name))
+            .write_stdin(plaintext.as_ref())
+            .assert()
+            .success();
+    }
+
+    /// Runs `saltlick` with `args` and checks that it fails with an error
+    /// containing `message`.
+    fn fails(&self, args: &str, message: &str) {
+        self.saltlick(args)
+            .assert()
+            .failure()
+            .stderr(contains(format!("Error: {}", message)));
+    }
+}
+
+#[test]
+fn key_file_round_trip_test() {
+    let env = Env::new();
+    env.saltlick("generate")
+        .assert()
+        .success()
+        .stdout(contains("Wrote public key \"public.pem\""));
+    env.write("plain.txt", PLAINTEXT);
+    env.saltlick("encrypt -p public.pem -i plain.txt -o plain.slk")
+        .assert()
+        .success();
+    assert_ne!(env.read("plain.slk"), PLAINTEXT.as_bytes());
+    env.saltlick("decrypt -p public.pem -s secret.pem -i plain.slk")
+        .assert()
+        .success()
+        .stdout(PLAINTEXT);
+}
+
+#[test]
+fn buffer_and_block_size_test() {
+    let env = Env::new();
+    env.saltlick("generate").assert().success();
+    let plaintext = PLAINTEXT.repeat(1000);
+    env.write("plain.txt", &plaintext);
+    env.saltlick(
+        "encrypt -p public.pem -i plain.txt -o small.slk --block-size 1K --buffer-size 4K",
+    )
+    .assert()
+    .success();
+    env.saltlick(
+        "encrypt -p public.pem -i plain.txt -o large.slk --block-size 8M --buffer-size 1M",
+    )
+    .assert()
+    .success();
+    // Smaller blocks mean more per-block overhead.
+    assert!(env.read("small.slk").len() > env.read("large.slk").len());
+    for name in &["small.slk", "large.slk"] {
+        env.saltlick(&format!(
+            "decrypt -p public.pem -s secret.pem -i {} --buffer-size 64K",
+            name
+        ))
+        .assert()
+        .success()
+        .stdout(plaintext.clone());
+    }
+
+    env.fails(
+        "encrypt -p public.pem -i plain.txt --buffer-size 1K",
+        "\"--buffer-size\" must be between 4K and 256M (got 1K)",
+    );
+    env.fails(
+        "encrypt -p public.pem -i plain.txt --block-size 16M",
+        "\"--block-size\" must be between 1K and 8M (got 16M)",
+    );
+    env.fails(
+        "decrypt -p public.pem -s secret.pem -i small.slk --buffer-size 1G",
+        "\"--buffer-size\" must be between 4K and 256M (got 1G)",
+    );
+}
+
+#[test]
+fn large_file_test() {
+    let env = Env::new();
+    env.saltlick("generate").assert().success();
+    // Large enough to be memory mapped, and not a whole number of blocks.
+    let plaintext = (0..3 * 1024 * 1024 + 7)
+        .map(|i: u32| (i % 253) as u8)
+        .collect::<Vec<_>>();
+    env.write("plain.bin", &plaintext);
+    env.saltlick("encrypt -p public.pem -i plain.bin -o plain.slk")
+        .assert()
+        .success();
+    // Six 512K blocks and a final block of 7 bytes, each with 38 bytes of
+    // overhead, after a 145 byte header. Preallocated space is cut back.
+    assert_eq!(env.read("plain.slk").len(), 145 + plaintext.len() + 7 * 38);
+    env.saltlick("decrypt -p public.pem -s secret.pem -i plain.slk -o out.bin")
+        .assert()
+        .success();
+    assert_eq!(env.read("out.bin"), plaintext);
+}
+
+#[test]
+fn range_test() {
+    let env = Env::new();
+    env.keypair("alice");
+    env.saltlick("generate").assert().success();
+    let plaintext = (0..10_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    env.write("plain.bin", &plaintext);
+    env.saltlick("encrypt -k alice --block-size 1K -i plain.bin -o data.slk")
+        .assert()
+        .success();
+
+    // Ranges within a block, across blocks, open at either end, and past
+    // the end of the plaintext.
+    for &(range, start, end) in &[
+        ("0-10", 0, 10),
+        ("1000-1100", 1000, 1100),
+        ("1K-3K", 1024, 3072),
+        ("9000-", 9000, 10_000),
+        ("-5", 0, 5),
+        ("9990-20000", 9990, 10_000),
+        ("20000-", 10_000, 10_000),
+    ] {
+        env.saltlick(&format!("decrypt -i data.slk --range {}", range))
+            .assert()
+            .success()
+            .stdout(plaintext[start..end].to_vec());
+    }
+    env.saltlick("decrypt -k alice -i data.slk --range 5000-5010 -o part.bin")
+        .assert()
+        .success();
+    assert_eq!(env.read("part.bin"), &plaintext[5000..5010]);
+
+    // Only the blocks holding the range are authenticated, but tampering
+    // with the MACs of the blocks skipped over is still detected.
+    let mut damaged = env.read("data.slk");
+    // The MAC of the second block, after the header, the first block, the
+    // length message, the tag byte and the second block itself.
+    damaged[145 + 1062 + 21 + 1 + 1024 + 3] ^= 0x01;
+    env.write("damaged.slk", &damaged);
+    env.saltlick("decrypt -i damaged.slk --range 0-10")
+        .assert()
+        .success();
+    env.fails(
+        "decrypt -i damaged.slk --range 5000-5010",
+        "error occurred while performing file I/O",
+    );
+
+    // A stream cut off at a block boundary looks like a shorter complete
+    // one, so ranges running to the end check the final block.
+    let ciphertext = env.read("data.slk");
+    env.write("cut.slk", &ciphertext[..145 + 2 * 1062 + 38]);
+    for range in &["0-", "100-5000", "2048-"] {
+        env.fails(
+            &format!("decrypt -i cut.slk --range {} -o cut.bin", range),
+            "error occurred while performing file I/O",
+        );
+        assert!(!env.path("cut.bin").exists());
+    }
+    env.saltlick("decrypt -i cut.slk --range 0-100")
+        .assert()
+        .success()
+        .stdout(plaintext[..100].to_vec());
+
+    env.fails(
+        "decrypt -p public.pem -s secret.pem -i data.slk --range 0-10",
+        "error occurred while performing file I/O",
+    );
+    env.saltlick("decrypt --range 0-10")
+        .assert()
+        .failure()
+        .stderr(contains("--infile"));
+    env.saltlick("decrypt -i data.slk --range 10-5")
+        .assert()
+        .failure()
+        .stderr(contains("invalid range \"10-5\""));
+}
+
+#[test]
+fn resume_test() {
+    let env = Env::new();
+    env.keypair("alice");
+    let plaintext = (0..10_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    env.write("plain.bin", &plaintext);
+    let encrypt =
+        "encrypt -k alice --block-size 1K --buffer-size 4K --resume -i plain.bin -o data.slk";
+
+    // Killed part way through the fifth block, leaving the journal behind.
+    env.interrupted(encrypt, 5000);
+    assert_eq!(env.read("data.slk").len(), 5000);
+    assert!(env.path("data.slk.saltlick-journal").exists());
+    env.saltlick(encrypt).assert().success().stderr("");
+    assert!(!env.path("data.slk.saltlick-journal").exists());
+    // The header, ten 1K blocks and a final block of 784 bytes.
+    assert_eq!(env.read("data.slk").len(), 145 + 10_000 + 10 * 38);
+    env.saltlick("decrypt -i data.slk")
+        .assert()
+        .success()
+        .stdout(plaintext.clone());
+
+    // Without a journal, an existing output still needs `--force`.
+    env.fails(encrypt, "unable to write output file");
+    fs::remove_file(env.path("data.slk")).unwrap();
+
+    // A journal for an input that has since changed is not resumed.
+    env.interrupted(encrypt, 5000);
+    let changed = vec![1u8; 3000];
+    env.write("plain.bin", &changed);
+    env.saltlick(encrypt)
+        .assert()
+        .success()
+        .stderr(contains("is for a different input, starting again"));
+    env.saltlick("decrypt -i data.slk")
+        .assert()
+        .success()
+        .stdout(changed.clone());
+
+    // Nor is one for an input whose contents changed without changing its
+    // length or modification time.
+    fs::remove_file(env.path("data.slk")).unwrap();
+    env.write("plain.bin", &plaintext);
+    env.interrupted(encrypt, 5000);
+    let modified = fs::metadata(env.path("plain.bin"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    let mut changed = plaintext.clone();
+    changed[100] ^= 1;
+    env.write("plain.bin", &changed);
+    filetime::set_file_mtime(
+        env.path("plain.bin"),
+        filetime::FileTime::from_system_time(modified),
+    )
+    .unwrap();
+    env.saltlick(encrypt).assert().success().stderr(contains(
+        "plain.bin does not match its journal, starting again",
+    ));
+    env.saltlick("decrypt -i data.slk")
+        .assert()
+        .success()
+        .stdout(changed.clone());
+
+    // Decryption resumes the same way. Key files keep the audit log, which
+    // is also limited in size, out of the way.
+    env.saltlick("generate").assert().success();
+
+    // The journal does not hold the stream key, so resuming needs the
+    // recipient's secret key to open it, and starts again without it.
+    let encrypt_pem =
+        "encrypt -p public.pem --block-size 1K --buffer-size 4K --resume -i plain.bin -o pem.slk";
+    env.interrupted(encrypt_pem, 5000);
+    let journal = String::from_utf8(env.read("pem.slk.saltlick-journal")).unwrap();
+    assert!(!journal.contains("stream_key"));
+    env.saltlick(encrypt_pem)
+        .assert()
+        .success()
+        .stderr(contains("unable to open the stream key of pem.slk"));
+    env.saltlick("decrypt -p public.pem -s secret.pem -i pem.slk")
+        .assert()
+        .success()
+        .stdout(changed.clone());
+
+    env.saltlick("encrypt -p public.pem -i plain.bin -o data.slk --force")
+        .assert()
+        .success();
+    let decrypt = "decrypt -p public.pem -s secret.pem --resume -i data.slk -o plain.out";
+    env.interrupted(decrypt, 1000);
+    assert!(env.path("plain.out.saltlick-journal").exists());
+    env.saltlick(decrypt).assert().success().stderr("");
+    assert!(!env.path("plain.out.saltlick-journal").exists());
+    assert_eq!(env.read("plain.out"), changed);
+
+    env.saltlick("decrypt --resume -i data.slk")
+        .assert()
+        .failure()
+        .stderr(contains("--outfile"));
+}
+
+#[test]
+fn split_test() {
+    let env = Env::new();
+    env.keypair("alice");
+    let plaintext = (0..10_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    env.write("plain.bin", &plaintext);
+    env.saltlick("encrypt -k alice --block-size 1K --split-size 4K -i plain.bin -o data.slk")
+        .assert()
+        .success();
+
+    // Three 1K blocks fit in each 4K volume, less the trailer, so the last
+    // holds 907 bytes.
+    for n in 1..=4 {
+        let len = env.read(&format!("data.slk.00{}", n)).len();
+        assert!(len <= 4096);
+        env.saltlick(&format!("decrypt -i data.slk.00{} -o part{}.bin", n, n))
+            .assert()
+            .success();
+    }
+    assert!(!env.path("data.slk.005").exists());
+    assert!(env.path("data.slk.manifest").exists());
+
+    // Either the first volume or the manifest decrypts the whole set.
+    env.saltlick("decrypt -i data.slk.001")
+        .assert()
+        .success()
+        .stdout(plaintext.clone());
+    env.saltlick("decrypt -k alice -i data.slk.manifest -o plain.out")
+        .assert()
+        .success();
+    assert_eq!(env.read("plain.out"), plaintext);
+
+    // Options that work on a single stream would skip the volume checks.
+    env.fails(
+        "decrypt -i data.slk.manifest --range 0-10",
+        "split output \"data.slk.manifest\": --range cannot be used with split output",
+    );
+    env.fails(
+        "decrypt -i data.slk.002 -o out.bin --resume",
+        "split output \"data.slk.manifest\": --resume cannot be used with split output",
+    );
+    assert!(!env.path("out.bin").exists());
+
+    // The manifest is not keyed, but a manifest rewritten to drop the last
+    // volume is caught by the trailers inside the volumes.
+    let original = env.read("data.slk.manifest");
+    let mut manifest = Manifest::parse(&original).unwrap();
+    manifest.volumes.pop();
+    let mut hash = StreamHash::new();
+    let mut writer = HashingWriter::new(io::sink(), &mut hash);
+    for volume in &manifest.volumes {
+        io::Write::write_all(&mut writer, &env.read(&volume.name)).unwrap();
+    }
+    env.write(
+        "data.slk.manifest",
+        Manifest::new(manifest.volumes, hash).to_vec(),
+    );
+    env.fails(
+        "decrypt -i data.slk.manifest -o out.bin",
+        "split output \"data.slk.manifest\": volume \"data.slk.003\" is not the last of its set",
+    );
+    assert!(!env.path("out.bin").exists());
+    env.write("data.slk.manifest", &original);
+
+    // Reordered and missing volumes are detected before anything is written.
+    fs::rename(env.path("data.slk.002"), env.path("tmp")).unwrap();
+    fs::rename(env.path("data.slk.003"), env.path("data.slk.002")).unwrap();
+    fs::rename(env.path("tmp"), env.path("data.slk.003")).unwrap();
+    env.fails(
+        "decrypt -i data.slk.001 -o out.bin",
+        "split output \"data.slk.manifest\": volume \"data.slk.002\" is out of order",
+    );
+    assert!(!env.path("out.bin").exists());
+    fs::remove_file(env.path("data.slk.002")).unwrap();
+    env.fails(
+        "decrypt -i data.slk.manifest",
+        "split output \"data.slk.manifest\": volume \"data.slk.002\" is missing",
+    );
+
+    // Without the manifest only the given volume is decrypted.
+    fs::rename(env.path("data.slk.003"), env.path("data.slk.002")).unwrap();
+    fs::remove_file(env.path("data.slk.manifest")).unwrap();
+    env.saltlick("decrypt -i data.slk.001")
+        .assert()
+        .success()
+        .stdout(plaintext[..3031].to_vec())
+        .stderr(contains("no manifest found"));
+    env.fails(
+        "decrypt -i data.slk.001 --range 0-10",
+        "split output \"data.slk.manifest\": --range cannot be used with split output",
+    );
+
+    env.fails(
+        "encrypt -k alice -i plain.bin -o small.slk --split-size 1K",
+        "\"--split-size\" must be between",
+    );
+    env.saltlick("encrypt -k alice -i plain.bin --split-size 1M")
+        .assert()
+        .failure()
+        .stderr(contains("--outfile"));
+}
+
+#[test]
+fn remove_source_test() {
+    let env = Env::new();
+    env.keypair("alice");
+    let plaintext = (0..10_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+    for shred in &["", "--shred 3"] {
+        env.write("plain.bin", &plaintext);
+        env.saltlick(&format!(
+            "encrypt -k alice --block-size 1K -i plain.bin -o data.slk --force --remove-source {}",
+            shred
+        ))
+        .assert()
+        .success();
+        assert!(!env.path("plain.bin").exists());
+        env.saltlick("decrypt -i data.slk")
+            .assert()
+            .success()
+            .stdout(plaintext.clone());
+    }
+
+    // Symbolic links and files with other hard links are left alone, as is
+    // the file they point to, and no output is written.
+    env.write("plain.bin", &plaintext);
+    std::os::unix::fs::symlink("plain.bin", env.path("link.bin")).unwrap();
+    env.fails(
+        "encrypt -k alice -i link.bin -o link.slk --remove-source",
+        "unable to remove input file \"link.bin\": it is a symbolic link",
+    );
+    fs::hard_link(env.path("plain.bin"), env.path("hard.bin")).unwrap();
+    env.fails(
+        "encrypt -k alice -i plain.bin -o plain.slk --remove-source",
+        "unable to remove input file \"plain.bin\": it has 1 other hard link",
+    );
+    assert!(env.path("plain.bin").exists());
+    assert!(!env.path("link.slk").exists());
+    assert!(!env.path("plain.slk").exists());
+
+    env.saltlick("encrypt -k alice -i plain.bin --remove-source")
+        .assert()
+        .failure()
+        .stderr(contains("--outfile"));
+    env.saltlick("encrypt -k alice -i plain.bin -o plain.slk --shred 1")
+        .assert()
+        .failure()
+        .stderr(contains("--remove-source"));
+}
+
+#[test]
+fn metadata_test() {
+    let env = Env::new();
+    env.keypair("alice");
+    env.write("notes.txt", PLAINTEXT);
+    fs::set_permissions(env.path("notes.txt"), fs::Permissions::from_mode(0o640)).unwrap();
+    let modified = filetime::FileTime::from_unix_time(981_173_106, 0);
+    filetime::set_file_mtime(env.path("notes.txt"), modified).unwrap();
+    env.saltlick("encrypt -k alice -i notes.txt -o notes.slk --preserve-metadata")
+        .assert()
+        .success();
+
+    // Nothing outside the plaintext records the metadata, so it is only
+    // removed when restoring it, and left in place otherwise with a warning.
+    let warning = "Warning: \"notes.slk\" starts with stored file metadata";
+    let output = env.saltlick("cat notes.slk").output().unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains(warning));
+    let output = output.stdout;
+    assert!(output.starts_with(b"\0saltlick-meta\0\x01"));
+    assert!(output.ends_with(PLAINTEXT.as_bytes()));
+    env.saltlick("decrypt -i notes.slk")
+        .assert()
+        .success()
+        .stdout(output)
+        .stderr(contains(warning));
+    env.saltlick("grep quick notes.slk")
+        .assert()
+        .success()
+        .stderr(contains(warning));
+    env.saltlick("decrypt -i notes.slk -o notes.out --restore-metadata")
+        .assert()
+        .success()
+        .stderr("");
+
+    // Plaintext that only looks like it starts with metadata decrypts
+    // unchanged.
+    let lookalike = b"\0saltlick-meta\0\x01\xff\xff\xff\xff not metadata";
+    env.encrypt("alice", "lookalike.slk", &lookalike[..]);
+    env.saltlick("decrypt -i lookalike.slk")
+        .assert()
+        .success()
+        .stdout(&lookalike[..])
+        .stderr(contains("starts with stored file metadata"));
+
+    // Restoring into a directory uses the stored name.
+    fs::create_dir(env.path("out")).unwrap();
+    env.saltlick("decrypt -i notes.slk -o out --restore-metadata")
+        .assert()
+        .success();
+    let restored = env.path("out/notes.txt");
+    assert_eq!(fs::read_to_string(&restored).unwrap(), PLAINTEXT);
+    let metadata = fs::metadata(&restored).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o640);
+    assert_eq!(
+        filetime::FileTime::from_last_modification_time(&metadata),
+        modified
+    );
+
+    // Without stored metadata there is nothing to restore or name the
+    // output after.
+    env.encrypt("alice", "plain.slk", PLAINTEXT);
+    env.saltlick("decrypt -i plain.slk -o plain.txt --restore-metadata")
+        .assert()
+        .success()
+        .stderr(contains("Warning: no metadata is stored for plain.txt"));
+    env.fails(
+        "decrypt -i plain.slk -o out --restore-metadata",
+        "unable to name output in \"out\": no file name is stored in the input",
+    );
+
+    env.saltlick("encrypt -k alice --preserve-metadata")
+        .write_stdin(PLAINTEXT)
+        .assert()
+        .failure()
+        .stderr(contains(
+            "\"--preserve-metadata\" cannot be used with stdin or stdout",
+        ));
+    env.fails(
+        "decrypt -i notes.slk --restore-metadata",
+        "\"--restore-metadata\" cannot be used with stdin or stdout",
+    );
+}
+
+#[test]
+fn keychain_round_trip_test() {
+    let env = Env::new();
+    env.keypair("alice");
+    env.keypair("bob");
+    env.saltlick("keychain list")
+        .assert()
+        .success()
+        .stdout("alice\nbob\n");
+
+    // Empty, small and multi-block inputs all survive the round trip.
+    let large = (0..300_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    for plaintext in &[Vec::new(), PLAINTEXT.as_bytes().to_vec(), large] {
+        env.encrypt("alice", "data.slk", plaintext);
+        // Decrypting searches the keychain for the key.
+        env.saltlick("decrypt -i data.slk")
+            .assert()
+            .success()
+            .stdout(plaintext.clone());
+        env.saltlick("decrypt -k alice")
+            .write_stdin(env.read("data.slk"))
+            .assert()
+            .success()
+            .stdout(plaintext.clone());
+        fs::remove_file(env.path("data.slk")).unwrap();
+    }
+
+    // The default keypair is used when encrypting without a key.
+    env.saltlick("keychain set-default bob").assert().success();
+    env.saltlick("encrypt -o default.slk")
+        .write_stdin(PLAINTEXT)
+        .assert()
+        .success();
+    env.saltlick("decrypt -k bob -i default.slk")
+        .assert()
+        .success()
+        .stdout(PLAINTEXT);
+}
+
+#[test]
+fn keychain_management_test() {
+    let env = Env::new();
+    env.keypair("original");
+    env.saltlick("keychain rename original renamed")
+        .assert()
+        .success();
+    env.saltlick("keychain export renamed -p renamed.pub.pem -s renamed.sec.pem")
+        .assert()
+        .success();
+    env.saltlick("keychain import copy renamed.pub.pem renamed.sec.pem")
+        .assert()
+        .success();
+    env.saltlick("keychain remove renamed").assert().success();
+    env.saltlick("keychain list")
+        .assert()
+        .success()
+        .stdout("copy\n");
+
+    // The imported copy decrypts what was encrypted to the exported key.
+    env.saltlick("encrypt -p renamed.pub.pem -o data.slk")
+        .write_stdin(PLAINTEXT)
+        .assert()
+        .success();
+    env.saltlick("decrypt -i data.slk")
+        .assert()
+        .success()
+        .stdout(PLAINTEXT);
+
+    env.saltlick("keychain log")
+        .assert()
+        .success()
+        .stdout(contains("rename").and(contains("decrypt")));
+}
+
+#[test]
+fn force_test() {
+    let env = Env::new();
+    env.keypair("alice");
+    env.write("plain.txt", PLAINTEXT);
+    env.write("existing", "keep me");
+    env.fails(
+        "encrypt -k alice -i plain.txt -o existing",
+        "unable to write output file \"existing\": File exists",
+    );
+    assert_eq!(env.read("existing"), b"keep me");
+    env.saltlick("encrypt -k alice -i plain.txt -o existing -f")
+        .assert()
+        .success();
+
+    env.write("decrypted", "keep me too");
+    env.fails(
+        "decrypt -i existing -o decrypted",
+        "unable to write output file \"decrypted\": File exists",
+    );
+    env.saltlick("decrypt -i existing -o decrypted --force")
+        .assert()
+        .success();
+    assert_eq!(env.read("decrypted"), PLAINTEXT.as_bytes());
+}
+
+#[test]
+fn batch_test() {
+    let env = Env::new();
+    env.keypair("alice");
+    env.write("one", "1");
+    env.write("two", "2");
+    env.saltlick("encrypt -k alice one two").assert().success();
+    fs::remove_file(env.path("one")).unwrap();
+    fs::remove_file(env.path("two")).unwrap();
+    env.saltlick("decrypt --files-from -")
+        .write_stdin("one.slk\ntwo.slk\n")
+        .assert()
+        .success();
+    assert_eq!(env.read("one"), b"1");
+    assert_eq!(env.read("two"), b"2");
+
+    env.saltlick("decrypt two missing.slk")
+        .assert()
+        .failure()
+        .stderr(contains(
+            "Error: two: unable to name output for \"two\": it does not end in \".slk\"",
+        ))
+        .stderr(contains("Error: missing.slk: unable to read input file"))
+        .stderr(contains("Error: 2 of 2 files failed"));
+}
+
+#[test]
+fn bench_test() {
+    let env = Env::new();
+    let output = env
+        .saltlick("bench --json -n 1 -s 4K -s 64K -b 8K")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let measurements: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    let measurements = measurements.as_array().unwrap();
+    assert_eq!(measurements.len(), 4);
+    assert_eq!(measurements[3]["operation"], "decrypt");
+    assert_eq!(measurements[3]["input_size"], 65536);
+    assert_eq!(measurements[3]["buffer_size"], 8192);
+
+    env.saltlick("bench -n 1 -s 1000 -b 4K")
+        .assert()
+        .success()
+        .stdout(contains("encrypt").and(contains("decrypt")));
+    env.saltlick("bench -s 4Q")
+        .assert()
+        .failure()
+        .stderr(contains("invalid size \"4Q\""));
+    env.fails(
+        "bench --buffer-size 0 --size 1K",
+        "\"--buffer-size\" must be between 4K and 256M (got 0)",
+    );
+    // The ephemeral key is never written anywhere, keychain included.
+    assert!(fs::read_dir(env.path("")).unwrap().next().is_none());
+}
+
+#[test]
+fn key_mismatch_test() {
+    let env = Env::new();
+    env.keypair("alice");
+    env.keypair("bob");
+    env.encrypt("alice", "data.slk", PLAINTEXT);
+    env.fails(
+        "decrypt -k bob -i data.slk",
+        "error occurred while performing file I/O",
+    );
+
+    // Without alice in the keychain, no key is found.
+    env.saltlick("keychain remove alice").assert().success();
+    env.fails(
+        "decrypt -i data.slk",
+        "error occurred while performing file I/O",
+    );
+}
+
+#[test]
+fn damaged_ciphertext_test() {
+    let env = Env::new();
+    env.keypair("alice");
+    env.encrypt("alice", "data.slk", PLAINTEXT.repeat(100));
+    let ciphertext = env.read("data.slk");
+
+    // Truncation anywhere - in the header, a length, or a block - and a
+    // corrupted byte anywhere are detected.
+    let header_len = 9 + 32 + 32 + 24 + 48;
+    for &len in &[0, 5, header_len - 1, header_len + 3, ciphertext.len() - 1] {
+        env.write("damaged.slk", &ciphertext[..len]);
+        env.saltlick("decrypt -i damaged.slk")
+            .assert()
+            .failure()
+            .stderr(contains("Error: error occurred while performing file I/O"));
+    }
+    for &offset in &[0, 9, header_len - 1, header_len + 1, ciphertext.len() - 1] {
+        let mut damaged = ciphertext.clone();
+        damaged[offset] ^= 0x01;
+        env.write("damaged.slk", &damaged);
+        env.saltlick("decrypt -k alice -i damaged.slk")
+            .assert()
+            .failure()
+            .stderr(contains("Error: error occurred while performing file I/O"));
+    }
+}
+
+#[test]
+fn key_error_messages_test() {
+    let env = Env::new();
+    env.keypair("alice");
+    env.write("plain.txt", PLAINTEXT);
+    env.write("garbage.pem", "not a key");
+
+    env.fails(
+        "encrypt -k alice -p garbage.pem",
+        "only one of \"--key\" or \"--public\" can be specified",
+    );
+    env.fails(
+        "encrypt -i plain.txt",
+        "one of \"--key\" or \"--public\" must be specified",
+    );
+    env.fails(
+        "encrypt -p garbage.pem",
+        "unable to load public key from \"garbage.pem\"",
+    );
+    env.fails(
+        "decrypt -p garbage.pem",
+        "unable to load public key from \"garbage.pem\"",
+    );
+    env.fails("encrypt -k nobody", "keypair \"nobody\" not found");
+    env.fails(
+        "encrypt -k alice -i missing.txt",
+        "unable to read input file \"missing.txt\": No such file or directory",
+    );
+
+    env.saltlick("keychain generate old --expires 2000-01-01")
+        .assert()
+        .success();
+    env.fails(
+        "encrypt -k old -i plain.txt",
+        "keypair \"old\" expired on 2000-01-01 (use \"--allow-expired\" to override)",
+    );
+    env.saltlick("encrypt -k old -i plain.txt --allow-expired")
+        .assert()
+        .success();
+
+    env.saltlick("generate").assert().success();
+    env.fails("generate", "public key already exists at \"public.pem\"");
+    env.fails(
+        "generate -p new.pem",
+        "secret key already exists at \"secret.pem\"",
+    );
+    env.fails(
+        "generate -p missing/public.pem -s missing/secret.pem",
+        "key file I/O error: No such file or directory",
+    );
+}
+
+#[test]
+fn keychain_error_messages_test() {
+    let env = Env::new();
+    env.keypair("alice");
+
+    env.saltlick("keychain generate derived --from-passphrase")
+        .write_stdin("\n")
+        .assert()
+        .failure()
+        .stderr(contains(
+            "Error: unable to derive keypair: passphrase cannot be empty",
+        ));
+
+    env.saltlick("keychain import restored --paper")
+        .write_stdin("not a paper backup\n")
+        .assert()
+        .failure()
+        .stderr(contains("Error: invalid paper backup"));
+
+    env.saltlick("keychain export alice --paper --qr-svg alice.svg")
+        .assert()
+        .success();
+    let mode = fs::metadata(env.path("alice.svg"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let shares = env.path("shares");
+    env.saltlick("keychain split alice --shares 3 --threshold 2")
+        .arg("--outdir")
+        .arg(&shares)
+        .assert()
+        .success();
+    let share = fs::read_dir(&shares)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    let mode = fs::metadata(&share).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    env.saltlick("keychain combine recovered")
+        .arg(&share)
+        .assert()
+        .failure()
+        .stderr(contains("Error: 1 shares provided but 2 are required"));
+    env.write("bad.share", "not a share");
+    env.fails(
+        "keychain combine recovered bad.share",
+        "share \"bad.share\": share file is invalid",
+    );
+
+    env.fails("agent list", "agent: no agent socket given");
+}
+
+#[test]
+fn stream_command_messages_test() {
+    let env = Env::new();
+    env.keypair("alice");
+    env.encrypt("alice", "old.slk", PLAINTEXT);
+    env.encrypt("alice", "new.slk", PLAINTEXT.replace("lazy", "sleepy"));
+
+    env.saltlick("cat old.slk")
+        .assert()
+        .success()
+        .stdout(PLAINTEXT);
+    env.saltlick("grep -n lazy old.slk new.slk")
+        .assert()
+        .success()
+        .stdout("old.slk:3:the lazy dog\n");
+    env.saltlick("grep --no-filename the old.slk new.slk")
+        .assert()
+        .success()
+        .stdout("the quick brown fox\nthe lazy dog\nthe quick brown fox\nthe sleepy dog\n");
+    env.saltlick("grep absent old.slk")
+        .assert()
+        .code(1)
+        .stdout("");
+    env.saltlick("grep ( old.slk")
+        .assert()
+        .code(2)
+        .stderr(contains("Error: invalid pattern"));
+    env.saltlick("grep lazy missing.slk").assert().code(2);
+    env.saltlick("diff old.slk new.slk")
+        .assert()
+        .code(1)
+        .stdout(contains("-the lazy dog\n+the sleepy dog\n"));
+    env.saltlick("diff --max-size 4 old.slk new.slk")
+        .assert()
+        .code(2)
+        .stderr(contains(
+            "Error: decrypted \"old.slk\" is larger than 4 bytes (see \"--max-size\")",
+        ));
+}
+
+#[test]
+fn values_messages_test() {
+    let env = Env::new();
+    env.keypair("alice");
+    env.write("config.json", r#"{"user": "admin", "password": "hunter2"}"#);
+    env.write("config.txt", "password=hunter2");
+
+    env.saltlick("encrypt-values -k alice -i config.json -o secret.json")
+        .assert()
+        .success();
+    assert!(!String::from_utf8(env.read("secret.json"))
+        .unwrap()
+        .contains("hunter2"));
+    env.saltlick("decrypt-values -i secret.json")
+        .assert()
+        .success()
+        .stdout(contains("hunter2"));
+
+    env.fails(
+        "encrypt-values -k alice -i config.txt",
+        "unable to tell the format of \"config.txt\" (use \"--format\")",
+    );
+    env.saltlick("encrypt-values -k alice")
+        .write_stdin("{}")
+        .assert()
+        .failure()
+        .stderr(contains(
+            "Error: \"--format\" is required when reading from stdin",
+        ));
+    env.fails(
+        "decrypt-values -i config.json",
+        "document has no saltlick metadata",
+    );
+
+    // Edits are written back with the document's permissions.
+    fs::set_permissions(env.path("secret.json"), fs::Permissions::from_mode(0o640)).unwrap();
+    env.saltlick("edit-values secret.json")
+        .env("EDITOR", "sed -i s/hunter2/hunter3/")
+        .assert()
+        .success();
+    env.saltlick("decrypt-values -i secret.json")
+        .assert()
+        .success()
+        .stdout(contains("hunter3"));
+    let mode = fs::metadata(env.path("secret.json"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o640);
+
+    env.saltlick("edit-values secret.json")
+        .env("EDITOR", "false")
+        .assert()
+        .failure()
+        .stderr(contains("Error: editor \"false\" failed"));
+    env.saltlick("edit-values secret.json")
+        .env("EDITOR", "/nonexistent/editor")
+        .assert()
+        .failure()
+        .stderr(contains(
+            "Error: unable to run editor \"/nonexistent/editor\"",
+        ));
+}
+
+#[test]
+fn exec_messages_test() {
+    let env = Env::new();
+    env.keypair("alice");
+    env.encrypt("alice", "app.env.slk", "GREETING=hello\n");
+    env.encrypt("alice", "bad.env.slk", "not a variable\n");
+
+    env.saltlick("exec -i app.env.slk -- sh -c")
+        .arg("echo $GREETING")
+        .assert()
+        .success()
+        .stdout("hello\n");
+
+    // The command runs with the core dump limits saltlick was started with,
+    // not the lowered limit saltlick uses itself.
+    let mut command = env.std_command("exec -i app.env.slk -- sh -c");
+    // SAFETY: getrlimit and setrlimit are async-signal-safe.
+    unsafe {
+        command.pre_exec(|| {
+            let mut rlimit = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            libc::getrlimit(libc::RLIMIT_CORE, &mut rlimit);
+            rlimit.rlim_cur = rlimit.rlim_max;
+            if libc::setrlimit(libc::RLIMIT_CORE, &rlimit) == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        });
+    }
+    let output = command.arg("ulimit -c; ulimit -Hc").output().unwrap();
+    assert!(output.status.success());
+    let limits = String::from_utf8(output.stdout).unwrap();
+    let limits = limits.lines().collect::<Vec<_>>();
+    assert_eq!(limits.len(), 2);
+    assert_eq!(limits[0], limits[1]);
+
+    env.fails(
+        "exec -i bad.env.slk -- true",
+        "unable to parse \"bad.env.slk\": line 1",
+    );
+    env.fails(
+        "exec -i app.env.slk -i app.env.slk --override error -- true",
+        "environment variable \"GREETING\" is defined more than once",
+    );
+    env.fails(
+        "exec -i app.env.slk -- /nonexistent/command",
+        "unable to run \"/nonexistent/command\"",
+    );
+}